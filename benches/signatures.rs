@@ -0,0 +1,41 @@
+// Times verifying the signatures of a 1000-transaction block one at a time
+// against `verify_all`. Run with `cargo bench --features testkit --bench signatures`.
+
+use std::time::{Duration, Instant};
+
+use mockchain::testkit::{new_account, verify_all, Transaction};
+
+const TRANSACTIONS: u64 = 1_000;
+const ROUNDS: u32 = 20;
+const CHAIN_ID: u64 = 1;
+
+fn main() {
+    let (secret_key, from) = new_account();
+    let (_, to) = new_account();
+    let transactions: Vec<_> = (1..=TRANSACTIONS)
+        .map(|amount| {
+            let mut tx = Transaction::new(&from, &to, amount);
+            tx.sign(&secret_key, CHAIN_ID);
+            tx
+        })
+        .collect();
+
+    let serial = time(|| transactions.iter().all(|tx| tx.verify(CHAIN_ID)));
+    let batched = time(|| verify_all(&transactions, CHAIN_ID));
+    println!(
+        "{} transactions, {} threads: serial {:?}, verify_all {:?}",
+        TRANSACTIONS,
+        std::thread::available_parallelism().map_or(1, |n| n.get()),
+        serial,
+        batched
+    );
+}
+
+// Mean time of a verification pass, which must succeed
+fn time(verify: impl Fn() -> bool) -> Duration {
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        assert!(verify());
+    }
+    started.elapsed() / ROUNDS
+}
@@ -0,0 +1,69 @@
+// Times saving and reloading a long chain in each storage format, and reports
+// the file sizes. Run with `cargo bench --features testkit --bench storage`.
+
+use std::{sync::Arc, time::Instant};
+
+use chrono::Utc;
+use mockchain::{
+    config::Config,
+    testkit::{self, new_account, MockClock, Storage, StorageFormat},
+};
+use secp256k1::{PublicKey, Secp256k1};
+
+const BLOCKS: usize = 2_000;
+const GRANTS_PER_BLOCK: usize = 5;
+
+fn main() {
+    let config = Config {
+        consensus: "dev".to_string(),
+        ..Config::default()
+    };
+    let clock = Arc::new(MockClock::new(Utc::now()));
+    let mut chain = testkit::open_chain(&config, clock.clone());
+    let (miner_key, _) = new_account();
+    let miner = PublicKey::from_secret_key(&Secp256k1::new(), &miner_key);
+    for _ in 0..BLOCKS {
+        for _ in 0..GRANTS_PER_BLOCK {
+            testkit::grant(&mut chain, &new_account().1, 100);
+        }
+        clock.advance(std::time::Duration::from_secs(1));
+        chain
+            .mine_pending_transactions(&miner)
+            .expect("block mines");
+    }
+    println!("{} blocks of {} faucet grants", BLOCKS, GRANTS_PER_BLOCK);
+
+    for format in [StorageFormat::Json, StorageFormat::Bincode] {
+        let path = std::env::temp_dir().join(format!("mockchain-bench-{:?}", format));
+        let storage = Storage {
+            path: path.clone(),
+            format,
+            compress: false,
+            passphrase: None,
+        };
+
+        let started = Instant::now();
+        chain.save_to_path(&storage).expect("chain saves");
+        let saved = started.elapsed();
+        let bytes = std::fs::metadata(&path).expect("saved file exists").len();
+
+        let reload = Config {
+            consensus: config.consensus.clone(),
+            chain_file: Some(path.clone()),
+            skip_validation: true,
+            ..Config::default()
+        };
+        let started = Instant::now();
+        testkit::open_chain(&reload, clock.clone());
+        let loaded = started.elapsed();
+        std::fs::remove_file(&path).expect("saved file is removable");
+
+        println!(
+            "{:?}: save {:?}, load {:?}, {} KiB",
+            format,
+            saved,
+            loaded,
+            bytes / 1024
+        );
+    }
+}
@@ -0,0 +1,78 @@
+// Counts GetTip and GetChainInfo requests served in a fixed window while
+// another thread mines under the blockchain lock. Run with
+// `cargo bench --features testkit --bench tip`.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use mockchain::{
+    blockchain::{ChainInfoRequest, TipRequest},
+    config::Config,
+    testkit::{self, new_account, TestNode},
+};
+use secp256k1::{PublicKey, Secp256k1};
+
+const WINDOW: Duration = Duration::from_secs(3);
+
+#[tokio::main]
+async fn main() {
+    let mut node = TestNode::spawn(Config {
+        consensus: "dev".to_string(),
+        ..Config::default()
+    })
+    .await;
+
+    let mining = Arc::new(AtomicBool::new(true));
+    let miner = {
+        let blockchain = Arc::clone(&node.blockchain);
+        let mining = Arc::clone(&mining);
+        let (miner_key, _) = new_account();
+        let miner = PublicKey::from_secret_key(&Secp256k1::new(), &miner_key);
+        thread::spawn(move || {
+            let mut blocks = 0;
+            while mining.load(Ordering::Relaxed) {
+                let mut chain = blockchain.lock().unwrap();
+                testkit::grant(&mut chain, &new_account().1, 1);
+                chain.mine_pending_transactions(&miner);
+                blocks += 1;
+            }
+            blocks
+        })
+    };
+
+    let mut tips = 0u64;
+    let started = Instant::now();
+    while started.elapsed() < WINDOW {
+        node.client
+            .get_tip(TipRequest {})
+            .await
+            .expect("GetTip succeeds");
+        tips += 1;
+    }
+
+    let mut infos = 0u64;
+    let started = Instant::now();
+    while started.elapsed() < WINDOW {
+        node.client
+            .get_chain_info(ChainInfoRequest {})
+            .await
+            .expect("GetChainInfo succeeds");
+        infos += 1;
+    }
+
+    mining.store(false, Ordering::Relaxed);
+    let blocks = miner.join().expect("miner doesn't panic");
+    let per_second = |count: u64| count as f64 / WINDOW.as_secs_f64();
+    println!(
+        "While mining {} blocks: GetTip {:.0}/s, GetChainInfo {:.0}/s",
+        blocks,
+        per_second(tips),
+        per_second(infos)
+    );
+}
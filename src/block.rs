@@ -1,8 +1,10 @@
-use chrono::{DateTime, Utc};
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Duration, Utc};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-use crate::transaction::Transaction;
+use crate::{hash, merkle, transaction::Transaction, MINING_REWARD};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -10,38 +12,360 @@ pub struct Block {
     pub timestamp: DateTime<Utc>,
     pub transactions: Vec<Transaction>,
     pub previous_hash: String,
+    pub merkle_root: String,
     pub hash: String,
     pub nonce: u64,
     pub miner: String,
+    // Root over account balances after this block; empty for blocks that predate it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub state_root: String,
+    // Treasury address and its share of the block subsidy, taken from the miner's reward
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub treasury_reward: Option<(String, u64)>,
+    // Part of each transaction's fee burned instead of paid to the miner; zero
+    // unless the node burns base fees
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub base_fee: u64,
+    // Arbitrary operator text, such as the genesis message; hashed when set
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub data: String,
+    // Subsidy minted when --max-supply held it below the schedule; None mints
+    // the full `reward_for_index`. Hashed when set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subsidy: Option<u64>,
+    // Consensus-specific header data, such as the PoW difficulty the block was
+    // sealed at; opaque to the chain and hashed when set
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub consensus_data: String,
+    // The producer's signature over the hash, checked against `miner` by
+    // consensuses where the miner is an identity rather than whoever did the
+    // work. Not hashed, since it signs the hash.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub producer_signature: Vec<u8>,
+}
+
+// A fully assembled block that the consensus hasn't sealed yet, e.g. by grinding
+// its nonce; it can be inspected but only becomes a chain block through
+// `Blockchain::seal_block`
+#[derive(Debug, Clone)]
+pub struct UnsealedBlock(Block);
+
+impl UnsealedBlock {
+    pub fn new(block: Block) -> Self {
+        Self(block)
+    }
+
+    pub fn block(&self) -> &Block {
+        &self.0
+    }
+
+    pub fn into_block(self) -> Block {
+        self.0
+    }
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
 }
 
 impl Block {
-    pub fn new(index: u64, transactions: Vec<Transaction>, previous_hash: String) -> Self {
+    // Builds the block following `previous`, or the genesis block when there is
+    // none, dated `now`. The timestamp is kept strictly after the parent's, even if
+    // the clock hasn't advanced or has gone backwards since the parent was mined.
+    pub fn new(
+        transactions: Vec<Transaction>,
+        previous: Option<&Block>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let timestamp = match previous {
+            Some(parent) => Self::next_timestamp(parent, now),
+            None => now,
+        };
+        Self::new_with_timestamp(transactions, previous, timestamp)
+    }
+
+    // Timestamp `new` gives the block following `parent` when built at `now`
+    pub fn next_timestamp(parent: &Block, now: DateTime<Utc>) -> DateTime<Utc> {
+        now.max(parent.timestamp + Duration::nanoseconds(1))
+    }
+
+    // Like `new`, but with a caller-chosen timestamp, so a block another node
+    // produced can be rebuilt exactly and hash the same
+    pub fn new_with_timestamp(
+        transactions: Vec<Transaction>,
+        previous: Option<&Block>,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        let (index, previous_hash) = match previous {
+            Some(parent) => (parent.index + 1, parent.hash.clone()),
+            None => (0, String::from("0")),
+        };
+
         let mut block = Self {
             index,
-            timestamp: Utc::now(),
+            timestamp,
             transactions,
             previous_hash,
+            merkle_root: String::new(),
             hash: String::new(),
             nonce: 0,
             miner: String::new(),
+            state_root: String::new(),
+            treasury_reward: None,
+            base_fee: 0,
+            data: String::new(),
+            subsidy: None,
+            consensus_data: String::new(),
+            producer_signature: Vec::new(),
         };
+        block.merkle_root = block.calculate_merkle_root();
         block.hash = block.calculate_hash();
         block
     }
 
+    // Transactions are committed through the merkle root so the hash can be
+    // recomputed from the header fields alone. The state root, base fee, data,
+    // subsidy, consensus data and treasury reward are only hashed when set, so
+    // blocks that predate them keep their hashes.
     pub fn calculate_hash(&self) -> String {
-        let mut hasher = Sha256::new();
-        let content = serde_json::to_string(&(
+        let header = (
             self.index,
             self.timestamp,
-            &self.transactions,
+            &self.merkle_root,
             &self.previous_hash,
             self.nonce,
-        ))
-        .unwrap();
+        );
+        let content = if let Some(treasury_reward) = &self.treasury_reward {
+            serde_json::to_string(&(
+                header,
+                &self.state_root,
+                self.base_fee,
+                &self.data,
+                self.subsidy,
+                &self.consensus_data,
+                treasury_reward,
+            ))
+        } else if !self.consensus_data.is_empty() {
+            serde_json::to_string(&(
+                header,
+                &self.state_root,
+                self.base_fee,
+                &self.data,
+                self.subsidy,
+                &self.consensus_data,
+            ))
+        } else if let Some(subsidy) = self.subsidy {
+            serde_json::to_string(&(header, &self.state_root, self.base_fee, &self.data, subsidy))
+        } else if !self.data.is_empty() {
+            serde_json::to_string(&(header, &self.state_root, self.base_fee, &self.data))
+        } else if self.base_fee != 0 {
+            serde_json::to_string(&(header, &self.state_root, self.base_fee))
+        } else if self.state_root.is_empty() {
+            serde_json::to_string(&header)
+        } else {
+            serde_json::to_string(&(header, &self.state_root))
+        };
+
+        hash::hex_digest(content.unwrap().as_bytes())
+    }
+
+    // Signs the final hash as the block's producer; any later change to the
+    // hashed fields invalidates the signature
+    pub fn sign_producer(&mut self, secret_key: &SecretKey) {
+        let message =
+            Message::from_slice(&hash::digest(self.hash.as_bytes())).expect("digests are 32 bytes");
+        self.producer_signature = Secp256k1::signing_only()
+            .sign_ecdsa(&message, secret_key)
+            .serialize_compact()
+            .to_vec();
+    }
+
+    // Whether the producer signature was made over this hash by the key `miner` names
+    pub fn has_valid_producer_signature(&self) -> bool {
+        let Some(public_key) = hex::decode(&self.miner)
+            .ok()
+            .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+        else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_compact(&self.producer_signature) else {
+            return false;
+        };
+        let message =
+            Message::from_slice(&hash::digest(self.hash.as_bytes())).expect("digests are 32 bytes");
+        Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature, &public_key)
+            .is_ok()
+    }
+
+    pub fn is_genesis(&self) -> bool {
+        self.index == 0
+    }
+
+    // Serialized length in bytes, as the block is stored
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).unwrap().len()
+    }
+
+    pub fn total_fees(&self) -> u64 {
+        self.transactions.iter().map(|tx| tx.fee).sum()
+    }
+
+    fn treasury_share(&self) -> u64 {
+        self.treasury_reward.as_ref().map_or(0, |(_, share)| *share)
+    }
+
+    // Each transaction burns up to the base fee; only the tip above it reaches the miner
+    pub fn burned_fees(&self) -> u64 {
+        self.transactions
+            .iter()
+            .map(|tx| tx.fee.min(self.base_fee))
+            .sum()
+    }
+
+    // New tokens the block mints: the scheduled subsidy unless it was capped
+    pub fn minted_subsidy(&self) -> u64 {
+        self.subsidy.unwrap_or_else(|| reward_for_index(self.index))
+    }
+
+    // The subsidy less the treasury share, plus the fees left after burning
+    pub fn miner_reward(&self) -> u64 {
+        self.minted_subsidy().saturating_sub(self.treasury_share()) + self.total_fees()
+            - self.burned_fees()
+    }
+
+    // The miner's reward is derived, so the only rewards a block can claim are a
+    // capped subsidy, which can't exceed the schedule for its height, and its
+    // treasury share, which must come out of the subsidy
+    pub fn has_valid_reward(&self) -> bool {
+        self.minted_subsidy() <= reward_for_index(self.index)
+            && self.treasury_share() <= self.minted_subsidy()
+    }
+
+    pub fn calculate_merkle_root(&self) -> String {
+        let hashes: Vec<String> = self.transactions.iter().map(Transaction::hash).collect();
+        merkle::merkle_root(&hashes)
+    }
+}
+
+static HALVING_INTERVAL: OnceLock<Option<u64>> = OnceLock::new();
+
+// Sets how many blocks pass between subsidy halvings, or None for a flat
+// subsidy. Part of the reward schedule every node must share, so it is set
+// once at startup, before any block is built or validated.
+pub fn init_reward_schedule(halving_interval: Option<u64>) -> Result<(), String> {
+    HALVING_INTERVAL
+        .set(halving_interval)
+        .map_err(|_| "Reward schedule already initialized".to_string())
+}
+
+fn schedule() -> RewardSchedule {
+    RewardSchedule {
+        halving_interval: *HALVING_INTERVAL.get_or_init(|| None),
+    }
+}
+
+// Halvings the subsidy has gone through by height `index`
+pub fn halvings_at(index: u64) -> u64 {
+    schedule().halvings_at(index)
+}
+
+// First height after `index` at which the subsidy halves again, if it ever does
+pub fn next_halving_after(index: u64) -> Option<u64> {
+    schedule().next_halving_after(index)
+}
+
+// Block subsidy at `index`: MINING_REWARD halved once per halving interval,
+// reaching zero after 64 halvings. The genesis block mints nothing.
+pub fn reward_for_index(index: u64) -> u64 {
+    schedule().reward_for_index(index)
+}
+
+// The subsidy schedule for a halving interval, behind the process-wide one
+#[derive(Clone, Copy)]
+struct RewardSchedule {
+    halving_interval: Option<u64>,
+}
 
-        hasher.update(content.as_bytes());
-        hex::encode(hasher.finalize())
+impl RewardSchedule {
+    fn halvings_at(self, index: u64) -> u64 {
+        self.halving_interval.map_or(0, |interval| index / interval)
+    }
+
+    fn next_halving_after(self, index: u64) -> Option<u64> {
+        self.halving_interval
+            .and_then(|interval| (self.halvings_at(index) + 1).checked_mul(interval))
+    }
+
+    fn reward_for_index(self, index: u64) -> u64 {
+        if index == 0 {
+            return 0;
+        }
+        u32::try_from(self.halvings_at(index))
+            .ok()
+            .and_then(|halvings| MINING_REWARD.checked_shr(halvings))
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> Block {
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        Block::new(Vec::new(), Some(&genesis), Utc::now())
+    }
+
+    #[test]
+    fn treasury_reward_is_hashed_only_when_set() {
+        let mut block = block();
+        let unpaid = block.calculate_hash();
+        assert_eq!(unpaid, block.hash);
+
+        block.treasury_reward = Some(("treasury".to_string(), 5));
+        let paid = block.calculate_hash();
+        assert_ne!(paid, unpaid);
+
+        block.treasury_reward = Some(("treasury".to_string(), 6));
+        assert_ne!(block.calculate_hash(), paid);
+        block.treasury_reward = Some(("elsewhere".to_string(), 5));
+        assert_ne!(block.calculate_hash(), paid);
+    }
+
+    #[test]
+    fn rebuilding_with_the_same_timestamp_reproduces_the_hash() {
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let transactions = vec![Transaction::new("from", "to", 5)];
+        let original = Block::new(transactions.clone(), Some(&genesis), Utc::now());
+
+        let rebuilt =
+            Block::new_with_timestamp(transactions.clone(), Some(&genesis), original.timestamp);
+        assert_eq!(rebuilt.hash, original.hash);
+        let later = original.timestamp + Duration::nanoseconds(1);
+        let moved = Block::new_with_timestamp(transactions, Some(&genesis), later);
+        assert_ne!(moved.hash, original.hash);
+    }
+
+    #[test]
+    fn subsidies_halve_at_each_interval_boundary() {
+        let halving = RewardSchedule {
+            halving_interval: Some(10),
+        };
+        let rewards: Vec<u64> = [0, 1, 9, 10, 19, 20]
+            .map(|index| halving.reward_for_index(index))
+            .to_vec();
+        let full = MINING_REWARD;
+        assert_eq!(rewards, [0, full, full, full / 2, full / 2, full / 4]);
+        assert_eq!((halving.halvings_at(9), halving.halvings_at(10)), (0, 1));
+        assert_eq!(halving.next_halving_after(9), Some(10));
+        assert_eq!(halving.next_halving_after(10), Some(20));
+        assert_eq!(halving.reward_for_index(640), 0);
+
+        let flat = RewardSchedule {
+            halving_interval: None,
+        };
+        assert_eq!(flat.reward_for_index(1_000_000), full);
+        assert_eq!(flat.next_halving_after(5), None);
     }
 }
@@ -0,0 +1,221 @@
+// Where the chain's blocks live. `MemoryBlockStore` keeps every block in RAM;
+// `FileBlockStore` writes each block to its own file and keeps only the most
+// recent ones resident, reading older blocks back on demand, for chains too
+// long to hold in memory.
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::block::Block;
+
+// Recent blocks a file-backed store keeps in memory unless configured otherwise
+pub const DEFAULT_MAX_RESIDENT_BLOCKS: usize = 1024;
+
+pub trait BlockStore: Send {
+    // Blocks in the chain, genesis included
+    fn len(&self) -> usize;
+    // Block at height `index`, borrowed when resident and loaded otherwise
+    fn get(&self, index: u64) -> Option<Cow<'_, Block>>;
+    // The tip, which is always resident
+    fn tip(&self) -> &Block;
+    fn push(&mut self, block: Block);
+    fn pop(&mut self) -> Option<Block>;
+    // The whole chain, when all of it is in memory
+    fn as_slice(&self) -> Option<&[Block]> {
+        None
+    }
+}
+
+impl dyn BlockStore {
+    pub fn height(&self) -> u64 {
+        self.len() as u64 - 1
+    }
+
+    // Blocks from height `from` to the tip, one at a time, so a replay over a
+    // paged chain never holds more than one evicted block
+    pub fn iter_from(&self, from: u64) -> impl DoubleEndedIterator<Item = Cow<'_, Block>> + '_ {
+        (from..self.len() as u64).map(|index| {
+            self.get(index)
+                .expect("every block up to the tip is stored")
+        })
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = Cow<'_, Block>> + '_ {
+        self.iter_from(0)
+    }
+
+    // Drops blocks from the tip until `len` remain
+    pub fn truncate(&mut self, len: usize) {
+        while self.len() > len {
+            self.pop();
+        }
+    }
+}
+
+pub struct MemoryBlockStore {
+    blocks: Vec<Block>,
+}
+
+impl MemoryBlockStore {
+    pub fn new(blocks: Vec<Block>) -> Self {
+        Self { blocks }
+    }
+}
+
+impl BlockStore for MemoryBlockStore {
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn get(&self, index: u64) -> Option<Cow<'_, Block>> {
+        self.blocks.get(index as usize).map(Cow::Borrowed)
+    }
+
+    fn tip(&self) -> &Block {
+        self.blocks
+            .last()
+            .expect("chain always has a genesis block")
+    }
+
+    fn push(&mut self, block: Block) {
+        self.blocks.push(block);
+    }
+
+    fn pop(&mut self) -> Option<Block> {
+        self.blocks.pop()
+    }
+
+    fn as_slice(&self) -> Option<&[Block]> {
+        Some(&self.blocks)
+    }
+}
+
+// Block N is saved as `<dir>/N.json` when it's added and removed when it's
+// popped. Only the last `max_resident` blocks stay in memory.
+pub struct FileBlockStore {
+    dir: PathBuf,
+    len: usize,
+    // The most recent blocks, oldest first, each with whether its file was
+    // written; a block is only evicted once it's safely on disk
+    resident: VecDeque<(Block, bool)>,
+    max_resident: usize,
+}
+
+impl FileBlockStore {
+    // Opens the store in `dir`, creating the directory if needed. Blocks already
+    // there, numbered contiguously from 0, make up the chain.
+    pub fn open(dir: &Path, max_resident: usize) -> Result<Self, String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create block store {}: {}", dir.display(), e))?;
+        let mut store = Self {
+            dir: dir.to_path_buf(),
+            len: 0,
+            resident: VecDeque::new(),
+            max_resident: max_resident.max(1),
+        };
+        while store.path_of(store.len as u64).exists() {
+            store.len += 1;
+        }
+        for index in store.len.saturating_sub(store.max_resident)..store.len {
+            let block = store.read(index as u64)?;
+            store.resident.push_back((block, true));
+        }
+        Ok(store)
+    }
+
+    fn path_of(&self, index: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", index))
+    }
+
+    fn read(&self, index: u64) -> Result<Block, String> {
+        let path = self.path_of(index);
+        let json = fs::read(&path)
+            .map_err(|e| format!("Failed to read block {}: {}", path.display(), e))?;
+        serde_json::from_slice(&json)
+            .map_err(|e| format!("Failed to parse block {}: {}", path.display(), e))
+    }
+
+    fn write(&self, block: &Block) -> Result<(), String> {
+        let path = self.path_of(block.index);
+        let json = serde_json::to_vec(block).expect("blocks serialize");
+        fs::write(&path, json)
+            .map_err(|e| format!("Failed to write block {}: {}", path.display(), e))
+    }
+
+    // Height of the oldest resident block
+    fn first_resident(&self) -> usize {
+        self.len - self.resident.len()
+    }
+}
+
+impl BlockStore for FileBlockStore {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, index: u64) -> Option<Cow<'_, Block>> {
+        let index = index as usize;
+        if index >= self.len {
+            return None;
+        }
+        if index >= self.first_resident() {
+            let (block, _) = &self.resident[index - self.first_resident()];
+            return Some(Cow::Borrowed(block));
+        }
+        match self.read(index as u64) {
+            Ok(block) => Some(Cow::Owned(block)),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            }
+        }
+    }
+
+    fn tip(&self) -> &Block {
+        let (block, _) = self
+            .resident
+            .back()
+            .expect("chain always has a genesis block");
+        block
+    }
+
+    fn push(&mut self, block: Block) {
+        let written = match self.write(&block) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("{}; keeping the block in memory", e);
+                false
+            }
+        };
+        self.resident.push_back((block, written));
+        self.len += 1;
+        while self.resident.len() > self.max_resident
+            && self.resident.front().is_some_and(|(_, written)| *written)
+        {
+            self.resident.pop_front();
+        }
+    }
+
+    fn pop(&mut self) -> Option<Block> {
+        let (block, written) = self.resident.pop_back()?;
+        self.len -= 1;
+        if written {
+            if let Err(e) = fs::remove_file(self.path_of(block.index)) {
+                warn!("Failed to remove block file for {}: {}", block.index, e);
+            }
+        }
+        // Keep the new tip resident
+        if self.resident.is_empty() && self.len > 0 {
+            match self.read(self.len as u64 - 1) {
+                Ok(tip) => self.resident.push_back((tip, true)),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        Some(block)
+    }
+}
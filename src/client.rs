@@ -0,0 +1,319 @@
+// Command-line client modes that query a running node over gRPC:
+//
+//     mockchain balance --address <addr> [--node <url>] [--json]
+//     mockchain chain-info [--node <url>] [--json]
+//     mockchain sign-raw --file <tx.json> --key-file <key> [--chain-id <n>] [--out <signed.json>]
+//     mockchain submit-raw --file <signed.json> [--node <url>] [--json]
+//
+// `sign-raw` never contacts a node, so transactions can be signed on an
+// air-gapped machine and carried over to one that broadcasts them.
+use std::{error::Error, fs, path::PathBuf};
+
+use secp256k1::{Secp256k1, SecretKey};
+use tonic::transport::Channel;
+
+use crate::{
+    blockchain::{
+        blockchain_service_client::BlockchainServiceClient, BalanceRequest, ChainInfoRequest,
+        ChainInfoResponse, Transaction as ProtoTransaction, TransactionResponse,
+    },
+    transaction::Transaction,
+};
+
+const DEFAULT_NODE: &str = "http://[::1]:50051";
+
+// Flags shared by every client command
+struct ClientArgs {
+    node: String,
+    address: Option<String>,
+    json: bool,
+    // Transaction JSON read by sign-raw and submit-raw
+    file: Option<PathBuf>,
+    // Hex secret key sign-raw signs with, kept in a file out of the process list
+    key_file: Option<PathBuf>,
+    chain_id: u64,
+    // Where sign-raw writes the signed transaction; stdout when unset
+    out: Option<PathBuf>,
+}
+
+impl ClientArgs {
+    fn parse(args: Vec<String>) -> Result<Self, String> {
+        let mut parsed = Self {
+            node: DEFAULT_NODE.to_string(),
+            address: None,
+            json: false,
+            file: None,
+            key_file: None,
+            chain_id: 0,
+            out: None,
+        };
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            if flag == "--json" {
+                parsed.json = true;
+                continue;
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", flag))?;
+            match flag.as_str() {
+                "--node" => parsed.node = value,
+                "--address" => parsed.address = Some(value),
+                "--file" => parsed.file = Some(PathBuf::from(value)),
+                "--key-file" => parsed.key_file = Some(PathBuf::from(value)),
+                "--chain-id" => {
+                    parsed.chain_id = value
+                        .parse()
+                        .map_err(|e| format!("Invalid value for --chain-id: {}", e))?
+                }
+                "--out" => parsed.out = Some(PathBuf::from(value)),
+                other => return Err(format!("Unknown option: {}", other)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+pub async fn connect(node: &str) -> Result<BlockchainServiceClient<Channel>, Box<dyn Error>> {
+    BlockchainServiceClient::connect(node.to_string())
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {}", node, e).into())
+}
+
+// Runs a client command and prints its result to stdout
+pub async fn run(command: &str, args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = ClientArgs::parse(args)?;
+
+    match command {
+        "balance" => {
+            let address = args.address.ok_or("balance requires --address")?;
+            let balance = connect(&args.node)
+                .await?
+                .get_balance(BalanceRequest {
+                    address: address.clone(),
+                })
+                .await?
+                .into_inner()
+                .balance;
+            println!("{}", format_balance(&address, balance, args.json));
+        }
+        "chain-info" => {
+            let info = connect(&args.node)
+                .await?
+                .get_chain_info(ChainInfoRequest {})
+                .await?
+                .into_inner();
+            println!("{}", format_chain_info(&info, args.json));
+        }
+        "sign-raw" => {
+            let file = args.file.ok_or("sign-raw requires --file")?;
+            let key_file = args.key_file.ok_or("sign-raw requires --key-file")?;
+            let unsigned = read_file(&file)?;
+            let key = read_file(&key_file)?;
+            let secret_key: SecretKey = key
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid secret key in {}: {}", key_file.display(), e))?;
+            let signed = sign_raw(&unsigned, &secret_key, args.chain_id)?;
+            match args.out {
+                Some(out) => fs::write(&out, signed)
+                    .map_err(|e| format!("Failed to write {}: {}", out.display(), e))?,
+                None => println!("{}", signed),
+            }
+        }
+        "submit-raw" => {
+            let file = args.file.ok_or("submit-raw requires --file")?;
+            let transaction = parse_raw(&read_file(&file)?)?;
+            let response = connect(&args.node)
+                .await?
+                .submit_transaction(ProtoTransaction::from(&transaction))
+                .await?
+                .into_inner();
+            println!(
+                "{}",
+                format_submission(&transaction.hash(), &response, args.json)
+            );
+            if !response.success {
+                return Err(format!("Transaction rejected: {}", response.message).into());
+            }
+        }
+        other => return Err(format!("Unknown command: {}", other).into()),
+    }
+    Ok(())
+}
+
+fn read_file(path: &PathBuf) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+}
+
+fn parse_raw(json: &str) -> Result<Transaction, String> {
+    serde_json::from_str(json).map_err(|e| format!("Invalid transaction file: {}", e))
+}
+
+// Signs a transaction file's contents for `chain_id`, returning the signed JSON.
+// A multisig transaction gains one more signature; otherwise the key must be
+// the sender's.
+fn sign_raw(json: &str, secret_key: &SecretKey, chain_id: u64) -> Result<String, String> {
+    let mut transaction = parse_raw(json)?;
+    let public_key = hex::encode(
+        secret_key
+            .public_key(&Secp256k1::signing_only())
+            .serialize(),
+    );
+
+    match &transaction.multisig {
+        Some(multisig) => {
+            if !multisig.public_keys.contains(&public_key) {
+                return Err("Key is not one of the multisig's keys".to_string());
+            }
+            transaction.sign_multisig(secret_key, chain_id);
+        }
+        None => {
+            if transaction.from != public_key {
+                return Err("Key does not match the transaction's sender".to_string());
+            }
+            transaction.sign(secret_key, chain_id);
+        }
+    }
+    Ok(serde_json::to_string_pretty(&transaction).expect("transactions serialize"))
+}
+
+fn format_submission(hash: &str, response: &TransactionResponse, json: bool) -> String {
+    if json {
+        serde_json::json!({
+            "hash": hash,
+            "success": response.success,
+            "message": response.message,
+        })
+        .to_string()
+    } else {
+        format!("{}: {}", hash, response.message)
+    }
+}
+
+fn format_balance(address: &str, balance: u64, json: bool) -> String {
+    if json {
+        serde_json::json!({ "address": address, "balance": balance }).to_string()
+    } else {
+        format!("Balance of {}: {}", address, balance)
+    }
+}
+
+fn format_chain_info(info: &ChainInfoResponse, json: bool) -> String {
+    if json {
+        return serde_json::json!({
+            "height": info.height,
+            "latest_hash": info.latest_hash,
+            "consensus": info.consensus,
+            "pending_transactions": info.pending_transactions,
+            "seconds_since_last_block": info.seconds_since_last_block,
+            "chain_id": info.chain_id,
+            "cumulative_work": info.cumulative_work,
+            "genesis_hash": info.genesis_hash,
+        })
+        .to_string();
+    }
+
+    [
+        format!("Height:               {}", info.height),
+        format!("Latest hash:          {}", info.latest_hash),
+        format!("Consensus:            {}", info.consensus),
+        format!("Pending transactions: {}", info.pending_transactions),
+        format!(
+            "Last block:           {}s ago",
+            info.seconds_since_last_block
+        ),
+        format!("Chain id:             {}", info.chain_id),
+        format!("Cumulative work:      {}", info.cumulative_work),
+        format!("Genesis hash:         {}", info.genesis_hash),
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        testkit::{new_account, TestNode},
+    };
+
+    #[tokio::test]
+    async fn query_output_is_formatted_from_the_node() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            chain_id: 9,
+            ..Config::default()
+        })
+        .await;
+        let (_, address) = node.funded_account(250);
+
+        let balance = node.balance(&address).await;
+        assert_eq!(
+            format_balance(&address, balance, false),
+            format!("Balance of {}: 250", address)
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_balance(&address, balance, true)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({ "address": address, "balance": 250 })
+        );
+
+        let info = node
+            .client
+            .get_chain_info(ChainInfoRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        let text = format_chain_info(&info, false);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "Height:               1");
+        assert_eq!(lines[2], "Consensus:            Dev Instant");
+        assert_eq!(lines[5], "Chain id:             9");
+        let json: serde_json::Value =
+            serde_json::from_str(&format_chain_info(&info, true)).unwrap();
+        assert_eq!(json["height"], 1);
+        assert_eq!(json["latest_hash"], info.latest_hash);
+        assert_eq!(json["chain_id"], 9);
+    }
+
+    #[tokio::test]
+    async fn raw_transactions_sign_offline_and_submit() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            chain_id: 9,
+            ..Config::default()
+        })
+        .await;
+        let (sender_key, sender) = node.funded_account(100);
+        let (other_key, recipient) = new_account();
+        let unsigned = serde_json::json!({
+            "from": sender,
+            "to": recipient,
+            "amount": 25,
+            "timestamp": 1_700_000_000,
+        })
+        .to_string();
+
+        let error = sign_raw(&unsigned, &other_key, 9).unwrap_err();
+        assert!(error.contains("does not match"), "{}", error);
+        let signed = parse_raw(&sign_raw(&unsigned, &sender_key, 9).unwrap()).unwrap();
+        assert!(signed.verify(9));
+
+        let response = node
+            .client
+            .submit_transaction(ProtoTransaction::from(&signed))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success, "{}", response.message);
+        let json: serde_json::Value =
+            serde_json::from_str(&format_submission(&signed.hash(), &response, true)).unwrap();
+        assert_eq!(json["hash"], signed.hash());
+        assert_eq!(json["success"], true);
+    }
+}
@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+// Source of the current time for everything time-dependent in the node: block
+// timestamps, transaction expiry, the pool TTL, heartbeats, and rate limits.
+// Injected so tests can run on virtual time instead of sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    // Seconds since the Unix epoch, the unit transaction timestamps use
+    fn unix_now(&self) -> u64 {
+        self.now().timestamp().max(0) as u64
+    }
+
+    // Time elapsed since `earlier`; zero if it's in the future
+    fn since(&self, earlier: DateTime<Utc>) -> Duration {
+        elapsed(earlier, self.now())
+    }
+}
+
+// Time from `earlier` to `later`, saturating at zero
+pub fn elapsed(earlier: DateTime<Utc>, later: DateTime<Utc>) -> Duration {
+    (later - earlier).to_std().unwrap_or_default()
+}
+
+// The wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    fmt::Display,
+    net::{Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use secp256k1::SecretKey;
+
+use crate::{
+    block_store::DEFAULT_MAX_RESIDENT_BLOCKS,
+    consensus::{BlockProduction, ConsensusParams},
+    hash::HashAlgo,
+    logging::{LogFormat, DEFAULT_LOG_MAX_BYTES},
+    p2p,
+    reputation::{DEFAULT_BAN_DURATION, DEFAULT_BAN_THRESHOLD},
+    storage::StorageFormat,
+    DEFAULT_BLOCK_GAS_LIMIT, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_MAX_BLOCK_BYTES,
+    DEFAULT_MAX_FUTURE_BLOCK_TIME, DEFAULT_MAX_PENDING_PER_SENDER, DEFAULT_MAX_REORG_DEPTH,
+    DEFAULT_MIN_RBF_BUMP_PERCENT, DEFAULT_RBF_MIN_INTERVAL,
+};
+
+// Node configuration, parsed from command-line flags
+#[derive(Debug)]
+pub struct Config {
+    // Address the gRPC server binds to
+    pub listen_addr: SocketAddr,
+    // File the chain is loaded from on startup and saved to after each block
+    pub chain_file: Option<PathBuf>,
+    pub storage_format: StorageFormat,
+    pub compress_chain: bool,
+    // Encrypts the chain file at rest; also needed to load an encrypted file
+    pub encrypt_passphrase: Option<String>,
+    // Alternative to `chain_file` for long chains: one file per block in this
+    // directory, with only the last `max_resident_blocks` kept in memory
+    pub block_store_dir: Option<PathBuf>,
+    pub max_resident_blocks: usize,
+    // Trust the chain file on startup instead of replaying it, for fast dev restarts
+    pub skip_validation: bool,
+    pub hash_algo: HashAlgo,
+    // gRPC endpoints of peer nodes, e.g. http://[::1]:50052
+    pub peers: Vec<String>,
+    // Enables developer-only RPCs such as UndoBlock
+    pub dev_mode: bool,
+    // When set, admin RPCs require `authorization: Bearer <token>` metadata
+    pub admin_token: Option<String>,
+    // Requests per second each client IP may make across all RPCs
+    pub rate_limit: Option<u32>,
+    // Reputation a peer may lose before it's banned, and for how long
+    pub ban_threshold: u32,
+    pub ban_duration: Duration,
+    // Minimum fee for non-faucet transactions; rises automatically when the pool is congested
+    pub min_fee: u64,
+    // Off with --no-zero-fee, which makes fees mandatory whatever the floor
+    pub allow_zero_fee: bool,
+    // Burns each block's base fee out of every fee instead of paying it to the
+    // miner; every node must agree on it, and on `min_fee` when it's set
+    pub burn_base_fee: bool,
+    // Supply past which blocks mint no subsidy; every node must agree on it
+    pub max_supply: Option<u64>,
+    // Blocks between block subsidy halvings; the subsidy stays flat when unset
+    pub halving_interval: Option<u64>,
+    // Height at which the upgrade making state roots and `valid_until` mandatory
+    // activates; every node must agree on it
+    pub activation_height: Option<u64>,
+    // Confirmations a faucet grant needs before it shows up in balances
+    pub faucet_confirmations: u64,
+    // Total the faucet may grant over the chain's lifetime; unlimited when unset
+    pub faucet_total_cap: Option<u64>,
+    // Receives `treasury_fraction` (0.0 to 1.0) of each block subsidy instead of the miner
+    pub treasury_address: Option<String>,
+    pub treasury_fraction: f64,
+    // Signed into every transaction so signatures can't be replayed across chains
+    pub chain_id: u64,
+    // Contents of a new chain's genesis block, which nodes must share to peer
+    pub genesis_time: Option<DateTime<Utc>>,
+    pub genesis_message: String,
+    // Largest encoded request the server will decode
+    pub max_request_bytes: usize,
+    // Deadline for unary RPCs and for delivering a whole server stream
+    pub rpc_timeout: Duration,
+    // Most blocks a single GetBlockRange page may return
+    pub max_block_range: u32,
+    // Blocks that must be mined on top of a transaction's block before GetFinality calls it final
+    pub finality_depth: u64,
+    // Name of a registered consensus: "pow", "pos", "poa", "dev", or a custom one
+    pub consensus: String,
+    // Proof of Work leading zero hex digits; `difficulty_bits` overrides it with a
+    // target of that many leading zero bits
+    pub difficulty: usize,
+    pub difficulty_bits: Option<u32>,
+    // Proof of Work nonce search threads
+    pub mining_threads: usize,
+    pub min_stake: u64,
+    // Produces every PoS block without a stake, for single-node testnets
+    pub bootstrap_validator: Option<String>,
+    // Proof of Authority producers, in turn order
+    pub authorities: Vec<String>,
+    // Signs the PoA and PoS blocks this node produces; it only produces blocks
+    // whose miner is this key's address
+    pub producer_key: Option<SecretKey>,
+    // Seeds PoS validator selection and throwaway miner keys for reproducible runs
+    pub rng_seed: Option<u64>,
+    pub block_production: BlockProduction,
+    // Mine empty blocks once the tip is `heartbeat_interval` old, for liveness on quiet chains
+    pub allow_empty_blocks: bool,
+    pub heartbeat_interval: Duration,
+    // Serialized transaction bytes a block may hold
+    pub max_block_bytes: usize,
+    // Total gas the transactions in a block may use
+    pub block_gas_limit: u64,
+    // Pool transactions one sender may have waiting at once
+    pub max_pending_per_sender: usize,
+    // Fee increase, in percent, and wait a replace-by-fee transaction needs
+    pub min_rbf_bump_percent: u64,
+    pub rbf_min_interval: Duration,
+    // Most blocks a replacement chain may revert, regardless of its length
+    pub max_reorg_depth: u64,
+    // Peer blocks timestamped further ahead of our clock than this are refused
+    pub max_future_block_time: Duration,
+    // How often expired transactions are swept from the pool
+    pub expiry_sweep_interval: Duration,
+    // Node-local limit on how long a transaction may wait in the pool
+    pub mempool_tx_ttl: Option<Duration>,
+    // Log to this file instead of stderr, rotating it once it grows past `log_max_bytes`
+    pub log_file: Option<PathBuf>,
+    pub log_max_bytes: u64,
+    pub log_format: LogFormat,
+    // Serve Prometheus metrics over HTTP at /metrics on this address
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from((Ipv6Addr::LOCALHOST, 50051)),
+            chain_file: None,
+            storage_format: StorageFormat::default(),
+            compress_chain: false,
+            encrypt_passphrase: None,
+            block_store_dir: None,
+            max_resident_blocks: DEFAULT_MAX_RESIDENT_BLOCKS,
+            skip_validation: false,
+            hash_algo: HashAlgo::default(),
+            peers: Vec::new(),
+            dev_mode: false,
+            admin_token: None,
+            rate_limit: None,
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            ban_duration: DEFAULT_BAN_DURATION,
+            min_fee: 0,
+            allow_zero_fee: true,
+            burn_base_fee: false,
+            max_supply: None,
+            halving_interval: None,
+            activation_height: None,
+            faucet_confirmations: 0,
+            faucet_total_cap: None,
+            treasury_address: None,
+            treasury_fraction: 0.0,
+            chain_id: 0,
+            genesis_time: None,
+            genesis_message: String::new(),
+            max_request_bytes: 64 * 1024,
+            rpc_timeout: Duration::from_secs(30),
+            max_block_range: 100,
+            finality_depth: 6,
+            consensus: "pow".to_string(),
+            difficulty: 3,
+            difficulty_bits: None,
+            mining_threads: 1,
+            min_stake: 1000,
+            bootstrap_validator: None,
+            authorities: Vec::new(),
+            producer_key: None,
+            rng_seed: None,
+            block_production: BlockProduction::default(),
+            allow_empty_blocks: false,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            max_block_bytes: DEFAULT_MAX_BLOCK_BYTES,
+            block_gas_limit: DEFAULT_BLOCK_GAS_LIMIT,
+            max_pending_per_sender: DEFAULT_MAX_PENDING_PER_SENDER,
+            min_rbf_bump_percent: DEFAULT_MIN_RBF_BUMP_PERCENT,
+            rbf_min_interval: DEFAULT_RBF_MIN_INTERVAL,
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            max_future_block_time: DEFAULT_MAX_FUTURE_BLOCK_TIME,
+            expiry_sweep_interval: Duration::from_secs(30),
+            mempool_tx_ttl: None,
+            log_file: None,
+            log_max_bytes: DEFAULT_LOG_MAX_BYTES,
+            log_format: LogFormat::default(),
+            metrics_addr: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, String> {
+        let mut config = Self::parse_args(std::env::args().skip(1))?;
+        if config.producer_key.is_none() {
+            if let Ok(key) = std::env::var(PRODUCER_KEY_ENV) {
+                config.producer_key = Some(parse(PRODUCER_KEY_ENV, key.trim())?);
+            }
+        }
+        Ok(config)
+    }
+
+    // Parses node flags, without the program name
+    pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            if flag == "--dev" {
+                config.dev_mode = true;
+                continue;
+            }
+            if flag == "--burn-base-fee" {
+                config.burn_base_fee = true;
+                continue;
+            }
+            if flag == "--no-zero-fee" {
+                config.allow_zero_fee = false;
+                continue;
+            }
+            if flag == "--compress" {
+                config.compress_chain = true;
+                continue;
+            }
+            if flag == "--skip-validation" {
+                config.skip_validation = true;
+                continue;
+            }
+            if flag == "--allow-empty-blocks" {
+                config.allow_empty_blocks = true;
+                continue;
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", flag))?;
+
+            match flag.as_str() {
+                "--listen" => config.listen_addr = parse(&flag, &value)?,
+                "--admin-token" => config.admin_token = Some(value),
+                "--max-supply" => config.max_supply = Some(parse(&flag, &value)?),
+                "--halving-interval" => config.halving_interval = Some(parse(&flag, &value)?),
+                "--activation-height" => config.activation_height = Some(parse(&flag, &value)?),
+                "--rate-limit" => config.rate_limit = Some(parse(&flag, &value)?),
+                "--ban-threshold" => config.ban_threshold = parse(&flag, &value)?,
+                "--ban-secs" => config.ban_duration = Duration::from_secs(parse(&flag, &value)?),
+                "--chain-file" => config.chain_file = Some(PathBuf::from(value)),
+                "--storage-format" => config.storage_format = parse(&flag, &value)?,
+                "--block-store-dir" => config.block_store_dir = Some(PathBuf::from(value)),
+                "--max-resident-blocks" => config.max_resident_blocks = parse(&flag, &value)?,
+                "--encrypt-passphrase" => config.encrypt_passphrase = Some(read_passphrase(value)?),
+                "--peer" => {
+                    p2p::validate_peer_addr(&value)?;
+                    config.peers.push(value)
+                }
+                "--hash-algo" => config.hash_algo = parse(&flag, &value)?,
+                "--min-fee" => config.min_fee = parse(&flag, &value)?,
+                "--faucet-confirmations" => config.faucet_confirmations = parse(&flag, &value)?,
+                "--faucet-cap" => config.faucet_total_cap = Some(parse(&flag, &value)?),
+                "--treasury-address" => config.treasury_address = Some(value),
+                "--treasury-fraction" => config.treasury_fraction = parse(&flag, &value)?,
+                "--chain-id" => config.chain_id = parse(&flag, &value)?,
+                "--genesis-time" => config.genesis_time = Some(parse(&flag, &value)?),
+                "--genesis-message" => config.genesis_message = value,
+                "--max-request-bytes" => config.max_request_bytes = parse(&flag, &value)?,
+                "--rpc-timeout-secs" => {
+                    config.rpc_timeout = Duration::from_secs(parse(&flag, &value)?)
+                }
+                "--max-block-range" => config.max_block_range = parse(&flag, &value)?,
+                "--finality-depth" => config.finality_depth = parse(&flag, &value)?,
+                "--consensus" => config.consensus = value,
+                "--difficulty" => config.difficulty = parse(&flag, &value)?,
+                "--difficulty-bits" => config.difficulty_bits = Some(parse(&flag, &value)?),
+                "--mining-threads" => config.mining_threads = parse(&flag, &value)?,
+                "--min-stake" => config.min_stake = parse(&flag, &value)?,
+                "--authority" => config.authorities.push(value),
+                "--bootstrap-validator" => config.bootstrap_validator = Some(value),
+                "--producer-key-file" => config.producer_key = Some(read_producer_key(&value)?),
+                "--max-block-bytes" => config.max_block_bytes = parse(&flag, &value)?,
+                "--block-gas-limit" => config.block_gas_limit = parse(&flag, &value)?,
+                "--max-pending-per-sender" => config.max_pending_per_sender = parse(&flag, &value)?,
+                "--min-rbf-bump-percent" => config.min_rbf_bump_percent = parse(&flag, &value)?,
+                "--rbf-interval-secs" => {
+                    config.rbf_min_interval = Duration::from_secs(parse(&flag, &value)?)
+                }
+                "--heartbeat-secs" => {
+                    config.heartbeat_interval = Duration::from_secs(parse(&flag, &value)?)
+                }
+                "--block-production" => config.block_production = parse(&flag, &value)?,
+                "--max-reorg-depth" => config.max_reorg_depth = parse(&flag, &value)?,
+                "--max-future-block-secs" => {
+                    config.max_future_block_time = Duration::from_secs(parse(&flag, &value)?)
+                }
+                "--expiry-sweep-secs" => {
+                    config.expiry_sweep_interval = Duration::from_secs(parse(&flag, &value)?)
+                }
+                "--mempool-ttl-secs" => {
+                    config.mempool_tx_ttl = Some(Duration::from_secs(parse(&flag, &value)?))
+                }
+                "--log-file" => config.log_file = Some(PathBuf::from(value)),
+                "--log-max-bytes" => config.log_max_bytes = parse(&flag, &value)?,
+                "--log-format" => config.log_format = parse(&flag, &value)?,
+                "--metrics-addr" => config.metrics_addr = Some(parse(&flag, &value)?),
+                "--rng-seed" => config.rng_seed = Some(parse(&flag, &value)?),
+                other => return Err(format!("Unknown option: {}", other)),
+            }
+        }
+
+        if !(0.0..=1.0).contains(&config.treasury_fraction) {
+            return Err("--treasury-fraction must be between 0 and 1".to_string());
+        }
+        if config.block_store_dir.is_some() && config.chain_file.is_some() {
+            return Err("--block-store-dir and --chain-file can't be used together".to_string());
+        }
+        if config.max_resident_blocks == 0 {
+            return Err("--max-resident-blocks must be at least 1".to_string());
+        }
+        if config.halving_interval == Some(0) {
+            return Err("--halving-interval must be at least 1".to_string());
+        }
+        if config.mining_threads == 0 {
+            return Err("--mining-threads must be at least 1".to_string());
+        }
+        if config.rate_limit == Some(0) {
+            return Err("--rate-limit must be at least 1".to_string());
+        }
+        if config.ban_threshold == 0 {
+            return Err("--ban-threshold must be at least 1".to_string());
+        }
+        if config.difficulty_bits.is_some_and(|bits| bits > 256) {
+            return Err("--difficulty-bits must be at most 256".to_string());
+        }
+        if config.consensus == "poa" && config.authorities.is_empty() {
+            return Err("--consensus poa needs at least one --authority".to_string());
+        }
+        Ok(config)
+    }
+
+    pub fn consensus_params(&self) -> ConsensusParams {
+        ConsensusParams {
+            difficulty: self.difficulty,
+            difficulty_bits: self.difficulty_bits,
+            mining_threads: self.mining_threads,
+            min_stake: self.min_stake,
+            rng_seed: self.rng_seed,
+            authorities: self.authorities.clone(),
+            bootstrap_validator: self.bootstrap_validator.clone(),
+        }
+    }
+}
+
+fn parse<T>(flag: &str, value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value for {}: {}", flag, e))
+}
+
+// Environment variable holding the producer key in hex, for deployments that
+// inject secrets through the environment rather than files
+pub const PRODUCER_KEY_ENV: &str = "MOCKCHAIN_PRODUCER_KEY";
+
+// Reads the hex producer key from a file, so the secret stays out of the
+// process list and shell history
+fn read_producer_key(path: &str) -> Result<SecretKey, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read the producer key from {}: {}", path, e))?;
+    parse("--producer-key-file", contents.trim())
+}
+
+// `-` prompts for the passphrase on stdin, keeping it out of the process list
+fn read_passphrase(value: String) -> Result<String, String> {
+    let passphrase = if value == "-" {
+        eprint!("Chain file passphrase: ");
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read the passphrase: {}", e))?;
+        line.trim_end_matches(['\r', '\n']).to_string()
+    } else {
+        value
+    };
+    if passphrase.is_empty() {
+        return Err("--encrypt-passphrase must not be empty".to_string());
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    #[test]
+    fn listens_on_localhost_unless_told_otherwise() {
+        let config = Config::parse_args(Vec::new()).unwrap();
+        assert_eq!(config.listen_addr, "[::1]:50051".parse().unwrap());
+
+        let config = Config::parse_args(args(&["--listen", "0.0.0.0:6000"])).unwrap();
+        assert_eq!(config.listen_addr, "0.0.0.0:6000".parse().unwrap());
+        assert!(Config::parse_args(args(&["--listen", "localhost"])).is_err());
+    }
+
+    #[test]
+    fn producer_key_is_read_from_a_file() {
+        let key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let path = std::env::temp_dir().join(format!("producer-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", hex::encode(key.secret_bytes()))).unwrap();
+
+        let file = path.to_str().unwrap();
+        let config = Config::parse_args(args(&["--producer-key-file", file])).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.producer_key, Some(key));
+
+        assert!(Config::parse_args(args(&["--producer-key-file", file])).is_err());
+        let inline = hex::encode(key.secret_bytes());
+        assert!(Config::parse_args(args(&["--producer-key", &inline])).is_err());
+    }
+}
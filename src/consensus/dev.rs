@@ -0,0 +1,161 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::info;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use tokio::task::JoinHandle;
+
+use crate::{block::Block, Blockchain};
+
+use super::Consensus;
+
+// The mining loop polls this often, so blocks appear almost as soon as a
+// transaction is accepted
+const DEV_BLOCK_INTERVAL: Duration = Duration::from_millis(100);
+// Fixed authority key, so every dev chain has the same block producer
+const DEV_SECRET_KEY: [u8; 32] = [1; 32];
+
+// Single-node authority for local development: no grinding and no stake
+pub struct DevInstant {
+    authority: PublicKey,
+}
+
+impl DevInstant {
+    pub fn new() -> Self {
+        let secret_key =
+            SecretKey::from_slice(&DEV_SECRET_KEY).expect("dev secret key is in range");
+        Self {
+            authority: secret_key.public_key(&Secp256k1::signing_only()),
+        }
+    }
+}
+
+impl Consensus for DevInstant {
+    fn name(&self) -> &str {
+        "Dev Instant"
+    }
+
+    fn select_miner(&self, _index: u64) -> Option<String> {
+        Some(hex::encode(self.authority.serialize()))
+    }
+
+    // Blocks are accepted as built, with nonce 0
+    fn seal_block(&self, block: Block) -> Block {
+        block
+    }
+
+    // The block must be intact, extend the previous one and be produced by the
+    // authority; there's no seal to check
+    fn validate_block(&self, block: &Block, previous_hash: &str) -> bool {
+        if block.previous_hash != previous_hash {
+            return false;
+        }
+
+        if block.merkle_root != block.calculate_merkle_root() {
+            return false;
+        }
+
+        if block.hash != block.calculate_hash() {
+            return false;
+        }
+
+        if !block.has_valid_reward() {
+            return false;
+        }
+
+        self.select_miner(block.index).as_ref() == Some(&block.miner)
+    }
+
+    fn start(&self, blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
+        let authority = self.authority;
+
+        tokio::spawn(async move {
+            loop {
+                {
+                    let mut chain = blockchain.lock().unwrap();
+                    chain.record_mining_round();
+                    if let Some(block) = chain.mine_pending_transactions(&authority) {
+                        info!(
+                            "Produced dev block {} with hash {}",
+                            block.index, block.hash
+                        );
+                    }
+                }
+                tokio::time::sleep(DEV_BLOCK_INTERVAL).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{
+        block::reward_for_index, testkit::new_account, transaction::Transaction,
+        FAUCET_MOCKCHAIN_ADDRESS,
+    };
+
+    #[test]
+    fn dev_blocks_have_nonce_zero_and_validate() {
+        let dev = DevInstant::new();
+        let genesis = dev.seal_block(Block::new(Vec::new(), None, Utc::now()));
+        let mut block = Block::new(Vec::new(), Some(&genesis), Utc::now());
+        block.miner = dev.select_miner(1).unwrap();
+        block.hash = block.calculate_hash();
+        let block = dev.seal_block(block);
+
+        assert_eq!(block.nonce, 0);
+        assert!(dev.validate_block(&block, &genesis.hash));
+        assert!(!dev.validate_block(&block, &block.hash));
+
+        let mut tampered = block.clone();
+        tampered.nonce = 1;
+        assert!(!dev.validate_block(&tampered, &genesis.hash));
+    }
+
+    // A block on `genesis` paying `to`, mined by the dev authority
+    fn authority_block(dev: &DevInstant, genesis: &Block, to: &str) -> Block {
+        let grant = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, to, 10);
+        let mut block = Block::new(vec![grant], Some(genesis), Utc::now());
+        block.miner = dev.select_miner(1).unwrap();
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    #[test]
+    fn dev_blocks_must_commit_to_their_transactions() {
+        let dev = DevInstant::new();
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let block = authority_block(&dev, &genesis, &new_account().1);
+        assert!(dev.validate_block(&block, &genesis.hash));
+
+        let mut swapped = block.clone();
+        swapped.transactions[0].to = new_account().1;
+        swapped.hash = swapped.calculate_hash();
+        assert!(!dev.validate_block(&swapped, &genesis.hash));
+    }
+
+    #[test]
+    fn dev_blocks_may_not_mint_above_the_schedule() {
+        let dev = DevInstant::new();
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let mut inflated = authority_block(&dev, &genesis, &new_account().1);
+        inflated.subsidy = Some(reward_for_index(1) + 1);
+        inflated.hash = inflated.calculate_hash();
+        assert!(!dev.validate_block(&inflated, &genesis.hash));
+    }
+
+    #[test]
+    fn only_the_dev_authority_may_produce() {
+        let dev = DevInstant::new();
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let mut usurped = authority_block(&dev, &genesis, &new_account().1);
+        usurped.miner = new_account().1;
+        usurped.hash = usurped.calculate_hash();
+        assert!(!dev.validate_block(&usurped, &genesis.hash));
+    }
+}
@@ -1,37 +1,265 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use dev::DevInstant;
+use log::warn;
+use poa::ProofOfAuthority;
+use pos::ProofOfStake;
+pub use pow::target_for_bits;
 use pow::ProofOfWork;
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1};
+use tokio::task::JoinHandle;
 
-use crate::{block::Block, transaction::Transaction, Blockchain};
+use crate::{block::Block, entropy, hash, transaction::Transaction, Blockchain};
 
+mod dev;
+mod poa;
+mod pos;
 mod pow;
+
+// How often the mining loop checks the transaction pool, in seconds
+pub const MINING_INTERVAL_SECS: u64 = 10;
+
+// Whether the consensus mining loop runs, or blocks are only produced on request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockProduction {
+    #[default]
+    Automatic,
+    // For CI: no mining loop; blocks come from ForceMine (and the faucet)
+    Manual,
+}
+
+impl FromStr for BlockProduction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "automatic" => Ok(Self::Automatic),
+            "manual" => Ok(Self::Manual),
+            other => Err(format!("Unknown block production mode: {}", other)),
+        }
+    }
+}
+
 // Consensus trait defines how blocks are produced and validated
 pub trait Consensus: Send + Sync {
-    fn generate_block(
-        &self,
-        index: u64,
-        transactions: Vec<Transaction>,
-        previous_hash: String,
-    ) -> Block;
+    // Picks the producer of block `index`; None lets the node's own miner key claim it
+    fn select_miner(&self, _index: u64) -> Option<String> {
+        None
+    }
+    // Fills in the consensus data a block commits to before it's sealed, such as
+    // the PoW target bits, so an assembled block is complete
+    fn prepare_block(&self, block: Block) -> Block {
+        block
+    }
+    // Finalizes a fully built candidate block, e.g. by grinding its nonce
+    fn seal_block(&self, block: Block) -> Block;
+    // Target bits a block's hash must meet, for consensuses an external miner
+    // can seal; None when sealing needs the node itself
+    fn target_bits(&self) -> Option<u32> {
+        None
+    }
     fn validate_block(&self, block: &Block, previous_hash: &str) -> bool;
-    fn start(&self, blockchain: Arc<Mutex<Blockchain>>);
+    // Whether blocks must carry their miner's producer signature, for
+    // consensuses where the miner is an identity rather than whoever did the work
+    fn signs_blocks(&self) -> bool {
+        false
+    }
+    // Difficulty `block` was sealed at, in target bits, read from its consensus
+    // data; None for consensuses without one and blocks that predate it
+    fn block_difficulty(&self, _block: &Block) -> Option<u32> {
+        None
+    }
+    // Consensus-specific transaction acceptance rules, checked on top of signature and balance
+    fn validate_transaction(&self, _transaction: &Transaction) -> bool {
+        true
+    }
+    // Registers `address` as a block producer; only stake-based consensuses accept stakes
+    fn register_stake(&self, _address: &str, _amount: u64) -> Result<(), String> {
+        Err(format!("{} does not use stakes", self.name()))
+    }
+    // Stake of a registered validator; consensuses without stakes have no validators
+    fn validator_stake(&self, _address: &str) -> Option<u64> {
+        None
+    }
+    // Every registered validator and its stake, for persisting across restarts
+    fn validators(&self) -> Vec<(String, u64)> {
+        Vec::new()
+    }
+    // Fork choice: a replacement chain must be heavier than ours, summing this
+    // over the blocks past the fork. By default every block weighs the same, so
+    // the longest chain wins.
+    fn block_weight(&self, _block: &Block) -> u128 {
+        1
+    }
+    fn start(&self, blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()>;
     fn name(&self) -> &str;
 }
 
-// Available consensus types
-#[derive(Debug)]
-pub enum ConsensusType {
-    ProofOfWorkType { difficulty: usize },
-    ProofOfStakeType { min_stake: u64 },
+// A throwaway key for a mining task to claim blocks with. Drawing it can fail
+// when the RNG does, so the task logs and retries instead of panicking.
+async fn throwaway_miner_key(blockchain: &Mutex<Blockchain>) -> PublicKey {
+    loop {
+        let drawn = entropy::generate_keypair(&mut blockchain.lock().unwrap().rng);
+        match drawn {
+            Ok((_, key)) => return key,
+            Err(e) => warn!("Failed to generate a miner key, retrying: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(MINING_INTERVAL_SECS)).await;
+    }
+}
+
+// Digest a validator signs to stake `amount` on chain `chain_id`, so only the
+// holder of an address's key can register it
+pub fn stake_message(address: &str, amount: u64, chain_id: u64) -> [u8; 32] {
+    let content = serde_json::json!(["register_stake", address, amount, chain_id]).to_string();
+    hash::digest(content.as_bytes())
+}
+
+// Whether `signature` is the key behind `address` signing its stake message
+pub fn verify_stake_signature(address: &str, amount: u64, chain_id: u64, signature: &[u8]) -> bool {
+    let Some(public_key) = hex::decode(address)
+        .ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_compact(signature) else {
+        return false;
+    };
+    let message = Message::from_slice(&stake_message(address, amount, chain_id))
+        .expect("digests are 32 bytes");
+    Secp256k1::verification_only()
+        .verify_ecdsa(&message, &signature, &public_key)
+        .is_ok()
+}
+
+// Tuning knobs handed to consensus factories; each factory reads the ones it needs
+#[derive(Debug, Clone)]
+pub struct ConsensusParams {
+    pub difficulty: usize,
+    pub difficulty_bits: Option<u32>,
+    pub mining_threads: usize,
+    pub min_stake: u64,
+    pub rng_seed: Option<u64>,
+    pub authorities: Vec<String>,
+    pub bootstrap_validator: Option<String>,
+}
+
+pub type ConsensusFactory = Box<dyn Fn(&ConsensusParams) -> Box<dyn Consensus> + Send + Sync>;
+
+// Named consensus factories, so new algorithms can be plugged in without touching
+// this module. PoW ("pow"), PoS ("pos"), PoA ("poa") and instant dev blocks
+// ("dev") are registered by default.
+pub struct ConsensusRegistry {
+    factories: HashMap<String, ConsensusFactory>,
+}
+
+impl Default for ConsensusRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.register("pow", |params| {
+            let pow = match params.difficulty_bits {
+                Some(bits) => ProofOfWork::with_target_bits(bits),
+                None => ProofOfWork::new(params.difficulty),
+            };
+            Box::new(pow.with_threads(params.mining_threads))
+        });
+        registry.register("pos", |params| {
+            Box::new(ProofOfStake::new(
+                params.min_stake,
+                params.rng_seed,
+                params.bootstrap_validator.clone(),
+            ))
+        });
+        registry.register("poa", |params| {
+            Box::new(ProofOfAuthority::new(params.authorities.clone()))
+        });
+        registry.register("dev", |_| Box::new(DevInstant::new()));
+        registry
+    }
+}
+
+impl ConsensusRegistry {
+    // Registers a factory under `name`, replacing any existing one
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(&ConsensusParams) -> Box<dyn Consensus> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    pub fn create(
+        &self,
+        name: &str,
+        params: &ConsensusParams,
+    ) -> Result<Box<dyn Consensus>, String> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| format!("Unknown consensus: {}", name))?;
+        Ok(factory(params))
+    }
 }
 
-impl ConsensusType {
-    pub fn create_consensus(&self) -> Box<dyn Consensus> {
-        match self {
-            ConsensusType::ProofOfWorkType { difficulty } => {
-                Box::new(ProofOfWork::new(*difficulty))
-            }
-            ConsensusType::ProofOfStakeType { min_stake: _ } => todo!(),
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::config::Config;
+
+    // An empty block on `parent` naming `miner` and signed with `key`, for the
+    // engines that check who produced a block
+    pub(super) fn signed_block(parent: &Block, miner: &str, key: &secp256k1::SecretKey) -> Block {
+        let mut block = Block::new(Vec::new(), Some(parent), Utc::now());
+        block.miner = miner.to_string();
+        block.hash = block.calculate_hash();
+        block.sign_producer(key);
+        block
+    }
+
+    // Accepts any block that extends the previous one
+    struct Anything;
+
+    impl Consensus for Anything {
+        fn seal_block(&self, block: Block) -> Block {
+            block
         }
+
+        fn validate_block(&self, block: &Block, previous_hash: &str) -> bool {
+            block.previous_hash == previous_hash
+        }
+
+        fn start(&self, _blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
+            tokio::spawn(async {})
+        }
+
+        fn name(&self) -> &str {
+            "Anything"
+        }
+    }
+
+    #[test]
+    fn custom_consensus_is_created_by_name() {
+        let params = Config::default().consensus_params();
+        let mut registry = ConsensusRegistry::default();
+        assert!(registry.create("anything", &params).is_err());
+
+        registry.register("anything", |_| Box::new(Anything));
+        assert_eq!(
+            registry.create("anything", &params).unwrap().name(),
+            "Anything"
+        );
+        assert_eq!(
+            registry.create("pow", &params).unwrap().name(),
+            "Proof of Work"
+        );
     }
 }
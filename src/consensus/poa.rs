@@ -0,0 +1,127 @@
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+use crate::{block::Block, Blockchain};
+
+use super::{throwaway_miner_key, Consensus, MINING_INTERVAL_SECS};
+
+// Proof of Authority for permissioned networks: a fixed, ordered list of
+// authorities takes turns, block N belonging to `authorities[N % len]`
+pub struct ProofOfAuthority {
+    authorities: Vec<String>,
+}
+
+impl ProofOfAuthority {
+    pub fn new(authorities: Vec<String>) -> Self {
+        Self { authorities }
+    }
+
+    fn authority_for(&self, index: u64) -> Option<&String> {
+        if self.authorities.is_empty() {
+            return None;
+        }
+        self.authorities
+            .get((index % self.authorities.len() as u64) as usize)
+    }
+}
+
+impl Consensus for ProofOfAuthority {
+    fn name(&self) -> &str {
+        "Proof of Authority"
+    }
+
+    fn select_miner(&self, index: u64) -> Option<String> {
+        self.authority_for(index).cloned()
+    }
+
+    // The scheduled authority is the block's producer; there is nothing to grind
+    fn seal_block(&self, block: Block) -> Block {
+        block
+    }
+
+    fn validate_block(&self, block: &Block, previous_hash: &str) -> bool {
+        if block.previous_hash != previous_hash {
+            return false;
+        }
+
+        if block.merkle_root != block.calculate_merkle_root() {
+            return false;
+        }
+
+        if block.hash != block.calculate_hash() {
+            return false;
+        }
+
+        if !block.has_valid_reward() {
+            return false;
+        }
+
+        // Naming an authority as miner isn't enough; the block must be signed by its key
+        if !block.has_valid_producer_signature() {
+            return false;
+        }
+
+        self.authority_for(block.index) == Some(&block.miner)
+    }
+
+    fn signs_blocks(&self) -> bool {
+        true
+    }
+
+    fn start(&self, blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
+        let has_authorities = !self.authorities.is_empty();
+
+        tokio::spawn(async move {
+            // Never used as the miner while authorities are configured
+            let fallback_key = throwaway_miner_key(&blockchain).await;
+
+            loop {
+                {
+                    let mut chain = blockchain.lock().unwrap();
+                    chain.record_mining_round();
+                    if !has_authorities {
+                        if !chain.transaction_pool.is_empty() {
+                            warn!("PoA has pending transactions but no configured authorities");
+                        }
+                    } else if let Some(block) = chain.mine_pending_transactions(&fallback_key) {
+                        info!(
+                            "Authority {} sealed block {} with hash {}",
+                            block.miner, block.index, block.hash
+                        );
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_secs(MINING_INTERVAL_SECS)).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{consensus::tests::signed_block, testkit::new_account};
+
+    #[test]
+    fn only_the_scheduled_authority_may_produce() {
+        let (first_key, first) = new_account();
+        let (second_key, second) = new_account();
+        let poa = ProofOfAuthority::new(vec![first.clone(), second.clone()]);
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+
+        // Block 1 belongs to the second authority
+        assert_eq!(poa.select_miner(1), Some(second.clone()));
+        let block = signed_block(&genesis, &second, &second_key);
+        assert!(poa.validate_block(&block, &genesis.hash));
+
+        let wrong_turn = signed_block(&genesis, &first, &first_key);
+        assert!(!poa.validate_block(&wrong_turn, &genesis.hash));
+
+        // Naming the right authority without its key doesn't pass either
+        let forged = signed_block(&genesis, &second, &first_key);
+        assert!(!poa.validate_block(&forged, &genesis.hash));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use log::{info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::task::JoinHandle;
+
+use crate::{block::Block, entropy, Blockchain};
+
+use super::{throwaway_miner_key, Consensus, MINING_INTERVAL_SECS};
+
+// Proof of Stake implementation
+pub struct ProofOfStake {
+    min_stake: u64,
+    // Ordered by address so a seeded selection is reproducible
+    validators: Arc<Mutex<BTreeMap<String, u64>>>,
+    rng: Mutex<StdRng>,
+    // Always selected when set, bypassing stakes entirely
+    bootstrap: Option<String>,
+}
+
+impl ProofOfStake {
+    // With a seed, the same stakes always produce the same sequence of validators
+    pub fn new(min_stake: u64, rng_seed: Option<u64>, bootstrap: Option<String>) -> Self {
+        let rng = match rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => entropy::os_seeded(),
+        };
+
+        Self {
+            min_stake,
+            validators: Arc::new(Mutex::new(BTreeMap::new())),
+            rng: Mutex::new(rng),
+            bootstrap,
+        }
+    }
+
+    // Picks the bootstrap validator if there is one, otherwise a registered
+    // validator with probability proportional to its stake
+    fn select_validator(&self) -> Option<String> {
+        if let Some(bootstrap) = &self.bootstrap {
+            return Some(bootstrap.clone());
+        }
+
+        let validators = self.validators.lock().unwrap();
+        let total_stake: u64 = validators.values().sum();
+        if total_stake == 0 {
+            return None;
+        }
+
+        let mut target = self.rng.lock().unwrap().gen_range(0..total_stake);
+        for (address, stake) in validators.iter() {
+            if target < *stake {
+                return Some(address.clone());
+            }
+            target -= stake;
+        }
+        None
+    }
+}
+
+impl Consensus for ProofOfStake {
+    fn name(&self) -> &str {
+        "Proof of Stake"
+    }
+
+    fn select_miner(&self, _index: u64) -> Option<String> {
+        self.select_validator()
+    }
+
+    // The selected validator is the block's authority; there is nothing to grind
+    fn seal_block(&self, block: Block) -> Block {
+        block
+    }
+
+    fn validate_block(&self, block: &Block, previous_hash: &str) -> bool {
+        if block.previous_hash != previous_hash {
+            return false;
+        }
+
+        if block.merkle_root != block.calculate_merkle_root() {
+            return false;
+        }
+
+        if block.hash != block.calculate_hash() {
+            return false;
+        }
+
+        if !block.has_valid_reward() {
+            return false;
+        }
+
+        // Naming a validator as miner isn't enough; the block must be signed by its key
+        if !block.has_valid_producer_signature() {
+            return false;
+        }
+
+        if self.bootstrap.as_ref() == Some(&block.miner) {
+            return true;
+        }
+
+        self.validators
+            .lock()
+            .unwrap()
+            .get(&block.miner)
+            .is_some_and(|stake| *stake >= self.min_stake)
+    }
+
+    fn signs_blocks(&self) -> bool {
+        true
+    }
+
+    fn register_stake(&self, address: &str, amount: u64) -> Result<(), String> {
+        if amount < self.min_stake {
+            return Err(format!(
+                "Stake {} is below the minimum of {}",
+                amount, self.min_stake
+            ));
+        }
+
+        info!("Registered validator {} with stake {}", address, amount);
+        self.validators
+            .lock()
+            .unwrap()
+            .insert(address.to_string(), amount);
+        Ok(())
+    }
+
+    fn validator_stake(&self, address: &str) -> Option<u64> {
+        self.validators.lock().unwrap().get(address).copied()
+    }
+
+    fn validators(&self) -> Vec<(String, u64)> {
+        self.validators
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, stake)| (address.clone(), *stake))
+            .collect()
+    }
+
+    fn start(&self, blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
+        let validators = Arc::clone(&self.validators);
+        let has_bootstrap = self.bootstrap.is_some();
+
+        tokio::spawn(async move {
+            // Only used when no validator is selected; PoS blocks carry the validator as miner
+            let fallback_key = throwaway_miner_key(&blockchain).await;
+
+            loop {
+                {
+                    let mut chain = blockchain.lock().unwrap();
+                    chain.record_mining_round();
+                    if !has_bootstrap && validators.lock().unwrap().is_empty() {
+                        if !chain.transaction_pool.is_empty() {
+                            warn!("PoS has pending transactions but no registered validators");
+                        }
+                    } else if let Some(block) = chain.mine_pending_transactions(&fallback_key) {
+                        info!(
+                            "Validator {} forged block {} with hash {}",
+                            block.miner, block.index, block.hash
+                        );
+                    }
+                }
+                tokio::time::sleep(tokio::time::Duration::from_secs(MINING_INTERVAL_SECS)).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::{consensus::tests::signed_block, testkit::new_account};
+
+    #[test]
+    fn bootstrap_validator_produces_every_block() {
+        let (bootstrap_key, bootstrap) = new_account();
+        let (staker_key, staker) = new_account();
+        let pos = ProofOfStake::new(100, Some(7), Some(bootstrap.clone()));
+        pos.register_stake(&staker, 1_000).unwrap();
+        for index in 1..50 {
+            assert_eq!(pos.select_miner(index), Some(bootstrap.clone()));
+        }
+
+        // The bootstrap validator needs no stake, while others still do
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let block = signed_block(&genesis, &bootstrap, &bootstrap_key);
+        assert!(pos.validate_block(&block, &genesis.hash));
+        let staked = signed_block(&genesis, &staker, &staker_key);
+        assert!(pos.validate_block(&staked, &genesis.hash));
+        let (outsider_key, outsider) = new_account();
+        let unstaked = signed_block(&genesis, &outsider, &outsider_key);
+        assert!(!pos.validate_block(&unstaked, &genesis.hash));
+    }
+}
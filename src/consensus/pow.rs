@@ -1,20 +1,113 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
 
 use log::info;
-use secp256k1::Secp256k1;
+use tokio::task::JoinHandle;
 
-use crate::{block::Block, blockchain::Transaction, Blockchain};
+use crate::{block::Block, metrics, Blockchain};
 
-use super::Consensus;
+use super::{throwaway_miner_key, Consensus, MINING_INTERVAL_SECS};
 
-// Proof of Work implementation
+// Target of 2^(256 - bits): the hash needs `bits` leading zero bits, so each
+// step only doubles the work. Zero bits accepts every hash.
+pub fn target_for_bits(bits: u32) -> [u8; 32] {
+    let mut target = [0xff; 32];
+    if bits > 0 {
+        target = [0; 32];
+        if bits < 256 {
+            let bit = 256 - bits as usize;
+            target[31 - bit / 8] = 1 << (bit % 8);
+        }
+    }
+    target
+}
+
+// Expected hashing effort behind a block mined to a target of `bits`: 2 to the
+// power of the bits, capped so the sum over a chain can't overflow in practice
+pub fn work_for_bits(bits: u32) -> u128 {
+    1 << bits.min(124)
+}
+
+// Proof of Work implementation: a block's hash, read as a 256-bit big-endian
+// integer, must be below the target
 pub struct ProofOfWork {
-    difficulty: usize,
+    target: [u8; 32],
+    // Leading zero bits the target requires, the difficulty label for metrics
+    bits: u32,
+    // Nonce search workers per block
+    threads: usize,
 }
 
 impl ProofOfWork {
+    // `difficulty` leading zero hex digits, i.e. four target bits each
     pub fn new(difficulty: usize) -> Self {
-        Self { difficulty }
+        Self::with_target_bits(difficulty.saturating_mul(4).min(256) as u32)
+    }
+
+    pub fn with_target_bits(bits: u32) -> Self {
+        Self {
+            target: target_for_bits(bits),
+            bits,
+            threads: 1,
+        }
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    // With several threads, worker `i` tries nonces i, i + threads, ... so the
+    // ranges are disjoint. The first to find a valid nonce stops the others, and
+    // every worker has exited by the time the scope returns.
+    fn grind(&self, mut block: Block) -> Block {
+        if self.threads == 1 || self.meets_target(&block.hash) {
+            while !self.meets_target(&block.hash) {
+                block.nonce += 1;
+                block.hash = block.calculate_hash();
+            }
+            return block;
+        }
+
+        let found = AtomicBool::new(false);
+        let sealed = Mutex::new(None);
+        thread::scope(|scope| {
+            for worker in 0..self.threads as u64 {
+                let mut candidate = block.clone();
+                let (found, sealed) = (&found, &sealed);
+                scope.spawn(move || {
+                    candidate.nonce = candidate.nonce.wrapping_add(worker);
+                    while !found.load(Ordering::Relaxed) {
+                        candidate.hash = candidate.calculate_hash();
+                        if self.meets_target(&candidate.hash) {
+                            if !found.swap(true, Ordering::Relaxed) {
+                                *sealed.lock().unwrap() = Some(candidate);
+                            }
+                            return;
+                        }
+                        candidate.nonce = candidate.nonce.wrapping_add(self.threads as u64);
+                    }
+                });
+            }
+        });
+
+        sealed
+            .into_inner()
+            .unwrap()
+            .expect("a worker only stops early once a block is sealed")
+    }
+
+    fn meets_target(&self, hash: &str) -> bool {
+        // Hex digests are big-endian, so comparing the bytes compares the integers.
+        // The zero-bit target is all ones, which no hash is below, so it's special-cased.
+        hex::decode(hash)
+            .is_ok_and(|bytes| self.bits == 0 || bytes.as_slice() < self.target.as_slice())
     }
 }
 
@@ -23,20 +116,18 @@ impl Consensus for ProofOfWork {
         "Proof of Work"
     }
 
-    fn generate_block(
-        &self,
-        index: u64,
-        transactions: Vec<Transaction>,
-        previous_hash: String,
-    ) -> Block {
-        let mut block = Block::new(index, transactions, previous_hash);
-
-        let target = "0".repeat(self.difficulty);
-        while !block.hash.starts_with(&target) {
-            block.nonce += 1;
-            block.hash = block.calculate_hash();
-        }
+    // Records the target in the header, so the nonce search covers it
+    fn prepare_block(&self, mut block: Block) -> Block {
+        block.consensus_data = self.bits.to_string();
+        block.hash = block.calculate_hash();
+        block
+    }
 
+    fn seal_block(&self, block: Block) -> Block {
+        let block = self.prepare_block(block);
+        let started = Instant::now();
+        let block = self.grind(block);
+        metrics::observe_mining_time(self.bits, started.elapsed());
         block
     }
 
@@ -45,17 +136,44 @@ impl Consensus for ProofOfWork {
             return false;
         }
 
+        if block.merkle_root != block.calculate_merkle_root() {
+            return false;
+        }
+
         if block.hash != block.calculate_hash() {
             return false;
         }
 
-        block.hash.starts_with(&"0".repeat(self.difficulty))
+        if !block.has_valid_reward() {
+            return false;
+        }
+
+        // Blocks from before difficulty was recorded carry none
+        if !block.consensus_data.is_empty() && self.block_difficulty(block) != Some(self.bits) {
+            return false;
+        }
+
+        self.meets_target(&block.hash)
     }
 
-    fn start(&self, blockchain: Arc<Mutex<Blockchain>>) {
+    fn target_bits(&self) -> Option<u32> {
+        Some(self.bits)
+    }
+
+    fn block_difficulty(&self, block: &Block) -> Option<u32> {
+        block.consensus_data.parse().ok()
+    }
+
+    // Most cumulative work wins, which stays correct if difficulty ever varies.
+    // Work follows the target the block was validated against, not how far its
+    // hash happened to beat it, so a lucky hash doesn't sway fork choice.
+    fn block_weight(&self, block: &Block) -> u128 {
+        work_for_bits(self.block_difficulty(block).unwrap_or(self.bits))
+    }
+
+    fn start(&self, blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let secp = Secp256k1::new();
-            let (_, miner_key) = secp.generate_keypair(&mut rand::thread_rng());
+            let miner_key = throwaway_miner_key(&blockchain).await;
             info!(
                 "PoW mining with address: {}",
                 hex::encode(miner_key.serialize())
@@ -64,14 +182,73 @@ impl Consensus for ProofOfWork {
             loop {
                 {
                     let mut chain = blockchain.lock().unwrap();
-                    if chain.transaction_pool.len() > 10 {
+                    chain.record_mining_round();
+                    if chain.transaction_pool.len() > 10 || chain.heartbeat_due() {
                         if let Some(block) = chain.mine_pending_transactions(&miner_key) {
                             info!("Mined block {} with hash {}", block.index, block.hash);
                         }
                     }
                 }
-                tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+                tokio::time::sleep(tokio::time::Duration::from_secs(MINING_INTERVAL_SECS)).await;
             }
-        });
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    // A 64-digit hex hash: `prefix` padded with `fill`
+    fn hash(prefix: &str, fill: char) -> String {
+        format!("{}{}", prefix, fill.to_string().repeat(64 - prefix.len()))
+    }
+
+    #[test]
+    fn hashes_must_be_strictly_below_the_target() {
+        // Ten bits puts the target at 0x0040 followed by zeros
+        let pow = ProofOfWork::with_target_bits(10);
+        assert!(pow.meets_target(&hash("003f", 'f')));
+        let at_target = hash("0040", '0');
+        assert!(!pow.meets_target(&at_target));
+        assert!(!pow.meets_target(&format!("{}1", &at_target[..63])));
+        assert!(!pow.meets_target("not hex"));
+
+        // Leading zero digits are four bits each
+        assert_eq!(ProofOfWork::new(2).target, target_for_bits(8));
+        assert!(ProofOfWork::with_target_bits(0).meets_target(&hash("", 'f')));
+        assert!(!ProofOfWork::with_target_bits(256).meets_target(&hash("", '0')));
+    }
+
+    #[test]
+    fn threaded_mining_seals_valid_blocks() {
+        let pow = ProofOfWork::with_target_bits(12).with_threads(4);
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        for _ in 0..3 {
+            let block = pow.seal_block(Block::new(Vec::new(), Some(&genesis), Utc::now()));
+            assert!(pow.validate_block(&block, &genesis.hash));
+            assert_eq!(pow.block_difficulty(&block), Some(12));
+        }
+    }
+
+    #[test]
+    fn work_follows_the_recorded_target_not_the_hash() {
+        let pow = ProofOfWork::with_target_bits(8);
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let mut block = pow.seal_block(Block::new(Vec::new(), Some(&genesis), Utc::now()));
+        assert_eq!(pow.block_weight(&block), 256);
+
+        // A hash far below the target counts the same
+        block.hash = hash("0000000", '0');
+        assert_eq!(pow.block_weight(&block), 256);
+
+        // Blocks without a recorded target count at the engine's own
+        block.consensus_data.clear();
+        assert_eq!(pow.block_weight(&block), 256);
+        block.consensus_data = "12".to_string();
+        assert_eq!(pow.block_weight(&block), 4096);
+        assert_eq!(work_for_bits(256), 1 << 124);
     }
 }
@@ -0,0 +1,101 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use rand::{
+    rngs::{OsRng, StdRng},
+    RngCore, SeedableRng,
+};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+// Draws before giving up on a generator that only yields invalid secret keys
+const KEYPAIR_ATTEMPTS: usize = 8;
+
+// A generator seeded from the OS. Sandboxes can block the OS source, so rather
+// than panic this falls back to the clock, which is fine for throwaway keys and
+// validator selection but not for anything secret.
+pub fn os_seeded() -> StdRng {
+    StdRng::from_rng(OsRng).unwrap_or_else(|e| {
+        warn!("OS entropy unavailable, seeding from the clock: {}", e);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        StdRng::seed_from_u64(nanos)
+    })
+}
+
+// Like `Secp256k1::generate_keypair`, but a failing generator is an error
+// instead of a panic
+pub fn generate_keypair<R: RngCore + ?Sized>(
+    rng: &mut R,
+) -> Result<(SecretKey, PublicKey), String> {
+    let secp = Secp256k1::new();
+    for _ in 0..KEYPAIR_ATTEMPTS {
+        let mut bytes = [0u8; 32];
+        rng.try_fill_bytes(&mut bytes)
+            .map_err(|e| format!("Random number generator failed: {}", e))?;
+        // Out-of-range scalars are vanishingly rare from a working generator
+        if let Ok(secret_key) = SecretKey::from_slice(&bytes) {
+            return Ok((secret_key, secret_key.public_key(&secp)));
+        }
+    }
+    Err("Random number generator produced no valid secret key".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fails every draw, like an OS source a sandbox blocks
+    struct Failing;
+
+    // Yields only zeros, which is never a valid secret key
+    struct Zeros;
+
+    impl RngCore for Failing {
+        fn next_u32(&mut self) -> u32 {
+            unreachable!("keys are drawn with try_fill_bytes")
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            unreachable!("keys are drawn with try_fill_bytes")
+        }
+
+        fn fill_bytes(&mut self, _dest: &mut [u8]) {
+            unreachable!("keys are drawn with try_fill_bytes")
+        }
+
+        fn try_fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), rand::Error> {
+            Err(rand::Error::new("entropy source blocked"))
+        }
+    }
+
+    impl RngCore for Zeros {
+        fn next_u32(&mut self) -> u32 {
+            0
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            0
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.fill(0);
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failing_generators_are_errors_not_panics() {
+        let error = generate_keypair(&mut Failing).unwrap_err();
+        assert!(error.contains("entropy source blocked"), "{}", error);
+        let error = generate_keypair(&mut Zeros).unwrap_err();
+        assert!(error.contains("no valid secret key"), "{}", error);
+
+        let (secret_key, public_key) = generate_keypair(&mut StdRng::seed_from_u64(7)).unwrap();
+        assert_eq!(secret_key.public_key(&Secp256k1::new()), public_key);
+    }
+}
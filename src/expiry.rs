@@ -0,0 +1,19 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::task::JoinHandle;
+
+use crate::Blockchain;
+
+// Periodically drops expired transactions, so the pool size reflects what can
+// still be mined even when no blocks are being produced
+pub fn spawn(blockchain: Arc<Mutex<Blockchain>>, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            blockchain.lock().unwrap().prune_expired();
+        }
+    })
+}
@@ -0,0 +1,79 @@
+use std::{str::FromStr, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Hash function used for block hashes, merkle trees, and transaction signing messages.
+// It is fixed for the lifetime of a chain, so it is set once at startup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgo {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for HashAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            "blake3" => Ok(Self::Blake3),
+            other => Err(format!("Unknown hash algorithm: {}", other)),
+        }
+    }
+}
+
+static HASH_ALGO: OnceLock<HashAlgo> = OnceLock::new();
+
+// Selects the chain's hash algorithm; must be called before anything is hashed
+pub fn init(algo: HashAlgo) -> Result<(), String> {
+    HASH_ALGO
+        .set(algo)
+        .map_err(|_| "Hash algorithm already initialized".to_string())
+}
+
+pub fn algo() -> HashAlgo {
+    *HASH_ALGO.get_or_init(HashAlgo::default)
+}
+
+impl HashAlgo {
+    // Always 32 bytes, the size secp256k1 signs; an algorithm with a wider output
+    // must be reduced to 32 bytes here
+    fn digest(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            Self::Sha256 => Sha256::digest(data).into(),
+            Self::Blake3 => *blake3::hash(data).as_bytes(),
+        }
+    }
+}
+
+// Hashes with the chain's algorithm
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    algo().digest(data)
+}
+
+pub fn hex_digest(data: &[u8]) -> String {
+    hex::encode(digest(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn algorithms_are_stable_and_distinct() {
+        let sha256 = HashAlgo::Sha256.digest(b"mockchain");
+        let blake3 = HashAlgo::Blake3.digest(b"mockchain");
+        assert_eq!(sha256, HashAlgo::Sha256.digest(b"mockchain"));
+        assert_eq!(blake3, HashAlgo::Blake3.digest(b"mockchain"));
+        assert_ne!(sha256, blake3);
+        assert_ne!(sha256, HashAlgo::Sha256.digest(b"mockchain!"));
+        assert_ne!(blake3, HashAlgo::Blake3.digest(b"mockchain!"));
+
+        assert_eq!(
+            hex::encode(HashAlgo::Sha256.digest(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
@@ -0,0 +1,2879 @@
+use blockchain::blockchain_service_server::BlockchainServiceServer;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use std::borrow::{Borrow, Cow};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::Server;
+
+pub mod blockchain {
+    tonic::include_proto!("blockchain");
+}
+
+use rpc::{AdminAuth, BlockchainServer, RateLimit};
+
+mod block;
+mod block_store;
+mod client;
+mod clock;
+pub mod config;
+mod consensus;
+mod entropy;
+mod expiry;
+mod hash;
+mod logging;
+mod merkle;
+mod metrics;
+mod p2p;
+mod reputation;
+mod rpc;
+mod state;
+mod storage;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+mod transaction;
+mod watchdog;
+
+use block::{Block, UnsealedBlock};
+use block_store::{BlockStore, FileBlockStore, MemoryBlockStore};
+use clock::{Clock, SystemClock};
+use config::Config;
+use consensus::{BlockProduction, Consensus, ConsensusRegistry};
+use logging::{LogFormat, DEFAULT_LOG_MAX_BYTES};
+use state::{AccountState, BalanceIndex};
+use storage::Storage;
+use transaction::Transaction;
+const FAUCET_MOCKCHAIN_ADDRESS: &str = "FAUCET_MOCKCHAIN_ADDRESS";
+// Reserved miner of the genesis block, which never receives a reward
+const GENESIS_MINER: &str = "GENESIS_MINER";
+const MINING_REWARD: u64 = 50;
+// Pool size above which the minimum fee starts rising
+const MEMPOOL_CONGESTION_THRESHOLD: usize = 100;
+// Extra pending transactions per unit of minimum-fee increase once congested
+const FEE_FLOOR_STEP: usize = 10;
+// Most transactions a block may include, on top of the byte budget; the rest wait in the pool
+const MAX_BLOCK_TRANSACTIONS: usize = 100;
+// Block fullness the burned base fee steers towards, and the fraction it moves by per block
+const BASE_FEE_TARGET_TRANSACTIONS: usize = MAX_BLOCK_TRANSACTIONS / 2;
+const BASE_FEE_CHANGE_DENOMINATOR: u64 = 8;
+const DEFAULT_MAX_BLOCK_BYTES: usize = 64 * 1024;
+// Enough for a full block of plain transfers, so only batches are held back by default
+const DEFAULT_BLOCK_GAS_LIMIT: u64 = MAX_BLOCK_TRANSACTIONS as u64 * transaction::TRANSFER_GAS;
+const DEFAULT_MAX_PENDING_PER_SENDER: usize = 64;
+const DEFAULT_MIN_RBF_BUMP_PERCENT: u64 = 10;
+const DEFAULT_RBF_MIN_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_REORG_DEPTH: u64 = 100;
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_FUTURE_BLOCK_TIME: Duration = Duration::from_secs(120);
+// Blocks averaged over for the block interval estimate
+const RECENT_BLOCKS: usize = 10;
+// Chain events buffered per subscriber before a slow one starts missing them
+const CHAIN_EVENT_CAPACITY: usize = 64;
+
+pub struct Blockchain {
+    pub chain: Box<dyn BlockStore>,
+    pub transaction_pool: VecDeque<Transaction>,
+    // Signing hashes of every transaction accepted into the pool or the chain,
+    // so relayed and replayed transactions are only accepted once, whatever
+    // signature bytes they arrive with
+    known_transactions: HashSet<String>,
+    // Transaction hashes of each block by height, and the height and position of
+    // every mined transaction by hash, so explorer lookups don't rescan the chain.
+    // Kept in step with `chain` by `reindex_from`.
+    block_tx_hashes: Vec<Vec<String>>,
+    tx_locations: HashMap<String, (u64, usize)>,
+    // Balances at every height and at the tip, for lookups without a replay
+    balances: BalanceIndex,
+    // Total fork-choice weight of the chain up to each height, for the tip's
+    // cumulative work
+    chain_work: Vec<u128>,
+    // When each pool transaction reached this node, by hash, for the pool TTL
+    pool_received: HashMap<String, DateTime<Utc>>,
+    // Node policy, not a consensus rule: pool transactions unmined for this long
+    // are dropped by the expiry sweep, whatever their signed `valid_until`
+    pub mempool_tx_ttl: Option<Duration>,
+    // Set while the initial sync from peers runs; transactions are refused and
+    // no blocks are mined until it finishes
+    pub syncing: bool,
+    // Fee floor for non-faucet transactions when the pool isn't congested
+    pub min_fee: u64,
+    // Accept non-faucet transactions paying no fee, which a zero floor otherwise allows
+    pub allow_zero_fee: bool,
+    // Blocks that must be mined on top of a faucet grant before it counts towards a balance
+    pub faucet_confirmations: u64,
+    // Address that receives `treasury_fraction` of every block subsidy
+    pub treasury_address: Option<String>,
+    pub treasury_fraction: f64,
+    // Domain separator for transaction signatures; 0 accepts pre-chain-id signatures
+    pub chain_id: u64,
+    consensus: Box<dyn Consensus>,
+    // Engines replaced by `set_consensus`, each with the height its successor
+    // took over at, so blocks below that are still judged by the rules they
+    // were made under
+    retired_consensus: Vec<(u64, Box<dyn Consensus>)>,
+    mining_task: Option<JoinHandle<()>>,
+    // When the mining task last went round its loop, so the watchdog can tell a
+    // stuck task from one that has nothing to mine
+    mining_round: DateTime<Utc>,
+    pub storage: Option<Storage>,
+    // Source of throwaway miner keys; seeded from --rng-seed for reproducible runs.
+    // Any generator will do, so one that fails can be swapped in.
+    pub rng: Box<dyn RngCore + Send>,
+    pub block_production: BlockProduction,
+    // Serialized transaction bytes a block may hold
+    pub max_block_bytes: usize,
+    // Total gas the transactions in a block may use
+    pub block_gas_limit: u64,
+    // Pool transactions a single sender may have waiting; the faucet is exempt
+    pub max_pending_per_sender: usize,
+    // A fee-bumped replacement must raise the fee by this percentage (and at
+    // least 1), and can't follow the transaction it replaces sooner than
+    // `rbf_min_interval`, so senders can't churn the pool with tiny bumps
+    pub min_rbf_bump_percent: u64,
+    pub rbf_min_interval: Duration,
+    // Most blocks a replacement chain may revert from our tip, however long it is
+    pub max_reorg_depth: u64,
+    // How far ahead of our clock a peer's block may be timestamped
+    pub max_future_block_time: Duration,
+    // Burns the block's base fee out of every transaction fee, paying the miner only the tip
+    pub burn_base_fee: bool,
+    // Block subsidies stop once the supply reaches this; miners then earn only fees
+    pub max_supply: Option<u64>,
+    // Upgrade height: blocks from here on must carry a state root and may only
+    // hold non-faucet transactions that set `valid_until`; blocks below it keep
+    // the old rules
+    pub activation_height: Option<u64>,
+    // Mine an empty block once the tip is `heartbeat_interval` old, so a quiet chain keeps advancing
+    pub allow_empty_blocks: bool,
+    pub heartbeat_interval: Duration,
+    // Time source for block timestamps, expiry, the pool TTL, and heartbeats
+    pub clock: Arc<dyn Clock>,
+    // Signs blocks for consensuses that check producer signatures; without it,
+    // or on another producer's turn, this node produces no blocks under them
+    pub producer_key: Option<SecretKey>,
+    // Every change to the chain, for streaming subscriptions
+    chain_events: broadcast::Sender<ChainEvent>,
+    // The current tip, republished whenever the chain changes
+    tip: watch::Sender<TipInfo>,
+}
+
+// A change to the chain, in the order it happened
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    // A block appended to the tip
+    Block(Box<Block>),
+    // Blocks taken off the tip, by a reorg or UndoBlock, leaving the chain at
+    // `fork_height` (any replacement blocks follow as `Block` events).
+    // `unconfirmed` are the reverted blocks' transactions the chain no longer
+    // holds, which are back in the pool if they're still valid.
+    Rollback {
+        fork_height: u64,
+        reverted: Vec<Block>,
+        unconfirmed: Vec<Transaction>,
+    },
+}
+
+// Snapshot of the chain tip, so the hottest reads don't need the blockchain lock
+#[derive(Debug, Clone)]
+pub struct TipInfo {
+    pub height: u64,
+    pub hash: String,
+    pub timestamp: DateTime<Utc>,
+    // Sum of every block's fork-choice weight: its target's work under PoW
+    pub cumulative_work: u128,
+    pub genesis_hash: String,
+}
+
+impl TipInfo {
+    fn of(chain: &dyn BlockStore, cumulative_work: u128) -> Self {
+        let tip = chain.tip();
+        let genesis = chain.get(0).expect("chain always has a genesis block");
+        Self {
+            height: tip.index,
+            hash: tip.hash.clone(),
+            timestamp: tip.timestamp,
+            cumulative_work,
+            genesis_hash: genesis.hash.clone(),
+        }
+    }
+}
+
+impl Blockchain {
+    // Builds an in-memory chain holding only its genesis block
+    pub fn new(
+        consensus: Box<dyn Consensus>,
+        genesis_time: Option<DateTime<Utc>>,
+        genesis_message: &str,
+    ) -> Self {
+        let genesis_block = genesis_block(&*consensus, genesis_time, genesis_message);
+        info!(
+            "Creating new blockchain with {} consensus",
+            consensus.name()
+        );
+
+        Self::from_store(
+            Box::new(MemoryBlockStore::new(vec![genesis_block])),
+            consensus,
+        )
+    }
+
+    fn from_store(chain: Box<dyn BlockStore>, consensus: Box<dyn Consensus>) -> Self {
+        // Published properly once the chain is indexed below
+        let tip = watch::channel(TipInfo::of(&*chain, 0)).0;
+
+        let mut blockchain = Self {
+            chain,
+            transaction_pool: VecDeque::new(),
+            known_transactions: HashSet::new(),
+            block_tx_hashes: Vec::new(),
+            tx_locations: HashMap::new(),
+            balances: BalanceIndex::default(),
+            chain_work: Vec::new(),
+            pool_received: HashMap::new(),
+            mempool_tx_ttl: None,
+            syncing: false,
+            min_fee: 0,
+            allow_zero_fee: true,
+            faucet_confirmations: 0,
+            treasury_address: None,
+            treasury_fraction: 0.0,
+            chain_id: 0,
+            consensus,
+            retired_consensus: Vec::new(),
+            mining_task: None,
+            mining_round: Utc::now(),
+            storage: None,
+            rng: Box::new(entropy::os_seeded()),
+            block_production: BlockProduction::default(),
+            max_block_bytes: DEFAULT_MAX_BLOCK_BYTES,
+            block_gas_limit: DEFAULT_BLOCK_GAS_LIMIT,
+            max_pending_per_sender: DEFAULT_MAX_PENDING_PER_SENDER,
+            min_rbf_bump_percent: DEFAULT_MIN_RBF_BUMP_PERCENT,
+            rbf_min_interval: DEFAULT_RBF_MIN_INTERVAL,
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            max_future_block_time: DEFAULT_MAX_FUTURE_BLOCK_TIME,
+            burn_base_fee: false,
+            max_supply: None,
+            activation_height: None,
+            allow_empty_blocks: false,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            clock: Arc::new(SystemClock),
+            producer_key: None,
+            chain_events: broadcast::channel(CHAIN_EVENT_CAPACITY).0,
+            tip,
+        };
+        blockchain.reindex_from(0);
+        blockchain.known_transactions = blockchain
+            .chain
+            .iter()
+            .flat_map(|block| {
+                block
+                    .transactions
+                    .iter()
+                    .map(Transaction::signing_hash)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        blockchain.publish_tip();
+        blockchain
+    }
+
+    // Signatures in the loaded chain are checked against `chain_id`. Unless
+    // `skip_validation` is set, the whole chain is replayed first, so a corrupted
+    // file fails here instead of serving wrong balances.
+    pub fn load_from_path(
+        path: &Path,
+        consensus: Box<dyn Consensus>,
+        chain_id: u64,
+        skip_validation: bool,
+        passphrase: Option<&str>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let storage::Snapshot { chain, validators } = storage::load(path, passphrase)?;
+        if chain.is_empty() {
+            return Err(format!("{} contains no blocks", path.display()).into());
+        }
+
+        info!(
+            "Loaded {} blocks from {} with {} consensus",
+            chain.len(),
+            path.display(),
+            consensus.name()
+        );
+        let mut blockchain = Self::from_store(Box::new(MemoryBlockStore::new(chain)), consensus);
+        blockchain.chain_id = chain_id;
+        for (address, stake) in validators {
+            if let Err(e) = blockchain.consensus.register_stake(&address, stake) {
+                warn!("Dropped saved stake of {}: {}", address, e);
+            }
+        }
+        if skip_validation {
+            warn!(
+                "Skipping validation of the chain loaded from {}",
+                path.display()
+            );
+        } else if let Err(e) = blockchain.validate() {
+            return Err(format!(
+                "{} failed its consistency check: {} (start with --skip-validation to load it anyway)",
+                path.display(),
+                e
+            )
+            .into());
+        }
+        Ok(blockchain)
+    }
+
+    // Opens the chain paged to and from `dir`, starting a new one there if it's
+    // empty. Only the last `max_resident_blocks` are kept in memory; validation
+    // streams the older ones back from disk one at a time.
+    pub fn open_block_store(
+        dir: &Path,
+        consensus: Box<dyn Consensus>,
+        config: &Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut store = FileBlockStore::open(dir, config.max_resident_blocks)?;
+        if store.len() == 0 {
+            info!("Starting a new chain in block store {}", dir.display());
+            store.push(genesis_block(
+                &*consensus,
+                config.genesis_time,
+                &config.genesis_message,
+            ));
+        } else {
+            info!(
+                "Opened {} blocks from block store {} with {} consensus",
+                store.len(),
+                dir.display(),
+                consensus.name()
+            );
+        }
+
+        let mut blockchain = Self::from_store(Box::new(store), consensus);
+        blockchain.chain_id = config.chain_id;
+        if config.skip_validation {
+            warn!("Skipping validation of block store {}", dir.display());
+        } else if let Err(e) = blockchain.validate() {
+            return Err(format!(
+                "{} failed its consistency check: {} (start with --skip-validation to load it anyway)",
+                dir.display(),
+                e
+            )
+            .into());
+        }
+        Ok(blockchain)
+    }
+
+    // Opens or starts the chain `config` points at, on `clock`, with the node
+    // settings from `config` applied. The node and the testkit both start here.
+    pub fn from_config(
+        config: &Config,
+        consensus: Box<dyn Consensus>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut blockchain = match (&config.block_store_dir, &config.chain_file) {
+            (Some(dir), _) => Self::open_block_store(dir, consensus, config)?,
+            (None, Some(path)) if path.exists() => Self::load_from_path(
+                path,
+                consensus,
+                config.chain_id,
+                config.skip_validation,
+                config.encrypt_passphrase.as_deref(),
+            )?,
+            _ => Self::new(consensus, config.genesis_time, &config.genesis_message),
+        };
+        blockchain.clock = clock;
+        blockchain.storage = config.chain_file.clone().map(|path| Storage {
+            path,
+            format: config.storage_format,
+            compress: config.compress_chain,
+            passphrase: config.encrypt_passphrase.clone(),
+        });
+        blockchain.min_fee = config.min_fee;
+        blockchain.allow_zero_fee = config.allow_zero_fee;
+        blockchain.producer_key = config.producer_key;
+        blockchain.faucet_confirmations = config.faucet_confirmations;
+        blockchain.chain_id = config.chain_id;
+        blockchain.block_production = config.block_production;
+        blockchain.max_block_bytes = config.max_block_bytes;
+        blockchain.block_gas_limit = config.block_gas_limit;
+        blockchain.max_pending_per_sender = config.max_pending_per_sender;
+        blockchain.min_rbf_bump_percent = config.min_rbf_bump_percent;
+        blockchain.rbf_min_interval = config.rbf_min_interval;
+        blockchain.max_reorg_depth = config.max_reorg_depth;
+        blockchain.max_future_block_time = config.max_future_block_time;
+        blockchain.mempool_tx_ttl = config.mempool_tx_ttl;
+        blockchain.burn_base_fee = config.burn_base_fee;
+        blockchain.max_supply = config.max_supply;
+        blockchain.activation_height = config.activation_height;
+        blockchain.allow_empty_blocks = config.allow_empty_blocks;
+        blockchain.heartbeat_interval = config.heartbeat_interval;
+        blockchain.treasury_address = config.treasury_address.clone();
+        blockchain.treasury_fraction = config.treasury_fraction;
+        if let Some(seed) = config.rng_seed {
+            blockchain.rng = Box::new(StdRng::seed_from_u64(seed));
+        }
+        Ok(blockchain)
+    }
+
+    pub fn save_to_path(&self, storage: &Storage) -> Result<(), Box<dyn Error>> {
+        let chain = self
+            .chain
+            .as_slice()
+            .ok_or("A chain file needs the whole chain in memory")?;
+        storage::save(storage, chain, self.consensus.validators())
+    }
+
+    // Rebuilds the transaction and balance indexes for every block from height `from` up,
+    // after the chain changed there
+    fn reindex_from(&mut self, from: usize) {
+        for hashes in self
+            .block_tx_hashes
+            .drain(from.min(self.block_tx_hashes.len())..)
+        {
+            for hash in hashes {
+                // A transaction mined twice stays indexed at its first block
+                if self
+                    .tx_locations
+                    .get(&hash)
+                    .is_some_and(|(height, _)| *height as usize >= from)
+                {
+                    self.tx_locations.remove(&hash);
+                }
+            }
+        }
+        self.chain_work.truncate(from);
+        self.balances.truncate(from);
+        for block in self.chain.iter_from(from as u64) {
+            self.balances.push(&block);
+            let work = self.chain_work.last().copied().unwrap_or(0)
+                + self.consensus_at(block.index).block_weight(&block);
+            self.chain_work.push(work);
+            let hashes: Vec<String> = block.transactions.iter().map(Transaction::hash).collect();
+            for (position, hash) in hashes.iter().enumerate() {
+                self.tx_locations
+                    .entry(hash.clone())
+                    .or_insert((block.index, position));
+            }
+            self.block_tx_hashes.push(hashes);
+        }
+    }
+
+    pub fn genesis_hash(&self) -> String {
+        let genesis = self.chain.get(0).expect("chain always has a genesis block");
+        genesis.hash.clone()
+    }
+
+    // Hashes of the transactions in block `index`, in block order
+    pub fn block_tx_hashes(&self, index: u64) -> Option<&[String]> {
+        self.block_tx_hashes.get(index as usize).map(Vec::as_slice)
+    }
+
+    // Height of the block holding a mined transaction, and its position there
+    pub fn locate_transaction(&self, hash: &str) -> Option<(u64, usize)> {
+        self.tx_locations.get(hash).copied()
+    }
+
+    // Publishes the tip to `watch_tip` readers; called after every change to the chain
+    fn publish_tip(&self) {
+        let work = self.chain_work.last().copied().unwrap_or(0);
+        self.tip.send_replace(TipInfo::of(&*self.chain, work));
+    }
+
+    // Saves the chain to the configured storage, if any
+    fn persist(&self) {
+        if let Some(storage) = &self.storage {
+            if let Err(e) = self.save_to_path(storage) {
+                warn!("Failed to save chain to {}: {}", storage.path.display(), e);
+            }
+        }
+    }
+
+    // Starts (or restarts) the consensus mining task, aborting any previous one
+    pub fn start_mining(&mut self, blockchain: Arc<Mutex<Blockchain>>) {
+        if let Some(task) = self.mining_task.take() {
+            task.abort();
+        }
+        if self.block_production == BlockProduction::Manual {
+            info!("Manual block production, not starting the mining loop");
+            return;
+        }
+        self.mining_round = self.clock.now();
+        self.mining_task = Some(self.consensus.start(blockchain));
+    }
+
+    // Called by the mining task on every round of its loop, whether or not it
+    // has anything to mine
+    pub fn record_mining_round(&mut self) {
+        self.mining_round = self.clock.now();
+    }
+
+    // How long since the mining task last went round its loop
+    pub fn since_mining_round(&self) -> Duration {
+        self.clock.since(self.mining_round)
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.mining_task
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+    }
+
+    // Swaps the consensus mechanism while keeping the transaction pool.
+    // Pending transactions are re-checked against the new consensus rules;
+    // faucet transactions stay exempt. The new rules apply from the next height;
+    // blocks below it keep being validated and weighed by the engine that made
+    // them. Returns the number of dropped transactions.
+    pub fn set_consensus(&mut self, consensus: Box<dyn Consensus>) -> usize {
+        info!(
+            "Switching consensus from {} to {}",
+            self.consensus.name(),
+            consensus.name()
+        );
+        let retired = std::mem::replace(&mut self.consensus, consensus);
+        self.retired_consensus
+            .push((self.chain.len() as u64, retired));
+
+        let before = self.transaction_pool.len();
+        self.transaction_pool.retain(|tx| {
+            tx.from == FAUCET_MOCKCHAIN_ADDRESS || self.consensus.validate_transaction(tx)
+        });
+
+        let dropped = before - self.transaction_pool.len();
+        if dropped > 0 {
+            warn!(
+                "Dropped {} pending transactions rejected by the new consensus",
+                dropped
+            );
+        }
+        dropped
+    }
+
+    // Lowest fee the pool currently accepts: the configured floor, raised by one
+    // for every FEE_FLOOR_STEP transactions beyond the congestion threshold
+    pub fn current_min_fee(&self) -> u64 {
+        let excess = self
+            .transaction_pool
+            .len()
+            .saturating_sub(MEMPOOL_CONGESTION_THRESHOLD);
+        self.min_fee + (excess / FEE_FLOOR_STEP) as u64
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
+        transaction.validate_structure().map_err(reject)?;
+        if transaction.is_expired(self.clock.unix_now()) {
+            return Err(reject(format!(
+                "Transaction expired at {}",
+                transaction.valid_until
+            )));
+        }
+
+        let recipients = transaction
+            .payments()
+            .iter()
+            .map(|(to, _)| *to)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        // Allow transactions from the faucet without verification
+        if transaction.from == FAUCET_MOCKCHAIN_ADDRESS {
+            info!(
+                "Adding faucet transaction to pool: FAUCET -> {}, amount: {}",
+                recipients,
+                transaction.total_amount()
+            );
+            self.pool_received
+                .insert(transaction.hash(), self.clock.now());
+            self.transaction_pool.push_back(transaction);
+            return Ok(());
+        }
+
+        // Checked against the next block, where the transaction could be mined
+        self.check_activation_rules(&transaction, self.chain.len() as u64)
+            .map_err(reject)?;
+
+        if self
+            .known_transactions
+            .contains(&transaction.signing_hash())
+        {
+            return Err(reject(format!(
+                "Duplicate transaction {}",
+                transaction.hash()
+            )));
+        }
+
+        // Re-signing a pending transfer with a different fee replaces it
+        // (replace-by-fee). There are no nonces, so a replacement is recognised by
+        // having the same sender, timestamp and payments.
+        let replaced = self.transaction_pool.iter().position(|tx| {
+            tx.from == transaction.from
+                && tx.timestamp == transaction.timestamp
+                && tx.payments() == transaction.payments()
+        });
+        if let Some(i) = replaced {
+            self.check_replacement(&self.transaction_pool[i], &transaction)
+                .map_err(reject)?;
+        } else {
+            let pending = self
+                .transaction_pool
+                .iter()
+                .filter(|tx| tx.from == transaction.from)
+                .count();
+            if pending >= self.max_pending_per_sender {
+                return Err(reject(format!(
+                    "Sender already has {} pending transactions",
+                    pending
+                )));
+            }
+        }
+
+        if transaction.fee == 0 && !self.allow_zero_fee {
+            return Err(reject("Zero-fee transactions are not accepted".to_string()));
+        }
+        let min_fee = self.current_min_fee();
+        if transaction.fee < min_fee {
+            return Err(reject(format!(
+                "Fee {} is below the minimum of {}",
+                transaction.fee, min_fee
+            )));
+        }
+
+        if !transaction.verify(self.chain_id) {
+            return Err(reject("Transaction verification failed".to_string()));
+        }
+
+        // Checked against what the sender's other pending spends leave, so the
+        // pool can't hold more than the account can pay for
+        let committed = self.pending_debits(&transaction.from, replaced);
+        let available = self
+            .get_balance(&transaction.from)
+            .saturating_sub(committed);
+        if available < transaction.total_debit() {
+            return Err(reject(if committed > 0 {
+                format!(
+                    "Insufficient balance for transaction: {} is already committed to pending transactions",
+                    committed
+                )
+            } else {
+                "Insufficient balance for transaction".to_string()
+            }));
+        }
+
+        info!(
+            "Adding transaction to pool: {} -> {}, amount: {}, fee: {}",
+            transaction.from,
+            recipients,
+            transaction.total_amount(),
+            transaction.fee
+        );
+        self.known_transactions.insert(transaction.signing_hash());
+        self.pool_received
+            .insert(transaction.hash(), self.clock.now());
+        match replaced {
+            // Keeps the replaced transaction's place in the pool; its hash stays
+            // known, so it can't be swapped back in
+            Some(i) => {
+                let old = std::mem::replace(&mut self.transaction_pool[i], transaction);
+                self.pool_received.remove(&old.hash());
+                info!(
+                    "Replaced pending transaction {} with a higher fee",
+                    old.hash()
+                );
+            }
+            None => self.transaction_pool.push_back(transaction),
+        }
+        Ok(())
+    }
+
+    // A replacement must beat the pending fee by the minimum bump, and not
+    // arrive within `rbf_min_interval` of the transaction it replaces
+    fn check_replacement(
+        &self,
+        pending: &Transaction,
+        replacement: &Transaction,
+    ) -> Result<(), String> {
+        // A fee too large to bump can't be outbid, so it's refused rather than wrapped
+        let required = pending
+            .fee
+            .checked_mul(self.min_rbf_bump_percent)
+            .map(|scaled| scaled.div_ceil(100).max(1))
+            .and_then(|bump| pending.fee.checked_add(bump))
+            .ok_or_else(|| {
+                format!(
+                    "Pending fee {} can't be bumped by {}%",
+                    pending.fee, self.min_rbf_bump_percent
+                )
+            })?;
+        if replacement.fee < required {
+            return Err(format!(
+                "Replacement fee {} must be at least {}, {}% above the pending fee {}",
+                replacement.fee, required, self.min_rbf_bump_percent, pending.fee
+            ));
+        }
+
+        let pooled_for = self
+            .pool_received
+            .get(&pending.hash())
+            .map(|received| self.clock.since(*received));
+        if pooled_for.is_some_and(|age| age < self.rbf_min_interval) {
+            return Err(format!(
+                "Transaction {} has been pending less than {}s and can't be replaced yet",
+                pending.hash(),
+                self.rbf_min_interval.as_secs()
+            ));
+        }
+        Ok(())
+    }
+
+    // Drops pool transactions that are past their expiry or have outlived the
+    // pool TTL, returning how many were dropped. Transactions re-pooled without
+    // passing through `add_transaction` start their TTL at the first sweep.
+    pub fn prune_expired(&mut self) -> usize {
+        let received_now = self.clock.now();
+        let now = self.clock.unix_now();
+        let ttl = self.mempool_tx_ttl;
+        let received = &mut self.pool_received;
+        let before = self.transaction_pool.len();
+        self.transaction_pool.retain(|tx| {
+            !tx.is_expired(now)
+                && ttl.is_none_or(|ttl| {
+                    let since = *received.entry(tx.hash()).or_insert(received_now);
+                    clock::elapsed(since, received_now) < ttl
+                })
+        });
+
+        // Forget transactions that have left the pool, whether mined or dropped
+        let pooled: HashSet<String> = self
+            .transaction_pool
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        self.pool_received.retain(|hash, _| pooled.contains(hash));
+
+        let pruned = before - self.transaction_pool.len();
+        if pruned > 0 {
+            info!("Pruned {} expired transactions from the pool", pruned);
+        }
+        pruned
+    }
+
+    pub fn mine_pending_transactions(&mut self, miner_key: &PublicKey) -> Option<Block> {
+        if self.syncing {
+            return None;
+        }
+        self.prune_expired();
+        let heartbeat_due = self.heartbeat_due();
+        if self.transaction_pool.is_empty() && !heartbeat_due {
+            return None;
+        }
+        let pending: Vec<Transaction> = self.transaction_pool.drain(..).collect();
+        let (candidates, deferred) = self.select_block_transactions(pending);
+        self.transaction_pool.extend(deferred);
+
+        let unsealed = self.assemble_block(candidates, miner_key);
+        if unsealed.block().transactions.is_empty() && !heartbeat_due {
+            return None;
+        }
+        // A block we can't sign would be rejected, so its transactions wait for
+        // a producer that can
+        if self.consensus.signs_blocks() && !self.can_sign(&unsealed.block().miner) {
+            self.transaction_pool
+                .extend(unsealed.into_block().transactions);
+            return None;
+        }
+        let block = self.seal_block(unsealed);
+
+        self.chain.push(block.clone());
+        self.reindex_from(block.index as usize);
+        self.persist();
+        self.publish_tip();
+        // No subscribers is not an error
+        let _ = self
+            .chain_events
+            .send(ChainEvent::Block(Box::new(block.clone())));
+        Some(block)
+    }
+
+    // Builds the next block on the tip from `candidates`, with its reward, base
+    // fee, and state root filled in, but not yet sealed by the consensus. Each
+    // candidate is re-checked against the state the block has built up so far:
+    // the chain can move between admission and mining (a reorg, or a block from
+    // a peer spending the same funds), so a transaction that would now
+    // overdraw its sender is dropped instead of included.
+    pub fn assemble_block(
+        &self,
+        candidates: Vec<Transaction>,
+        miner_key: &PublicKey,
+    ) -> UnsealedBlock {
+        let mut state = self.tip_state();
+        let supply = state.balances.values().sum();
+        // Pool transactions accepted before the activation height can be left
+        // invalid once it's reached
+        let index = self.chain.len() as u64;
+        // Expiry is judged at the block's own timestamp, as peers will judge it
+        let timestamp = Block::next_timestamp(self.chain.tip(), self.clock.now());
+        let time = timestamp.timestamp().max(0) as u64;
+        let transactions: Vec<Transaction> = candidates
+            .into_iter()
+            .filter(|tx| {
+                match check_expiry(tx, time)
+                    .and_then(|()| self.check_activation_rules(tx, index))
+                    .and_then(|()| state.apply_transaction(tx))
+                {
+                    Ok(()) => true,
+                    Err(e) => {
+                        warn!("Dropping pending transaction: {}", e);
+                        false
+                    }
+                }
+            })
+            .collect();
+
+        let mut block = Block::new_with_timestamp(transactions, Some(self.chain.tip()), timestamp);
+        block.miner = self
+            .consensus
+            .select_miner(block.index)
+            .unwrap_or_else(|| hex::encode(miner_key.serialize()));
+        let subsidy = self.allowed_subsidy(block.index, supply);
+        if subsidy < block::reward_for_index(block.index) {
+            block.subsidy = Some(subsidy);
+        }
+        block.base_fee = self.base_fee_after(self.chain.tip());
+        block.treasury_reward = self.treasury_reward_for(subsidy);
+        state.apply_reward(&block);
+        block.state_root = state.root();
+        block.hash = block.calculate_hash();
+        UnsealedBlock::new(self.consensus.prepare_block(block))
+    }
+
+    // Assembles the next block from a copy of the pool for an external miner to
+    // seal. The pool is left as is; the block's transactions leave it when the
+    // sealed block comes back through `add_block`.
+    pub fn block_template(&self, miner_key: &PublicKey) -> UnsealedBlock {
+        let pending = self.transaction_pool.iter().cloned().collect();
+        let (candidates, _) = self.select_block_transactions(pending);
+        self.assemble_block(candidates, miner_key)
+    }
+
+    // Base fee the block after `parent` must record: zero unless burning.
+    // Otherwise it follows the parent's, rising by an eighth (at least 1) after
+    // a block over half full and falling as much after one under half full,
+    // never below the fee floor. It depends only on the chain, so every node
+    // derives the same value.
+    fn base_fee_after(&self, parent: &Block) -> u64 {
+        if !self.burn_base_fee {
+            return 0;
+        }
+        let step = (parent.base_fee / BASE_FEE_CHANGE_DENOMINATOR).max(1);
+        let base_fee = match parent.transactions.len().cmp(&BASE_FEE_TARGET_TRANSACTIONS) {
+            Ordering::Greater => parent.base_fee.saturating_add(step),
+            Ordering::Less => parent.base_fee.saturating_sub(step),
+            Ordering::Equal => parent.base_fee,
+        };
+        base_fee.max(self.min_fee)
+    }
+
+    // Applies the consensus seal, e.g. grinding a PoW nonce, then signs the
+    // block as its producer if the consensus asks for it; the block isn't appended
+    pub fn seal_block(&self, unsealed: UnsealedBlock) -> Block {
+        let mut block = self.consensus.seal_block(unsealed.into_block());
+        if let Some(key) = &self.producer_key {
+            if self.consensus.signs_blocks() && self.can_sign(&block.miner) {
+                block.sign_producer(key);
+            }
+        }
+        block
+    }
+
+    // Whether our producer key is the one `miner` names
+    fn can_sign(&self, miner: &str) -> bool {
+        self.producer_key.is_some_and(|key| {
+            hex::encode(key.public_key(&Secp256k1::signing_only()).serialize()) == miner
+        })
+    }
+
+    // Appends a block produced by another node on top of our tip, after checking
+    // it against the consensus rules and replaying it over the tip balances.
+    // Its transactions leave the pool.
+    pub fn add_block(&mut self, block: Block) -> Result<(), String> {
+        let tip = self.chain.tip();
+        // Linking by hash alone would let a bogus index through, breaking height lookups
+        if block.index != tip.index + 1 {
+            return Err(format!(
+                "Block {} does not follow the tip at height {}",
+                block.index, tip.index
+            ));
+        }
+        self.check_timestamp(&block)?;
+        let mut state = self.tip_state();
+        self.validate_block(&block, Some(tip), &mut state)?;
+
+        let included: HashSet<String> = block
+            .transactions
+            .iter()
+            .map(Transaction::signing_hash)
+            .collect();
+        self.transaction_pool
+            .retain(|tx| !included.contains(&tx.signing_hash()));
+        self.known_transactions.extend(included);
+
+        info!(
+            "Accepted block {} ({}) from a peer",
+            block.index, block.hash
+        );
+        self.chain.push(block.clone());
+        self.reindex_from(block.index as usize);
+        self.persist();
+        self.publish_tip();
+        let _ = self.chain_events.send(ChainEvent::Block(Box::new(block)));
+        Ok(())
+    }
+
+    // Splits transactions into those that fit the next block and those that have to
+    // wait. A sender's transactions are mined in signed-timestamp order (there are
+    // no nonces), so only each sender's earliest pending transaction competes:
+    // highest fee per byte first. One that would overflow the byte budget, the gas
+    // budget or the transaction limit is deferred along with every later
+    // transaction from its sender. Ties are broken by transaction hash, never by
+    // arrival order, so nodes with the same pool build the same block.
+    // Time-locked transactions wait without holding up their sender's others.
+    pub fn select_block_transactions(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> (Vec<Transaction>, Vec<Transaction>) {
+        let now = self.clock.unix_now();
+        let mut deferred = Vec::new();
+        let mut by_time: Vec<(usize, String, Transaction)> = transactions
+            .into_iter()
+            .enumerate()
+            .map(|(position, tx)| (position, tx.hash(), tx))
+            .collect();
+        by_time.sort_by(|(_, a_hash, a), (_, b_hash, b)| {
+            (a.timestamp, a_hash).cmp(&(b.timestamp, b_hash))
+        });
+        let mut queues: HashMap<String, VecDeque<(usize, String, Transaction)>> = HashMap::new();
+        for (position, hash, tx) in by_time {
+            if tx.is_time_locked(now) {
+                deferred.push((position, tx));
+                continue;
+            }
+            queues
+                .entry(tx.from.clone())
+                .or_default()
+                .push_back((position, hash, tx));
+        }
+
+        let fee_rate = |tx: &Transaction| ((tx.fee as u128) << 64) / tx.size() as u128;
+        let head = |sender: &String, (_, hash, tx): &(usize, String, Transaction)| {
+            (fee_rate(tx), Reverse(hash.clone()), sender.clone())
+        };
+        let mut heads: BinaryHeap<(u128, Reverse<String>, String)> = queues
+            .iter()
+            .map(|(sender, queue)| head(sender, &queue[0]))
+            .collect();
+
+        let mut block_bytes = 0;
+        let mut block_gas = 0;
+        let mut included = Vec::new();
+        while let Some((_, _, sender)) = heads.pop() {
+            let queue = queues.get_mut(&sender).expect("every head has a queue");
+            let (position, _, tx) = queue.pop_front().expect("queues in the heap are non-empty");
+            let size = tx.size();
+            let gas = tx.gas_used();
+            if included.len() < MAX_BLOCK_TRANSACTIONS
+                && block_bytes + size <= self.max_block_bytes
+                && block_gas + gas <= self.block_gas_limit
+            {
+                block_bytes += size;
+                block_gas += gas;
+                included.push(tx);
+                if let Some(next) = queue.front() {
+                    heads.push(head(&sender, next));
+                }
+            } else {
+                deferred.push((position, tx));
+                deferred.extend(queue.drain(..).map(|(position, _, tx)| (position, tx)));
+            }
+        }
+
+        // Deferred transactions go back to the pool in their original order
+        deferred.sort_by_key(|(position, _)| *position);
+        (included, deferred.into_iter().map(|(_, tx)| tx).collect())
+    }
+
+    pub fn subscribe_chain_events(&self) -> broadcast::Receiver<ChainEvent> {
+        self.chain_events.subscribe()
+    }
+
+    // A handle that always sees the latest tip without locking the blockchain
+    pub fn watch_tip(&self) -> watch::Receiver<TipInfo> {
+        self.tip.subscribe()
+    }
+
+    // Dev tool: removes the tip block (never genesis) and puts its transactions
+    // back at the front of the pool. Balances are derived from the chain, so
+    // dropping the block is enough to roll them back.
+    pub fn undo_last_block(&mut self) -> Option<Block> {
+        if self.chain.len() <= 1 {
+            return None;
+        }
+
+        let block = self.chain.pop()?;
+        let len = self.chain.len();
+        self.reindex_from(len);
+        for tx in block.transactions.iter().rev() {
+            self.transaction_pool.push_front(tx.clone());
+        }
+        self.persist();
+        self.publish_tip();
+        let _ = self.chain_events.send(ChainEvent::Rollback {
+            fork_height: len as u64 - 1,
+            reverted: vec![block.clone()],
+            unconfirmed: block.transactions.clone(),
+        });
+
+        info!(
+            "Undid block {} ({}), re-pooled {} transactions",
+            block.index,
+            block.hash,
+            block.transactions.len()
+        );
+        Some(block)
+    }
+
+    // Adopts `branch`, a run of blocks whose first block follows one of ours
+    // (above the genesis), in place of our blocks from there on, if the result
+    // is a heavier valid chain by the consensus's fork choice and reverts at
+    // most `max_reorg_depth` of our blocks. Blocks the branch shares with our
+    // chain are skipped. Reverted transactions the branch doesn't include go
+    // back through the pool. Sync calls this when a peer's chain forks from ours.
+    pub fn replace_chain(&mut self, branch: Vec<Block>) -> Result<(), String> {
+        let start = branch
+            .first()
+            .filter(|first| {
+                first.index > 0
+                    && self
+                        .chain
+                        .get(first.index - 1)
+                        .is_some_and(|parent| parent.hash == first.previous_hash)
+            })
+            .ok_or("Candidate branch does not fork from our chain")?
+            .index;
+
+        // The shared prefix weighs the same on both sides
+        let mut shared = 0;
+        while branch.get(shared).is_some_and(|block| {
+            self.chain
+                .get(block.index)
+                .is_some_and(|ours| ours.hash == block.hash)
+        }) {
+            shared += 1;
+        }
+        let fork = start as usize + shared;
+        let candidate = &branch[shared..];
+        let ours: Vec<Cow<Block>> = self.chain.iter_from(fork as u64).collect();
+        let our_weight: u128 = ours
+            .iter()
+            .map(|block| self.consensus_at(block.index).block_weight(block))
+            .sum();
+        let their_weight: u128 = candidate
+            .iter()
+            .map(|block| self.consensus_at(block.index).block_weight(block))
+            .sum();
+        if their_weight <= our_weight {
+            return Err(format!(
+                "Candidate chain to height {} is not heavier than ours",
+                fork + candidate.len() - 1
+            ));
+        }
+        let depth = (self.chain.len() - fork) as u64;
+        if depth > self.max_reorg_depth {
+            return Err(format!(
+                "Candidate chain reverts {} blocks, more than the maximum of {}",
+                depth, self.max_reorg_depth
+            ));
+        }
+        // Our blocks below the fork were validated when we took them, so the
+        // branch is replayed from the indexed state there, not from genesis
+        let parent = self
+            .chain
+            .get(fork as u64 - 1)
+            .expect("the fork is above the genesis");
+        let state = self.balances.state_at(fork as u64 - 1);
+        self.validate_from(state, Some(&*parent), candidate)
+            .map_err(|e| format!("Candidate chain is invalid: {}", e))?;
+        for block in candidate {
+            self.check_timestamp(block)?;
+        }
+
+        let adopted: HashSet<String> = candidate
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .map(Transaction::signing_hash)
+            .collect();
+        let reverted: Vec<Transaction> = ours
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .filter(|tx| !adopted.contains(&tx.signing_hash()))
+            .cloned()
+            .collect();
+        let reverted_blocks: Vec<Block> = ours.into_iter().rev().map(Cow::into_owned).collect();
+
+        self.transaction_pool
+            .retain(|tx| !adopted.contains(&tx.signing_hash()));
+        self.known_transactions.extend(adopted);
+        self.chain.truncate(fork);
+        let adopted_blocks: Vec<Block> = branch.into_iter().skip(shared).collect();
+        for block in &adopted_blocks {
+            self.chain.push(block.clone());
+        }
+        self.reindex_from(fork);
+        self.persist();
+        self.publish_tip();
+
+        // Subscribers see the rollback, then the new branch block by block
+        if !reverted_blocks.is_empty() {
+            let _ = self.chain_events.send(ChainEvent::Rollback {
+                fork_height: fork as u64 - 1,
+                reverted: reverted_blocks,
+                unconfirmed: reverted.clone(),
+            });
+        }
+        for block in adopted_blocks {
+            let _ = self.chain_events.send(ChainEvent::Block(Box::new(block)));
+        }
+
+        // Orphaned transactions go back through the pool checks against the new
+        // tip; ones the new chain makes invalid (e.g. now overdrawn) are dropped
+        let orphaned = reverted.len();
+        let mut resurrected = 0;
+        for tx in reverted {
+            self.known_transactions.remove(&tx.signing_hash());
+            if self.add_transaction(tx).is_ok() {
+                resurrected += 1;
+            }
+        }
+
+        info!(
+            "Replaced chain from block {}, reverting {} blocks; new tip is {}",
+            fork,
+            depth,
+            self.chain.len() - 1
+        );
+        info!(
+            "Returned {} of {} orphaned transactions to the pool",
+            resurrected, orphaned
+        );
+        Ok(())
+    }
+
+    // Tokens in circulation at the tip: every non-zero balance
+    pub fn total_supply(&self) -> u64 {
+        self.tip_state().balances.values().sum()
+    }
+
+    // Everything the faucet has granted, mined or still pending
+    pub fn faucet_emitted(&self) -> u64 {
+        let mined: u64 = self
+            .chain
+            .iter()
+            .flat_map(|block| {
+                block
+                    .transactions
+                    .iter()
+                    .filter(|tx| tx.from == FAUCET_MOCKCHAIN_ADDRESS)
+                    .map(Transaction::total_amount)
+                    .collect::<Vec<_>>()
+            })
+            .sum();
+        let pending: u64 = self
+            .transaction_pool
+            .iter()
+            .filter(|tx| tx.from == FAUCET_MOCKCHAIN_ADDRESS)
+            .map(Transaction::total_amount)
+            .sum();
+        mined + pending
+    }
+
+    pub fn total_burned(&self) -> u64 {
+        self.chain.iter().map(|block| block.burned_fees()).sum()
+    }
+
+    // Heights of the blocks with a transaction from or to `address` or a reward
+    // paid to it, from the balance index, so per-address lookups only read those
+    pub fn address_heights(&self, address: &str) -> impl Iterator<Item = u64> + '_ {
+        self.balances.heights(address)
+    }
+
+    pub fn get_balance(&self, address: &str) -> u64 {
+        self.get_balance_at(address, self.chain.height())
+            .expect("the tip is always in the chain")
+    }
+
+    // Balance as of block `height`, ignoring every later block; None if the chain is
+    // shorter. Faucet credits only count once buried under `faucet_confirmations`
+    // blocks at that height.
+    pub fn get_balance_at(&self, address: &str, height: u64) -> Option<u64> {
+        if height > self.chain.height() {
+            return None;
+        }
+        Some(
+            self.balances
+                .balance_at(address, height, self.faucet_confirmations),
+        )
+    }
+
+    // Balances right after the transaction at `position` in block `index`, before
+    // the rest of the block and its reward, with faucet grants counted straight
+    // away. Only the accounts the block touches up to there are included, read
+    // from the index as of the previous block rather than replayed.
+    pub fn state_after_transaction(&self, index: u64, position: usize) -> Option<AccountState> {
+        let block = self.chain.get(index)?;
+        let transactions = block.transactions.get(..=position)?;
+        let mut state = AccountState::default();
+        for tx in transactions {
+            let touched = std::iter::once(tx.from.as_str())
+                .chain(tx.payments().into_iter().map(|(to, _)| to));
+            for address in touched {
+                let balance = index
+                    .checked_sub(1)
+                    .map_or(0, |previous| self.balances.balance_at(address, previous, 0));
+                state.balances.entry(address.to_string()).or_insert(balance);
+            }
+        }
+        for tx in transactions {
+            if let Err(e) = state.apply_transaction(tx) {
+                warn!("Block {} is invalid: {}", index, e);
+            }
+        }
+        Some(state)
+    }
+
+    // Balances after every block in the chain
+    fn tip_state(&self) -> AccountState {
+        self.balances.tip().clone()
+    }
+
+    // Replays the whole chain, checking each block against the consensus rules
+    // and rejecting any block whose transactions would overdraw an account
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_chain(self.chain.iter())
+    }
+
+    // Refuses peer blocks dated too far past our clock, which could otherwise skew
+    // anything derived from block times. The peer can resend once it's due.
+    fn check_timestamp(&self, block: &Block) -> Result<(), String> {
+        let limit = chrono::Duration::from_std(self.max_future_block_time)
+            .map_err(|e| format!("Invalid future block window: {}", e))?;
+        if block.timestamp > self.clock.now() + limit {
+            return Err(format!(
+                "Block {} is timestamped {}, more than {}s in the future",
+                block.index,
+                block.timestamp,
+                self.max_future_block_time.as_secs()
+            ));
+        }
+        Ok(())
+    }
+
+    // Subsidy block `index` may mint on top of `supply`, the tokens in circulation
+    // before it: the schedule, held down so the supply never passes --max-supply
+    fn allowed_subsidy(&self, index: u64, supply: u64) -> u64 {
+        let scheduled = block::reward_for_index(index);
+        match self.max_supply {
+            Some(max_supply) => scheduled.min(max_supply.saturating_sub(supply)),
+            None => scheduled,
+        }
+    }
+
+    fn check_subsidy(&self, block: &Block, supply: u64) -> Result<(), String> {
+        let allowed = self.allowed_subsidy(block.index, supply);
+        if block.minted_subsidy() > allowed {
+            return Err(format!(
+                "Block {} mints {}, more than the {} the supply cap allows",
+                block.index,
+                block.minted_subsidy(),
+                allowed
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks a block's state root against `state`, the balances after it. Only
+    // blocks below the activation height may leave it out, as blocks from
+    // before state roots do; without an activation height every block needs one.
+    fn check_state_root(&self, block: &Block, state: &AccountState) -> Result<(), String> {
+        if block.state_root.is_empty() {
+            if self
+                .activation_height
+                .is_none_or(|activation| block.index >= activation)
+            {
+                return Err(format!("Block {} has no state root", block.index));
+            }
+            return Ok(());
+        }
+        if block.state_root != state.root() {
+            return Err(format!(
+                "Block {} has a mismatched state root: it committed to {}, replaying the chain gives {}",
+                block.index,
+                block.state_root,
+                state.root()
+            ));
+        }
+        Ok(())
+    }
+
+    // Treasury address and share of `subsidy` a block pays, if we have a treasury
+    fn treasury_reward_for(&self, subsidy: u64) -> Option<(String, u64)> {
+        self.treasury_address.clone().map(|treasury| {
+            let share = (subsidy as f64 * self.treasury_fraction).round() as u64;
+            (treasury, share)
+        })
+    }
+
+    // A block must pay exactly the treasury reward we would have, so a producer
+    // can neither skip the treasury nor redirect or resize its share
+    fn check_treasury_reward(&self, block: &Block) -> Result<(), String> {
+        let expected = self.treasury_reward_for(block.minted_subsidy());
+        if block.treasury_reward != expected {
+            return Err(format!(
+                "Block {} pays treasury reward {:?}, expected {:?}",
+                block.index, block.treasury_reward, expected
+            ));
+        }
+        Ok(())
+    }
+
+    // Checks every transaction signature in the block, in parallel, that none is
+    // mined before its time lock or after its expiry, and the rules active at its height
+    fn validate_transactions(&self, block: &Block) -> Result<(), String> {
+        if !transaction::verify_all(&block.transactions, self.chain_id) {
+            return Err(format!(
+                "Block {} has a transaction with an invalid signature",
+                block.index
+            ));
+        }
+        let time = block.timestamp.timestamp().max(0) as u64;
+        for tx in &block.transactions {
+            if tx.is_time_locked(time) {
+                return Err(format!(
+                    "Block {} includes transaction {} before its time lock at {}",
+                    block.index,
+                    tx.hash(),
+                    tx.not_before
+                ));
+            }
+            check_expiry(tx, time)
+                .map_err(|e| format!("Block {} has an invalid transaction: {}", block.index, e))?;
+            self.check_activation_rules(tx, block.index)
+                .map_err(|e| format!("Block {} has an invalid transaction: {}", block.index, e))?;
+        }
+        Ok(())
+    }
+
+    // Rules that depend on the height a transaction is mined at: from the
+    // activation height on, non-faucet transactions must carry an expiry
+    fn check_activation_rules(&self, transaction: &Transaction, height: u64) -> Result<(), String> {
+        match self.activation_height {
+            Some(activation) if height >= activation => {
+                if transaction.from != FAUCET_MOCKCHAIN_ADDRESS && transaction.valid_until == 0 {
+                    return Err(format!(
+                        "Transactions must set valid_until from height {}",
+                        activation
+                    ));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Checks every block against the consensus rules and replays it over the
+    // balances, which must match the state root each block committed to
+    fn validate_chain<B: Borrow<Block>>(
+        &self,
+        chain: impl IntoIterator<Item = B>,
+    ) -> Result<(), String> {
+        self.validate_from(AccountState::default(), None, chain)
+    }
+
+    // `validate_chain` for the blocks following `parent`, with `state` the
+    // balances after it
+    fn validate_from<B: Borrow<Block>>(
+        &self,
+        mut state: AccountState,
+        mut parent: Option<B>,
+        blocks: impl IntoIterator<Item = B>,
+    ) -> Result<(), String> {
+        let first = parent
+            .as_ref()
+            .map_or(0, |parent| parent.borrow().index + 1);
+
+        for (height, item) in (first..).zip(blocks) {
+            let block = item.borrow();
+            if block.index != height {
+                return Err(format!(
+                    "Block at height {} claims index {}",
+                    height, block.index
+                ));
+            }
+            self.validate_block(block, parent.as_ref().map(Borrow::borrow), &mut state)?;
+            parent = Some(item);
+        }
+        Ok(())
+    }
+
+    // Every rule a block must meet on top of `parent` (none for genesis), with
+    // `state` the balances after the parent. On success `state` holds the
+    // balances after the block. Blocks from peers, reorgs and chain files all
+    // come through here, so none can skip a rule the others check.
+    fn validate_block(
+        &self,
+        block: &Block,
+        parent: Option<&Block>,
+        state: &mut AccountState,
+    ) -> Result<(), String> {
+        if let Some(parent) = parent {
+            let consensus = self.consensus_at(block.index);
+            if !consensus.validate_block(block, &parent.hash) {
+                return Err(format!(
+                    "Block {} fails {} validation",
+                    block.index,
+                    consensus.name()
+                ));
+            }
+            self.check_treasury_reward(block)?;
+            let base_fee = self.base_fee_after(parent);
+            if block.base_fee != base_fee {
+                return Err(format!(
+                    "Block {} records base fee {}, expected {}",
+                    block.index, block.base_fee, base_fee
+                ));
+            }
+        }
+
+        self.validate_transactions(block)
+            .and_then(|()| self.check_subsidy(block, state.balances.values().sum()))
+            .and_then(|()| state.apply_block(block))
+            .map_err(|e| format!("Block {} is invalid: {}", block.index, e))?;
+        self.check_state_root(block, state)
+    }
+
+    // The engine whose rules apply to the block at `height`
+    fn consensus_at(&self, height: u64) -> &dyn Consensus {
+        self.retired_consensus
+            .iter()
+            .find(|(until, _)| height < *until)
+            .map_or(&*self.consensus, |(_, consensus)| &**consensus)
+    }
+
+    // Amount and fees `address` has pending in the pool, skipping the entry at
+    // `except` (a transaction being replaced)
+    fn pending_debits(&self, address: &str, except: Option<usize>) -> u64 {
+        self.transaction_pool
+            .iter()
+            .enumerate()
+            .filter(|(i, tx)| tx.from == address && Some(*i) != except)
+            .map(|(_, tx)| tx.total_debit())
+            .sum()
+    }
+
+    pub fn check_balance(&self, address: &str, amount: u64) -> bool {
+        let balance = self.get_balance(address);
+        balance >= amount
+    }
+
+    // Mean seconds between the last RECENT_BLOCKS blocks, or the mining interval
+    // before there are any
+    pub fn average_block_interval(&self) -> u64 {
+        let from = self.chain.len().saturating_sub(RECENT_BLOCKS + 1) as u64;
+        let recent: Vec<_> = self.chain.iter_from(from).collect();
+        match (recent.first(), recent.last()) {
+            (Some(first), Some(last)) if recent.len() > 1 => {
+                let span = (last.timestamp - first.timestamp).num_seconds().max(0) as u64;
+                span / (recent.len() as u64 - 1)
+            }
+            _ => consensus::MINING_INTERVAL_SECS,
+        }
+    }
+
+    // Whether an empty block should be mined to keep a quiet chain advancing
+    pub fn heartbeat_due(&self) -> bool {
+        self.allow_empty_blocks
+            && self.seconds_since_last_block() >= self.heartbeat_interval.as_secs()
+    }
+
+    pub fn seconds_since_last_block(&self) -> u64 {
+        let last_block = self.chain.tip();
+        self.clock.since(last_block.timestamp).as_secs()
+    }
+}
+
+// The first block of a new chain. Nodes must agree on the genesis time and
+// message to share a genesis hash; without a time the genesis block is dated
+// now, so every new chain is distinct.
+fn genesis_block(
+    consensus: &dyn Consensus,
+    genesis_time: Option<DateTime<Utc>>,
+    genesis_message: &str,
+) -> Block {
+    let timestamp = genesis_time.unwrap_or_else(Utc::now);
+    let mut genesis_block = Block::new_with_timestamp(Vec::new(), None, timestamp);
+    genesis_block.miner = GENESIS_MINER.to_string();
+    genesis_block.data = genesis_message.to_string();
+    genesis_block.state_root = AccountState::default().root();
+    genesis_block.hash = genesis_block.calculate_hash();
+    consensus.seal_block(genesis_block)
+}
+
+// Refuses a transaction whose signed expiry has passed by `now`
+fn check_expiry(transaction: &Transaction, now: u64) -> Result<(), String> {
+    if transaction.is_expired(now) {
+        return Err(format!(
+            "Transaction {} expired at {}",
+            transaction.hash(),
+            transaction.valid_until
+        ));
+    }
+    Ok(())
+}
+
+fn reject(reason: String) -> String {
+    warn!("Rejected transaction: {}", reason);
+    reason
+}
+
+// Runs the client command or the node the command line asks for
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    // A leading non-flag argument selects a client command instead of running a node
+    let mut args = std::env::args().skip(1).peekable();
+    if let Some(command) = args.next_if(|arg| !arg.starts_with("--")) {
+        logging::init(None, DEFAULT_LOG_MAX_BYTES, LogFormat::default())?;
+        return client::run(&command, args.collect()).await;
+    }
+
+    let config = Config::from_args()?;
+    logging::init(
+        config.log_file.as_deref(),
+        config.log_max_bytes,
+        config.log_format,
+    )?;
+    hash::init(config.hash_algo)?;
+    block::init_reward_schedule(config.halving_interval)?;
+
+    // Register custom consensus factories here to select them with --consensus
+    let registry = ConsensusRegistry::default();
+    let consensus = registry.create(&config.consensus, &config.consensus_params())?;
+
+    info!("Blockchain node starting...");
+    let mut blockchain = Blockchain::from_config(&config, consensus, Arc::new(SystemClock))?;
+    if blockchain.consensus.signs_blocks() && config.producer_key.is_none() {
+        warn!(
+            "{} blocks must be signed by their producer; without --producer-key-file or {} this node produces none",
+            blockchain.consensus.name(),
+            config::PRODUCER_KEY_ENV
+        );
+    }
+    // With peers configured, catch up from them before accepting transactions
+    blockchain.syncing = !config.peers.is_empty();
+    let server = BlockchainServer::new(blockchain, registry, &config);
+    if !config.peers.is_empty() {
+        tokio::spawn(p2p::initial_sync(
+            Arc::clone(&server.blockchain),
+            config.peers.clone(),
+        ));
+    }
+
+    server.spawn_block_gossip();
+
+    // Start consensus mechanism, supervised by the stale-tip watchdog
+    if config.block_production == BlockProduction::Automatic {
+        server
+            .blockchain
+            .lock()
+            .unwrap()
+            .start_mining(Arc::clone(&server.blockchain));
+        watchdog::spawn(Arc::clone(&server.blockchain));
+    }
+
+    expiry::spawn(Arc::clone(&server.blockchain), config.expiry_sweep_interval);
+    if let Some(addr) = config.metrics_addr {
+        metrics::spawn(addr);
+    }
+
+    let addr = config.listen_addr;
+    info!("Starting gRPC server on {}", addr);
+
+    Server::builder()
+        .timeout(config.rpc_timeout)
+        .add_service(grpc_service(server, &config, Arc::new(SystemClock)))
+        .serve(addr)
+        .await?;
+
+    Ok(())
+}
+
+type GrpcService = InterceptedService<
+    InterceptedService<BlockchainServiceServer<BlockchainServer>, RateLimit>,
+    AdminAuth,
+>;
+
+// The gRPC service with its request limits and interceptors, as the node serves it.
+// Oversized requests are rejected from their length prefix, before the body is
+// buffered. The admin check runs first so the rate limit can exempt admins.
+fn grpc_service(server: BlockchainServer, config: &Config, clock: Arc<dyn Clock>) -> GrpcService {
+    InterceptedService::new(
+        InterceptedService::new(
+            BlockchainServiceServer::new(server)
+                .max_decoding_message_size(config.max_request_bytes),
+            RateLimit::new(config.rate_limit, clock),
+        ),
+        AdminAuth::new(config.admin_token.clone()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::ConsensusParams;
+    use crate::storage::StorageFormat;
+    use crate::testkit::{new_account, MockClock};
+
+    // Chains built here share a genesis block, so one can follow another's blocks
+    fn dev_chain() -> Blockchain {
+        let consensus = ConsensusRegistry::default()
+            .create("dev", &Config::default().consensus_params())
+            .unwrap();
+        let genesis_time = DateTime::from_timestamp(1_700_000_000, 0);
+        Blockchain::new(consensus, genesis_time, "")
+    }
+
+    // Mines a block granting `amount` from the faucet to a new account
+    fn mine_grant(chain: &mut Blockchain, amount: u64) -> Block {
+        funded_account(chain, amount);
+        chain.chain.tip().clone()
+    }
+
+    // Creates an account and mines a faucet grant of `amount` to it
+    fn funded_account(chain: &mut Blockchain, amount: u64) -> (SecretKey, String) {
+        let (secret_key, address) = new_account();
+        chain
+            .add_transaction(Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &address, amount))
+            .unwrap();
+        chain.mine_pending_transactions(&any_miner()).unwrap();
+        (secret_key, address)
+    }
+
+    fn any_miner() -> PublicKey {
+        let (_, miner) = new_account();
+        PublicKey::from_slice(&hex::decode(miner).unwrap()).unwrap()
+    }
+
+    // A transfer signed for `chain`'s chain id
+    fn transfer(
+        chain: &Blockchain,
+        secret_key: &SecretKey,
+        from: &str,
+        to: &str,
+        amount: u64,
+    ) -> Transaction {
+        transfer_with_fee(chain, secret_key, from, to, amount, 0)
+    }
+
+    fn transfer_with_fee(
+        chain: &Blockchain,
+        secret_key: &SecretKey,
+        from: &str,
+        to: &str,
+        amount: u64,
+        fee: u64,
+    ) -> Transaction {
+        let mut tx = Transaction::new(from, to, amount);
+        tx.fee = fee;
+        tx.sign(secret_key, chain.chain_id);
+        tx
+    }
+
+    fn consensus(name: &str, params: ConsensusParams) -> Box<dyn Consensus> {
+        ConsensusRegistry::default().create(name, &params).unwrap()
+    }
+
+    #[test]
+    fn treasury_reward_must_match_our_settings() {
+        let mut producer = dev_chain();
+        producer.treasury_address = Some("treasury".to_string());
+        producer.treasury_fraction = 0.1;
+        let block = mine_grant(&mut producer, 10);
+        assert_eq!(block.treasury_reward, Some(("treasury".to_string(), 5)));
+
+        let mut follower = dev_chain();
+        assert!(follower.add_block(block.clone()).is_err());
+        follower.treasury_address = Some("treasury".to_string());
+        follower.treasury_fraction = 0.1;
+
+        let mut inflated = block.clone();
+        inflated.treasury_reward = Some(("treasury".to_string(), 50));
+        inflated.hash = inflated.calculate_hash();
+        assert!(follower.add_block(inflated).is_err());
+
+        follower.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn forks_paying_another_treasury_are_refused() {
+        let mut ours = dev_chain();
+        ours.treasury_address = Some("treasury".to_string());
+        ours.treasury_fraction = 0.1;
+        mine_grant(&mut ours, 10);
+
+        let mut theirs = dev_chain();
+        theirs.treasury_address = Some("attacker".to_string());
+        theirs.treasury_fraction = 0.1;
+        let branch: Vec<Block> = (1..=2)
+            .map(|amount| mine_grant(&mut theirs, amount))
+            .collect();
+        let error = ours.replace_chain(branch).unwrap_err();
+        assert!(error.contains("treasury reward"), "{}", error);
+        assert_eq!(ours.chain.len(), 2);
+    }
+
+    #[test]
+    fn blocks_may_not_include_expired_transactions() {
+        let mut producer = dev_chain();
+        let mut block = mine_grant(&mut producer, 10);
+        let mut follower = dev_chain();
+
+        let time = block.timestamp.timestamp() as u64;
+        let (_, address) = new_account();
+        let mut expired = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &address, 1);
+        expired.valid_until = time - 1;
+        block.transactions.push(expired);
+        block.merkle_root = block.calculate_merkle_root();
+        block.hash = block.calculate_hash();
+
+        let error = follower.add_block(block).unwrap_err();
+        assert!(error.contains("expired"), "{}", error);
+    }
+
+    #[test]
+    fn expired_transactions_are_left_out_of_blocks() {
+        let chain = dev_chain();
+        let (_, address) = new_account();
+        let now = chain.clock.unix_now();
+        let mut tx = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &address, 1);
+        tx.valid_until = now - 1;
+
+        let (_, miner) = new_account();
+        let miner_key = PublicKey::from_slice(&hex::decode(miner).unwrap()).unwrap();
+        let block = chain.assemble_block(vec![tx], &miner_key).into_block();
+        assert!(block.transactions.is_empty());
+    }
+
+    #[test]
+    fn heavier_branch_replaces_our_blocks() {
+        let mut ours = dev_chain();
+        let orphan = mine_grant(&mut ours, 10);
+        let mut theirs = dev_chain();
+        let branch = vec![mine_grant(&mut theirs, 20), mine_grant(&mut theirs, 30)];
+
+        ours.replace_chain(branch.clone()).unwrap();
+        assert_eq!(ours.chain.tip().hash, branch[1].hash);
+        // The reverted grant is back in the pool, to be mined on the new branch
+        let orphaned = orphan.transactions[0].hash();
+        assert!(ours.transaction_pool.iter().any(|tx| tx.hash() == orphaned));
+    }
+
+    #[test]
+    fn blocks_keep_the_rules_of_the_engine_that_made_them() {
+        let pow = || {
+            let params = ConsensusParams {
+                difficulty_bits: Some(4),
+                ..Config::default().consensus_params()
+            };
+            consensus("pow", params)
+        };
+        let mut ours = dev_chain();
+        mine_grant(&mut ours, 10);
+        mine_grant(&mut ours, 10);
+        ours.set_consensus(pow());
+
+        // Both switched at height 3; their dev blocks below it are judged as dev
+        // blocks and their PoW block above it as PoW
+        let mut theirs = dev_chain();
+        let mut branch = vec![mine_grant(&mut theirs, 20), mine_grant(&mut theirs, 30)];
+        theirs.set_consensus(pow());
+        branch.push(mine_grant(&mut theirs, 40));
+        theirs.validate().unwrap();
+
+        ours.replace_chain(branch).unwrap();
+        assert_eq!(ours.chain.tip().hash, theirs.chain.tip().hash);
+        assert_eq!(ours.watch_tip().borrow().cumulative_work, 3 + 16);
+        ours.validate().unwrap();
+
+        // A dev block can't follow the switch
+        let mut dev_only = dev_chain();
+        let branch = (1..=4)
+            .map(|amount| mine_grant(&mut dev_only, amount))
+            .collect();
+        let error = ours.replace_chain(branch).unwrap_err();
+        assert!(
+            error.contains("fails Proof of Work validation"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn lighter_or_unconnected_branches_are_refused() {
+        let mut ours = dev_chain();
+        mine_grant(&mut ours, 10);
+        mine_grant(&mut ours, 10);
+        let tip = ours.chain.tip().hash.clone();
+
+        let mut theirs = dev_chain();
+        let lighter = vec![mine_grant(&mut theirs, 20)];
+        assert!(ours.replace_chain(lighter).is_err());
+        let unconnected = vec![mine_grant(&mut theirs, 20)];
+        assert!(ours.replace_chain(unconnected).is_err());
+        assert_eq!(ours.chain.tip().hash, tip);
+    }
+
+    #[test]
+    fn reorgs_deeper_than_the_limit_are_refused() {
+        let mut ours = dev_chain();
+        ours.max_reorg_depth = 1;
+        mine_grant(&mut ours, 10);
+        mine_grant(&mut ours, 10);
+
+        let mut theirs = dev_chain();
+        let branch: Vec<Block> = (0..3).map(|_| mine_grant(&mut theirs, 20)).collect();
+        let error = ours.replace_chain(branch).unwrap_err();
+        assert!(error.contains("reverts 2 blocks"), "{}", error);
+    }
+
+    // A block from `producer` with its state root stripped, as blocks from
+    // before state roots were
+    fn rootless_block(producer: &mut Blockchain) -> Block {
+        let mut block = mine_grant(producer, 10);
+        block.state_root.clear();
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    #[test]
+    fn state_root_is_required_without_an_activation_height() {
+        let block = rootless_block(&mut dev_chain());
+        let error = dev_chain().add_block(block).unwrap_err();
+        assert!(error.contains("no state root"), "{}", error);
+    }
+
+    #[test]
+    fn state_root_is_optional_only_below_the_activation_height() {
+        let mut producer = dev_chain();
+        let early = rootless_block(&mut producer);
+        let late = rootless_block(&mut producer);
+
+        let mut follower = dev_chain();
+        follower.activation_height = Some(2);
+        follower.add_block(early).unwrap();
+        assert!(follower.add_block(late).is_err());
+    }
+
+    #[test]
+    fn mismatched_state_root_is_rejected() {
+        let mut block = mine_grant(&mut dev_chain(), 10);
+        block.state_root = AccountState::default().root();
+        block.hash = block.calculate_hash();
+        let error = dev_chain().add_block(block).unwrap_err();
+        assert!(error.contains("mismatched state root"), "{}", error);
+    }
+
+    #[test]
+    fn base_fee_follows_block_fullness() {
+        let mut chain = dev_chain();
+        chain.burn_base_fee = true;
+        chain.min_fee = 2;
+        let mut parent = chain.chain.tip().clone();
+        assert_eq!(chain.base_fee_after(&parent), 2);
+
+        parent.base_fee = 80;
+        assert_eq!(chain.base_fee_after(&parent), 70);
+        parent.transactions = vec![Transaction::new("a", "b", 1); BASE_FEE_TARGET_TRANSACTIONS];
+        assert_eq!(chain.base_fee_after(&parent), 80);
+        parent.transactions.push(Transaction::new("a", "b", 1));
+        assert_eq!(chain.base_fee_after(&parent), 90);
+
+        chain.burn_base_fee = false;
+        assert_eq!(chain.base_fee_after(&parent), 0);
+    }
+
+    #[test]
+    fn base_fee_must_match_the_schedule() {
+        let mut producer = dev_chain();
+        producer.burn_base_fee = true;
+        producer.min_fee = 3;
+        let block = mine_grant(&mut producer, 10);
+        assert_eq!(block.base_fee, 3);
+
+        let mut follower = dev_chain();
+        follower.burn_base_fee = true;
+        follower.min_fee = 3;
+        let mut inflated = block.clone();
+        inflated.base_fee = 4;
+        inflated.hash = inflated.calculate_hash();
+        let error = follower.add_block(inflated).unwrap_err();
+        assert!(error.contains("base fee"), "{}", error);
+
+        follower.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn forks_off_the_base_fee_schedule_are_refused() {
+        let mut ours = dev_chain();
+        ours.burn_base_fee = true;
+        ours.min_fee = 3;
+        mine_grant(&mut ours, 10);
+
+        let mut theirs = dev_chain();
+        theirs.burn_base_fee = true;
+        theirs.min_fee = 7;
+        let branch: Vec<Block> = (1..=2)
+            .map(|amount| mine_grant(&mut theirs, amount))
+            .collect();
+        let error = ours.replace_chain(branch).unwrap_err();
+        assert!(
+            error.contains("records base fee 7, expected 3"),
+            "{}",
+            error
+        );
+        assert_eq!(ours.chain.len(), 2);
+    }
+
+    #[test]
+    fn saved_chain_reloads_in_either_format() {
+        let mut chain = dev_chain();
+        for amount in 1..=3 {
+            mine_grant(&mut chain, amount);
+        }
+        let tip = chain.chain.tip().hash.clone();
+
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let path = std::env::temp_dir().join(format!(
+                "mockchain-roundtrip-{:?}-{}",
+                format,
+                std::process::id()
+            ));
+            let storage = Storage {
+                path: path.clone(),
+                format,
+                compress: false,
+                passphrase: None,
+            };
+            chain.save_to_path(&storage).unwrap();
+
+            // The format is detected from the file, not passed in
+            let consensus = ConsensusRegistry::default()
+                .create("dev", &Config::default().consensus_params())
+                .unwrap();
+            let loaded = Blockchain::load_from_path(&path, consensus, chain.chain_id, false, None);
+            std::fs::remove_file(&path).unwrap();
+            let loaded = loaded.unwrap();
+            assert_eq!(loaded.chain.len(), 4, "{:?}", format);
+            assert_eq!(loaded.chain.tip().hash, tip, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn pending_transactions_survive_a_switch_to_pos() {
+        let params = ConsensusParams {
+            difficulty: 1,
+            ..Config::default().consensus_params()
+        };
+        let mut chain = Blockchain::new(consensus("pow", params.clone()), None, "");
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let (_, recipient) = new_account();
+        let tx = transfer(&chain, &sender_key, &sender, &recipient, 40);
+        chain.add_transaction(tx.clone()).unwrap();
+
+        let (producer_key, producer) = new_account();
+        let pos = consensus(
+            "pos",
+            ConsensusParams {
+                bootstrap_validator: Some(producer.clone()),
+                ..params
+            },
+        );
+        assert_eq!(chain.set_consensus(pos), 0);
+        assert_eq!(chain.transaction_pool.len(), 1);
+
+        chain.producer_key = Some(producer_key);
+        let producer = PublicKey::from_slice(&hex::decode(producer).unwrap()).unwrap();
+        let block = chain.mine_pending_transactions(&producer).unwrap();
+        assert!(block
+            .transactions
+            .iter()
+            .any(|mined| mined.hash() == tx.hash()));
+        assert_eq!(chain.get_balance(&recipient), 40);
+    }
+
+    #[test]
+    fn undo_restores_balances_and_requeues_transactions() {
+        let mut chain = dev_chain();
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let (_, recipient) = new_account();
+        let tx = transfer(&chain, &sender_key, &sender, &recipient, 40);
+        chain.add_transaction(tx.clone()).unwrap();
+        let miner = chain
+            .consensus
+            .select_miner(chain.chain.len() as u64)
+            .unwrap();
+        let mined_before = chain.get_balance(&miner);
+        let block = chain.mine_pending_transactions(&any_miner()).unwrap();
+        assert_eq!(chain.get_balance(&recipient), 40);
+
+        let undone = chain.undo_last_block().unwrap();
+        assert_eq!(undone.hash, block.hash);
+        assert_eq!(chain.chain.tip().index, block.index - 1);
+        assert_eq!(chain.get_balance(&sender), 100);
+        assert_eq!(chain.get_balance(&recipient), 0);
+        assert_eq!(chain.get_balance(&miner), mined_before);
+        let pooled: Vec<String> = chain.transaction_pool.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(pooled, vec![tx.hash()]);
+    }
+
+    #[test]
+    fn blocks_with_unaffordable_transactions_are_rejected() {
+        let mut producer = dev_chain();
+        let mut follower = dev_chain();
+        let (sender_key, sender) = funded_account(&mut producer, 10);
+        follower.add_block(producer.chain.tip().clone()).unwrap();
+
+        let (_, recipient) = new_account();
+        let mut block = mine_grant(&mut producer, 5);
+        let overspend = transfer(&producer, &sender_key, &sender, &recipient, 50);
+        block.transactions.push(overspend);
+        block.merkle_root = block.calculate_merkle_root();
+        block.hash = block.calculate_hash();
+
+        let error = follower.add_block(block).unwrap_err();
+        assert!(error.contains("overdraws"), "{}", error);
+        assert_eq!(follower.chain.len(), 2);
+        assert_eq!(follower.get_balance(&sender), 10);
+        assert_eq!(follower.get_balance(&recipient), 0);
+    }
+
+    #[test]
+    fn chains_saved_with_another_hash_algorithm_are_refused() {
+        let mut chain = dev_chain();
+        mine_grant(&mut chain, 10);
+        let path = std::env::temp_dir().join(format!("mockchain-algo-{}", std::process::id()));
+        let storage = Storage {
+            path: path.clone(),
+            format: StorageFormat::Json,
+            compress: false,
+            passphrase: None,
+        };
+        chain.save_to_path(&storage).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("\"hash_algo\": \"Sha256\""));
+        std::fs::write(&path, saved.replace("\"Sha256\"", "\"Blake3\"")).unwrap();
+
+        let loaded = Blockchain::load_from_path(
+            &path,
+            consensus("dev", Config::default().consensus_params()),
+            chain.chain_id,
+            false,
+            None,
+        );
+        std::fs::remove_file(&path).unwrap();
+        let error = loaded.err().unwrap().to_string();
+        assert!(error.contains("saved with Blake3"), "{}", error);
+    }
+
+    #[test]
+    fn fees_below_the_floor_are_rejected_and_the_floor_rises_with_congestion() {
+        let mut chain = dev_chain();
+        chain.min_fee = 5;
+        let (sender_key, sender) = funded_account(&mut chain, 1000);
+        let (_, recipient) = new_account();
+        let pay = |chain: &mut Blockchain, amount, fee| {
+            let tx = transfer_with_fee(chain, &sender_key, &sender, &recipient, amount, fee);
+            chain.add_transaction(tx)
+        };
+
+        let error = pay(&mut chain, 1, 4).unwrap_err();
+        assert!(error.contains("below the minimum of 5"), "{}", error);
+        pay(&mut chain, 1, 5).unwrap();
+
+        // Two steps past the congestion threshold raise the floor by two
+        while chain.transaction_pool.len() < MEMPOOL_CONGESTION_THRESHOLD + 2 * FEE_FLOOR_STEP {
+            let (_, address) = new_account();
+            chain
+                .add_transaction(Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &address, 1))
+                .unwrap();
+        }
+        assert_eq!(chain.current_min_fee(), 7);
+        let error = pay(&mut chain, 2, 6).unwrap_err();
+        assert!(error.contains("below the minimum of 7"), "{}", error);
+        pay(&mut chain, 2, 7).unwrap();
+    }
+
+    #[test]
+    fn genesis_pays_no_reward() {
+        let mut chain = dev_chain();
+        let genesis = chain.chain.tip().clone();
+        assert_eq!(genesis.miner, GENESIS_MINER);
+        assert_eq!(genesis.miner_reward(), 0);
+        assert_eq!(chain.total_supply(), 0);
+
+        for _ in 0..3 {
+            mine_grant(&mut chain, 10);
+            assert_eq!(chain.get_balance(GENESIS_MINER), 0);
+            assert_eq!(chain.get_balance(""), 0);
+        }
+    }
+
+    #[test]
+    fn faucet_grants_wait_for_their_confirmations() {
+        let mut chain = dev_chain();
+        chain.faucet_confirmations = 2;
+        let (_, address) = funded_account(&mut chain, 10);
+        assert_eq!(chain.get_balance(&address), 0);
+
+        mine_grant(&mut chain, 1);
+        assert_eq!(chain.get_balance(&address), 0);
+        mine_grant(&mut chain, 1);
+        assert_eq!(chain.get_balance(&address), 10);
+    }
+
+    #[test]
+    fn blocks_mined_within_one_instant_get_increasing_timestamps() {
+        let mut chain = dev_chain();
+        // The clock stands still, so every block is mined at the same moment
+        chain.clock = Arc::new(MockClock::new(Utc::now()));
+        let blocks: Vec<Block> = (0..20).map(|_| mine_grant(&mut chain, 1)).collect();
+        for pair in blocks.windows(2) {
+            assert!(pair[1].timestamp > pair[0].timestamp);
+        }
+        chain.validate().unwrap();
+    }
+
+    #[test]
+    fn historical_balances_exclude_later_blocks() {
+        let mut chain = dev_chain();
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let funded_at = chain.chain.height();
+        let (_, recipient) = new_account();
+        let tx = transfer(&chain, &sender_key, &sender, &recipient, 30);
+        chain.add_transaction(tx).unwrap();
+        chain.mine_pending_transactions(&any_miner()).unwrap();
+
+        assert_eq!(chain.get_balance_at(&sender, funded_at - 1), Some(0));
+        assert_eq!(chain.get_balance_at(&sender, funded_at), Some(100));
+        assert_eq!(chain.get_balance_at(&recipient, funded_at), Some(0));
+        assert_eq!(chain.get_balance_at(&sender, funded_at + 1), Some(70));
+        assert_eq!(chain.get_balance_at(&recipient, funded_at + 1), Some(30));
+        assert_eq!(chain.get_balance_at(&sender, funded_at + 2), None);
+    }
+
+    #[test]
+    fn large_transactions_fill_the_byte_limit_first() {
+        let mut chain = dev_chain();
+        chain.block_gas_limit = u64::MAX;
+        let batches: Vec<Transaction> = (0..5)
+            .map(|_| {
+                let mut tx = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, "", 0);
+                tx.outputs = (0..10).map(|_| (new_account().1, 1)).collect();
+                tx
+            })
+            .collect();
+        let size = batches[0].size();
+        chain.max_block_bytes = 3 * size + size / 2;
+
+        let (included, deferred) = chain.select_block_transactions(batches);
+        assert_eq!(included.len(), 3);
+        assert_eq!(deferred.len(), 2);
+        assert!(included.len() < MAX_BLOCK_TRANSACTIONS);
+    }
+
+    #[test]
+    fn inflated_coinbase_is_rejected() {
+        let mut producer = dev_chain();
+        let mut follower = dev_chain();
+        let block = mine_grant(&mut producer, 10);
+
+        let mut inflated = block.clone();
+        inflated.subsidy = Some(block::reward_for_index(block.index) + 1);
+        inflated.hash = inflated.calculate_hash();
+        assert!(!inflated.has_valid_reward());
+        // Every consensus refuses a reward above the schedule
+        let error = follower.add_block(inflated).unwrap_err();
+        assert!(error.contains("fails Dev Instant validation"), "{}", error);
+
+        follower.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn senders_are_limited_in_pending_transactions() {
+        let mut chain = dev_chain();
+        chain.max_pending_per_sender = 3;
+        let (alice_key, alice) = funded_account(&mut chain, 100);
+        let (bob_key, bob) = funded_account(&mut chain, 100);
+        let (_, recipient) = new_account();
+
+        for amount in 1..=3 {
+            let tx = transfer(&chain, &alice_key, &alice, &recipient, amount);
+            chain.add_transaction(tx).unwrap();
+        }
+        let fourth = transfer(&chain, &alice_key, &alice, &recipient, 4);
+        let error = chain.add_transaction(fourth).unwrap_err();
+        assert!(error.contains("already has 3 pending"), "{}", error);
+
+        let other = transfer(&chain, &bob_key, &bob, &recipient, 4);
+        chain.add_transaction(other).unwrap();
+    }
+
+    #[test]
+    fn empty_blocks_are_mined_once_the_heartbeat_is_due() {
+        let mut chain = dev_chain();
+        let clock = MockClock::new(Utc::now());
+        chain.clock = Arc::new(clock.clone());
+        chain.allow_empty_blocks = true;
+        mine_grant(&mut chain, 1);
+        assert!(chain.mine_pending_transactions(&any_miner()).is_none());
+
+        clock.advance(chain.heartbeat_interval);
+        let heartbeat = chain.mine_pending_transactions(&any_miner()).unwrap();
+        assert!(heartbeat.transactions.is_empty());
+        assert_eq!(chain.chain.len(), 3);
+
+        chain.allow_empty_blocks = false;
+        clock.advance(chain.heartbeat_interval);
+        assert!(chain.mine_pending_transactions(&any_miner()).is_none());
+    }
+
+    #[test]
+    fn transactions_past_the_gas_limit_are_deferred() {
+        let mut chain = dev_chain();
+        chain.block_gas_limit = 5 * transaction::TRANSFER_GAS;
+        let mut batch = Transaction::new("batcher", "", 0);
+        batch.outputs = (0..4).map(|_| (new_account().1, 1)).collect();
+        batch.fee = 1_000;
+        let transfers = ["alice", "bob"].map(|from| Transaction::new(from, "carol", 1));
+
+        let mut pending = vec![batch.clone()];
+        pending.extend(transfers);
+        let (included, deferred) = chain.select_block_transactions(pending);
+        assert_eq!(included.len(), 2);
+        assert_eq!(included[0].hash(), batch.hash());
+        let gas: u64 = included.iter().map(Transaction::gas_used).sum();
+        assert_eq!(gas, chain.block_gas_limit);
+        assert_eq!(deferred.len(), 1);
+        assert_eq!(deferred[0].to, "carol");
+    }
+
+    #[test]
+    fn lucky_hashes_add_no_work() {
+        let pow_chain = || {
+            let params = ConsensusParams {
+                difficulty_bits: Some(4),
+                ..Config::default().consensus_params()
+            };
+            Blockchain::new(
+                consensus("pow", params),
+                DateTime::from_timestamp(1_700_000_000, 0),
+                "",
+            )
+        };
+        let mut ours = pow_chain();
+        let mut theirs = pow_chain();
+        mine_grant(&mut ours, 10);
+
+        // Three more zero digits than the 4-bit target needs
+        let mut lucky = mine_grant(&mut theirs, 20);
+        while !lucky.hash.starts_with("0000") {
+            lucky.nonce += 1;
+            lucky.hash = lucky.calculate_hash();
+        }
+        let error = ours.replace_chain(vec![lucky.clone()]).unwrap_err();
+        assert!(error.contains("not heavier"), "{}", error);
+
+        // Two blocks at the same target are more work
+        let mut longer = pow_chain();
+        let branch = vec![mine_grant(&mut longer, 20), mine_grant(&mut longer, 30)];
+        ours.replace_chain(branch).unwrap();
+        assert_eq!(ours.chain.len(), 3);
+        assert_eq!(ours.watch_tip().borrow().cumulative_work, 3 * 16);
+    }
+
+    #[test]
+    fn reorgs_return_only_transactions_the_new_chain_lacks() {
+        let mut ours = dev_chain();
+        let (sender_key, sender) = funded_account(&mut ours, 100);
+        let mut theirs = dev_chain();
+        theirs.add_block(ours.chain.tip().clone()).unwrap();
+
+        let (_, recipient) = new_account();
+        let orphaned = transfer(&ours, &sender_key, &sender, &recipient, 30);
+        let shared = transfer(&ours, &sender_key, &sender, &recipient, 20);
+        ours.add_transaction(orphaned.clone()).unwrap();
+        ours.add_transaction(shared.clone()).unwrap();
+        ours.mine_pending_transactions(&any_miner()).unwrap();
+
+        theirs.add_transaction(shared.clone()).unwrap();
+        let branch = vec![
+            theirs.mine_pending_transactions(&any_miner()).unwrap(),
+            mine_grant(&mut theirs, 5),
+        ];
+        ours.replace_chain(branch).unwrap();
+
+        let pooled: Vec<String> = ours
+            .transaction_pool
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        assert_eq!(pooled, [orphaned.hash()]);
+        ours.mine_pending_transactions(&any_miner()).unwrap();
+        assert_eq!(ours.get_balance(&recipient), 50);
+    }
+
+    #[test]
+    fn pool_transactions_are_dropped_after_the_ttl() {
+        let mut chain = dev_chain();
+        let clock = MockClock::new(Utc::now());
+        chain.clock = Arc::new(clock.clone());
+        chain.mempool_tx_ttl = Some(Duration::from_secs(60));
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let (_, recipient) = new_account();
+
+        let old = transfer(&chain, &sender_key, &sender, &recipient, 1);
+        chain.add_transaction(old).unwrap();
+        clock.advance(Duration::from_secs(30));
+        let recent = transfer(&chain, &sender_key, &sender, &recipient, 2);
+        chain.add_transaction(recent.clone()).unwrap();
+        assert_eq!(chain.prune_expired(), 0);
+
+        clock.advance(Duration::from_secs(31));
+        assert_eq!(chain.prune_expired(), 1);
+        let pooled: Vec<String> = chain
+            .transaction_pool
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        assert_eq!(pooled, [recent.hash()]);
+    }
+
+    #[test]
+    fn blocks_with_the_wrong_index_are_rejected() {
+        let mut producer = dev_chain();
+        let mut follower = dev_chain();
+        let mut block = mine_grant(&mut producer, 10);
+        block.index += 1;
+        block.hash = block.calculate_hash();
+
+        let error = follower.add_block(block.clone()).unwrap_err();
+        assert!(error.contains("does not follow"), "{}", error);
+        assert_eq!(follower.chain.len(), 1);
+
+        // Loaded chains are checked the same way
+        follower.chain.push(block);
+        let error = follower.validate().unwrap_err();
+        assert!(error.contains("claims index 2"), "{}", error);
+    }
+
+    #[test]
+    fn a_sender_is_mined_in_timestamp_order_whatever_the_fees() {
+        let mut chain = dev_chain();
+        let earlier = Transaction::new("alice", "bob", 1);
+        let mut later = Transaction::new("alice", "bob", 2);
+        later.timestamp = earlier.timestamp + 1;
+        later.fee = 100;
+        let mut other = Transaction::new("carol", "bob", 3);
+        other.fee = 50;
+        let pending = || vec![later.clone(), earlier.clone(), other.clone()];
+
+        let (included, _) = chain.select_block_transactions(pending());
+        let amounts: Vec<u64> = included.iter().map(|tx| tx.amount).collect();
+        assert_eq!(amounts, [3, 1, 2]);
+
+        // With room for one, the highest fee still waits on its sender's earlier transaction
+        chain.block_gas_limit = transaction::TRANSFER_GAS;
+        let (included, deferred) = chain.select_block_transactions(pending());
+        assert_eq!(included.iter().map(|tx| tx.amount).collect::<Vec<_>>(), [3]);
+        assert_eq!(deferred.len(), 2);
+    }
+
+    #[test]
+    fn identical_pools_build_identical_blocks() {
+        let clock = MockClock::new(Utc::now());
+        let grants: Vec<Transaction> = (1..7)
+            .map(|amount| {
+                let mut tx = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &new_account().1, amount);
+                // Equal timestamps leave the order to the transaction hashes
+                tx.timestamp = 1_700_000_000;
+                tx
+            })
+            .collect();
+        let miner = any_miner();
+        let build = |order: &mut dyn Iterator<Item = &Transaction>| {
+            let mut chain = dev_chain();
+            chain.clock = Arc::new(clock.clone());
+            for tx in order {
+                chain.add_transaction(tx.clone()).unwrap();
+            }
+            chain.mine_pending_transactions(&miner).unwrap()
+        };
+
+        let forward = build(&mut grants.iter());
+        let backward = build(&mut grants.iter().rev());
+        assert_eq!(forward.transactions.len(), grants.len());
+        assert_eq!(forward.hash, backward.hash);
+    }
+
+    #[test]
+    fn stakes_survive_a_save_and_reload() {
+        let pos = |min_stake| {
+            consensus(
+                "pos",
+                ConsensusParams {
+                    min_stake,
+                    ..Config::default().consensus_params()
+                },
+            )
+        };
+        let chain = Blockchain::new(pos(100), DateTime::from_timestamp(1_700_000_000, 0), "");
+        chain.consensus.register_stake("small", 150).unwrap();
+        chain.consensus.register_stake("large", 500).unwrap();
+
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let path = std::env::temp_dir().join(format!(
+                "mockchain-stakes-{:?}-{}",
+                format,
+                std::process::id()
+            ));
+            let storage = Storage {
+                path: path.clone(),
+                format,
+                compress: false,
+                passphrase: None,
+            };
+            chain.save_to_path(&storage).unwrap();
+
+            let reloaded = Blockchain::load_from_path(&path, pos(100), 0, false, None).unwrap();
+            assert_eq!(
+                reloaded.consensus.validators(),
+                chain.consensus.validators()
+            );
+            // Stakes the node's minimum has since outgrown are dropped
+            let raised = Blockchain::load_from_path(&path, pos(200), 0, false, None).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(raised.consensus.validators(), [("large".to_string(), 500)]);
+        }
+    }
+
+    #[test]
+    fn blocks_from_the_far_future_wait_for_our_clock() {
+        let start = Utc::now();
+        let mut producer = dev_chain();
+        producer.clock = Arc::new(MockClock::new(start + chrono::Duration::hours(1)));
+        let block = mine_grant(&mut producer, 10);
+
+        let mut follower = dev_chain();
+        let clock = MockClock::new(start);
+        follower.clock = Arc::new(clock.clone());
+        follower.max_future_block_time = Duration::from_secs(60);
+        let error = follower.add_block(block.clone()).unwrap_err();
+        assert!(error.contains("in the future"), "{}", error);
+
+        clock.advance(Duration::from_secs(3_600));
+        follower.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn tampered_chain_files_fail_to_load() {
+        let mut chain = dev_chain();
+        mine_grant(&mut chain, 10);
+        let path = std::env::temp_dir().join(format!("mockchain-tampered-{}", std::process::id()));
+        let storage = Storage {
+            path: path.clone(),
+            format: StorageFormat::Json,
+            compress: false,
+            passphrase: None,
+        };
+        chain.save_to_path(&storage).unwrap();
+        let json = std::fs::read_to_string(&path).unwrap();
+        assert!(json.contains("\"amount\": 10,"));
+        std::fs::write(&path, json.replace("\"amount\": 10,", "\"amount\": 10000,")).unwrap();
+
+        let load = |skip_validation| {
+            Blockchain::load_from_path(
+                &path,
+                consensus("dev", Config::default().consensus_params()),
+                0,
+                skip_validation,
+                None,
+            )
+        };
+        let error = load(false).err().unwrap().to_string();
+        assert!(error.contains("failed its consistency check"), "{}", error);
+        let skipped = load(true);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(skipped.unwrap().chain.len(), 2);
+    }
+
+    #[test]
+    fn genesis_messages_give_distinct_genesis_blocks() {
+        let genesis = |message: &str| {
+            let time = DateTime::from_timestamp(1_700_000_000, 0);
+            let dev = consensus("dev", Config::default().consensus_params());
+            Blockchain::new(dev, time, message).chain.tip().clone()
+        };
+        let plain = genesis("");
+        let tagged = genesis("testnet 1");
+        assert_eq!(tagged.data, "testnet 1");
+        assert_ne!(tagged.hash, plain.hash);
+        assert_ne!(genesis("testnet 2").hash, tagged.hash);
+        assert_eq!(genesis("testnet 1").hash, tagged.hash);
+    }
+
+    #[test]
+    fn subsidies_stop_at_the_max_supply() {
+        let mut chain = dev_chain();
+        let clock = MockClock::new(Utc::now());
+        chain.clock = Arc::new(clock.clone());
+        chain.allow_empty_blocks = true;
+        mine_grant(&mut chain, 10);
+        let reward = block::reward_for_index(2);
+        let cap = chain.total_supply() + reward + reward / 2;
+        chain.max_supply = Some(cap);
+
+        let mut subsidies = Vec::new();
+        for _ in 0..3 {
+            clock.advance(chain.heartbeat_interval);
+            let block = chain.mine_pending_transactions(&any_miner()).unwrap();
+            subsidies.push(block.minted_subsidy());
+        }
+        assert_eq!(subsidies, [reward, reward / 2, 0]);
+        assert_eq!(chain.total_supply(), cap);
+        chain.validate().unwrap();
+    }
+
+    #[test]
+    fn fee_bumps_replace_pending_transactions() {
+        let mut chain = dev_chain();
+        let clock = MockClock::new(Utc::now());
+        chain.clock = Arc::new(clock.clone());
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let (_, recipient) = new_account();
+        let original = transfer_with_fee(&chain, &sender_key, &sender, &recipient, 30, 10);
+        chain.add_transaction(original.clone()).unwrap();
+        let chain_id = chain.chain_id;
+        let bumped = |fee| {
+            let mut tx = original.clone();
+            tx.fee = fee;
+            tx.sign(&sender_key, chain_id);
+            tx
+        };
+        let error = chain.add_transaction(bumped(20)).unwrap_err().to_string();
+        assert!(error.contains("can't be replaced yet"), "{}", error);
+        clock.advance(chain.rbf_min_interval);
+        // A 10% bump on a fee of 10 rounds up to 11
+        let error = chain.add_transaction(bumped(5)).unwrap_err().to_string();
+        assert!(error.contains("must be at least 11"), "{}", error);
+        let replacement = bumped(11);
+        chain.add_transaction(replacement.clone()).unwrap();
+
+        let pooled: Vec<String> = chain
+            .transaction_pool
+            .iter()
+            .map(Transaction::hash)
+            .collect();
+        assert_eq!(pooled, [replacement.hash()]);
+        // The replaced transaction can't be swapped back in
+        assert!(chain.add_transaction(original).is_err());
+    }
+
+    #[test]
+    fn fee_bumps_that_overflow_are_refused() {
+        let mut chain = dev_chain();
+        chain.min_rbf_bump_percent = u64::MAX;
+        let mut pending = Transaction::new("sender", "recipient", 1);
+        pending.fee = 2;
+        let mut replacement = pending.clone();
+        replacement.fee = u64::MAX;
+        let error = chain.check_replacement(&pending, &replacement).unwrap_err();
+        assert!(error.contains("can't be bumped"), "{}", error);
+
+        // Bumping the largest fee by any amount overflows too
+        chain.min_rbf_bump_percent = 10;
+        pending.fee = u64::MAX;
+        assert!(chain.check_replacement(&pending, &replacement).is_err());
+    }
+
+    #[test]
+    fn assembling_then_sealing_matches_mining() {
+        let clock = MockClock::new(Utc::now());
+        let grant = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &new_account().1, 10);
+        let miner = any_miner();
+        let pow_chain = || {
+            let params = ConsensusParams {
+                difficulty_bits: Some(6),
+                ..Config::default().consensus_params()
+            };
+            let time = DateTime::from_timestamp(1_700_000_000, 0);
+            let mut chain = Blockchain::new(consensus("pow", params), time, "");
+            chain.clock = Arc::new(clock.clone());
+            chain.add_transaction(grant.clone()).unwrap();
+            chain
+        };
+
+        let staged = pow_chain();
+        let unsealed = staged.assemble_block(vec![grant.clone()], &miner);
+        assert_eq!(unsealed.block().transactions.len(), 1);
+        let sealed = staged.seal_block(unsealed);
+        let mined = pow_chain().mine_pending_transactions(&miner).unwrap();
+        assert_eq!(sealed.hash, mined.hash);
+        assert_eq!(sealed.nonce, mined.nonce);
+        assert!(staged
+            .consensus
+            .validate_block(&sealed, &staged.chain.tip().hash));
+    }
+
+    // Asserts both transaction indexes agree with a scan of the chain
+    fn assert_indexes_match(chain: &Blockchain) {
+        for block in chain.chain.iter() {
+            let hashes: Vec<String> = block.transactions.iter().map(Transaction::hash).collect();
+            assert_eq!(chain.block_tx_hashes(block.index), Some(hashes.as_slice()));
+            for (position, hash) in hashes.iter().enumerate() {
+                assert_eq!(
+                    chain.locate_transaction(hash),
+                    Some((block.index, position))
+                );
+            }
+        }
+        assert_eq!(chain.block_tx_hashes(chain.chain.len() as u64), None);
+    }
+
+    #[test]
+    fn transaction_indexes_follow_the_chain() {
+        let mut chain = dev_chain();
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        for amount in 1..=3 {
+            for _ in 0..amount {
+                let tx = transfer(&chain, &sender_key, &sender, &new_account().1, amount);
+                chain.add_transaction(tx).unwrap();
+            }
+            chain.mine_pending_transactions(&any_miner()).unwrap();
+            assert_indexes_match(&chain);
+        }
+
+        let undone = chain.undo_last_block().unwrap();
+        assert_indexes_match(&chain);
+        for tx in &undone.transactions {
+            assert_eq!(chain.locate_transaction(&tx.hash()), None);
+        }
+    }
+
+    #[test]
+    fn zero_fee_transactions_follow_the_policy() {
+        for allow_zero_fee in [true, false] {
+            let mut chain = dev_chain();
+            chain.allow_zero_fee = allow_zero_fee;
+            // Faucet grants are free under either policy
+            let (sender_key, sender) = funded_account(&mut chain, 100);
+            let (_, recipient) = new_account();
+
+            let free = transfer(&chain, &sender_key, &sender, &recipient, 10);
+            let result = chain.add_transaction(free);
+            assert_eq!(result.is_ok(), allow_zero_fee, "{:?}", result.err());
+            if !allow_zero_fee {
+                let paid = transfer_with_fee(&chain, &sender_key, &sender, &recipient, 10, 1);
+                chain.add_transaction(paid).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn advancing_the_mock_clock_expires_transactions() {
+        let mut chain = dev_chain();
+        let clock = MockClock::new(Utc::now());
+        chain.clock = Arc::new(clock.clone());
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let chain_id = chain.chain_id;
+        let expiring = || {
+            let mut tx = Transaction::new(&sender, &new_account().1, 10);
+            tx.valid_until = clock.unix_now() + 60;
+            tx.sign(&sender_key, chain_id);
+            tx
+        };
+
+        chain.add_transaction(expiring()).unwrap();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(chain.prune_expired(), 0);
+        let late = expiring();
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(chain.prune_expired(), 1);
+        assert!(chain.transaction_pool.is_empty());
+        let error = chain.add_transaction(late).unwrap_err().to_string();
+        assert!(error.contains("expired"), "{}", error);
+    }
+
+    #[test]
+    fn activation_rules_apply_from_the_activation_height() {
+        let mut producer = dev_chain();
+        let (sender_key, sender) = funded_account(&mut producer, 100);
+        // Transfers without an expiry, at heights 2 and 3
+        let blocks: Vec<Block> = (0..2)
+            .map(|_| {
+                let tx = transfer(&producer, &sender_key, &sender, &new_account().1, 10);
+                producer.add_transaction(tx).unwrap();
+                producer.mine_pending_transactions(&any_miner()).unwrap()
+            })
+            .collect();
+
+        let mut follower = dev_chain();
+        follower.activation_height = Some(3);
+        follower
+            .add_block(producer.chain.get(1).unwrap().into_owned())
+            .unwrap();
+        follower.add_block(blocks[0].clone()).unwrap();
+        let error = follower.add_block(blocks[1].clone()).unwrap_err();
+        assert!(
+            error.contains("must set valid_until from height 3"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn spends_overtaken_by_another_block_are_dropped_at_mine_time() {
+        let mut ours = dev_chain();
+        let (sender_key, sender) = funded_account(&mut ours, 100);
+        let mut peer = dev_chain();
+        peer.add_block(ours.chain.tip().clone()).unwrap();
+
+        let pending = transfer(&ours, &sender_key, &sender, &new_account().1, 80);
+        ours.add_transaction(pending.clone()).unwrap();
+        let intervening = transfer(&peer, &sender_key, &sender, &new_account().1, 50);
+        peer.add_transaction(intervening).unwrap();
+        ours.add_block(peer.mine_pending_transactions(&any_miner()).unwrap())
+            .unwrap();
+
+        // Valid when submitted, but only 50 is left to cover it
+        assert!(ours.mine_pending_transactions(&any_miner()).is_none());
+        assert!(ours.transaction_pool.is_empty());
+        assert_eq!(ours.locate_transaction(&pending.hash()), None);
+        assert_eq!(ours.get_balance(&sender), 50);
+    }
+
+    #[test]
+    fn time_locked_transactions_wait_in_the_pool_until_their_time() {
+        let mut chain = dev_chain();
+        let clock = MockClock::new(Utc::now());
+        chain.clock = Arc::new(clock.clone());
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let mut scheduled = Transaction::new(&sender, &new_account().1, 10);
+        scheduled.not_before = clock.unix_now() + 60;
+        scheduled.sign(&sender_key, chain.chain_id);
+
+        // The lock is signed, so it can't be lifted by a relay
+        let mut unlocked = scheduled.clone();
+        unlocked.not_before = 0;
+        assert!(!unlocked.verify(chain.chain_id));
+
+        chain.add_transaction(scheduled.clone()).unwrap();
+        clock.advance(Duration::from_secs(59));
+        assert!(chain.mine_pending_transactions(&any_miner()).is_none());
+        assert_eq!(chain.transaction_pool.len(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        let block = chain.mine_pending_transactions(&any_miner()).unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].hash(), scheduled.hash());
+        assert!(chain.transaction_pool.is_empty());
+    }
+
+    #[test]
+    fn mined_transactions_with_new_signature_bytes_are_not_paid_again() {
+        let mut chain = dev_chain();
+        let (sender_key, sender) = funded_account(&mut chain, 500);
+        let (_, recipient) = new_account();
+        let payment = transfer(&chain, &sender_key, &sender, &recipient, 100);
+        chain.add_transaction(payment.clone()).unwrap();
+        chain.mine_pending_transactions(&any_miner()).unwrap();
+
+        // Junk signatures change the hash but not what was signed
+        let mut padded = payment.clone();
+        padded.signatures.push(vec![1, 2, 3]);
+        assert_ne!(padded.hash(), payment.hash());
+        assert!(!padded.verify(chain.chain_id));
+        assert!(chain.add_transaction(padded).is_err());
+
+        // Another quorum of a multisig signs a valid copy with a new hash
+        let keys: Vec<(SecretKey, String)> = (0..3).map(|_| new_account()).collect();
+        let multisig =
+            transaction::Multisig::new(2, keys.iter().map(|(_, key)| key.clone()).collect());
+        chain
+            .add_transaction(Transaction::new(
+                FAUCET_MOCKCHAIN_ADDRESS,
+                &multisig.address(),
+                500,
+            ))
+            .unwrap();
+        chain.mine_pending_transactions(&any_miner()).unwrap();
+        let unsigned = Transaction::new_multisig(multisig, &recipient, 100);
+        let chain_id = chain.chain_id;
+        let signed_by = |first: usize, second: usize| {
+            let mut tx = unsigned.clone();
+            tx.sign_multisig(&keys[first].0, chain_id);
+            tx.sign_multisig(&keys[second].0, chain_id);
+            tx
+        };
+        chain.add_transaction(signed_by(0, 1)).unwrap();
+        chain.mine_pending_transactions(&any_miner()).unwrap();
+        let replay = signed_by(2, 1);
+        assert!(replay.verify(chain_id));
+        let error = chain.add_transaction(replay).unwrap_err();
+        assert!(error.contains("Duplicate"), "{}", error);
+
+        assert!(chain.mine_pending_transactions(&any_miner()).is_none());
+        assert_eq!(chain.get_balance(&recipient), 200);
+    }
+
+    #[test]
+    fn states_after_a_transaction_match_a_replay() {
+        let mut chain = dev_chain();
+        let (sender_key, sender) = funded_account(&mut chain, 100);
+        let (_, recipient) = new_account();
+        for amount in [10, 20, 30] {
+            let tx = transfer(&chain, &sender_key, &sender, &recipient, amount);
+            chain.add_transaction(tx).unwrap();
+        }
+        let block = chain.mine_pending_transactions(&any_miner()).unwrap();
+        // Receipts count grants straight away, as a replay does
+        chain.faucet_confirmations = 3;
+
+        let mut replayed = AccountState::default();
+        for earlier in chain.chain.iter().take(block.index as usize) {
+            replayed.apply_block(&earlier).unwrap();
+        }
+        for (position, tx) in block.transactions.iter().enumerate() {
+            replayed.apply_transaction(tx).unwrap();
+            let state = chain
+                .state_after_transaction(block.index, position)
+                .unwrap();
+            for address in [&sender, &recipient] {
+                assert_eq!(state.balances.get(address), replayed.balances.get(address));
+            }
+        }
+        assert!(chain.state_after_transaction(block.index, 3).is_none());
+        assert!(chain.state_after_transaction(block.index + 1, 0).is_none());
+    }
+}
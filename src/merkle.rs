@@ -0,0 +1,82 @@
+use crate::hash;
+
+fn hash_pair(left: &str, right: &str) -> String {
+    hash::hex_digest(format!("{}{}", left, right).as_bytes())
+}
+
+// Computes the Merkle root over a list of leaf hashes.
+// Odd levels duplicate their last node; an empty list yields an all-zero root.
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return "0".repeat(64);
+    }
+
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    level.remove(0)
+}
+
+// Returns the sibling hashes needed to rebuild the root from the leaf at `position`,
+// ordered from the leaf level upwards
+pub fn merkle_proof(leaves: &[String], position: usize) -> Vec<String> {
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    let mut index = position;
+
+    while level.len() > 1 {
+        let sibling = level.get(index ^ 1).unwrap_or(&level[index]);
+        proof.push(sibling.clone());
+
+        level = level
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        index /= 2;
+    }
+    proof
+}
+
+// Folds a proof back up to a root; matches the block's merkle_root only if the leaf is included
+pub fn root_from_proof(leaf: &str, position: usize, proof: &[String]) -> String {
+    let mut hash = leaf.to_string();
+    let mut index = position;
+
+    for sibling in proof {
+        hash = if index & 1 == 0 {
+            hash_pair(&hash, sibling)
+        } else {
+            hash_pair(sibling, &hash)
+        };
+        index /= 2;
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_verify_against_the_root_and_tampering_fails() {
+        let leaves: Vec<String> = (0..5u8).map(|i| hash::hex_digest(&[i])).collect();
+        let root = merkle_root(&leaves);
+
+        for (position, leaf) in leaves.iter().enumerate() {
+            let proof = merkle_proof(&leaves, position);
+            assert_eq!(root_from_proof(leaf, position, &proof), root);
+
+            let mut tampered = proof.clone();
+            tampered[0] = hash::hex_digest(b"forged");
+            assert_ne!(root_from_proof(leaf, position, &tampered), root);
+            assert_ne!(
+                root_from_proof(&leaves[(position + 1) % 5], position, &proof),
+                root
+            );
+        }
+    }
+}
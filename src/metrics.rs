@@ -0,0 +1,139 @@
+use std::{
+    collections::BTreeMap, convert::Infallible, fmt::Write, net::SocketAddr, sync::Mutex,
+    time::Duration,
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+// Upper bounds, in seconds, of the PoW mining time buckets
+const MINING_TIME_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Observations per bucket, not cumulative; ones above the last bound only count in `count`
+    buckets: [u64; MINING_TIME_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+// Time to seal a PoW block, by difficulty in target bits
+static MINING_TIME: Mutex<BTreeMap<u32, Histogram>> = Mutex::new(BTreeMap::new());
+
+pub fn observe_mining_time(difficulty_bits: u32, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let mut histograms = MINING_TIME.lock().unwrap();
+    let histogram = histograms.entry(difficulty_bits).or_default();
+    if let Some(bucket) = MINING_TIME_BUCKETS.iter().position(|le| seconds <= *le) {
+        histogram.buckets[bucket] += 1;
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+// Every metric in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP mockchain_block_mining_seconds Wall-clock time to find a valid PoW nonce\n",
+    );
+    out.push_str("# TYPE mockchain_block_mining_seconds histogram\n");
+    for (bits, histogram) in MINING_TIME.lock().unwrap().iter() {
+        let mut cumulative = 0;
+        for (le, count) in MINING_TIME_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "mockchain_block_mining_seconds_bucket{{difficulty_bits=\"{}\",le=\"{}\"}} {}",
+                bits, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "mockchain_block_mining_seconds_bucket{{difficulty_bits=\"{}\",le=\"+Inf\"}} {}",
+            bits, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "mockchain_block_mining_seconds_sum{{difficulty_bits=\"{}\"}} {}",
+            bits, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "mockchain_block_mining_seconds_count{{difficulty_bits=\"{}\"}} {}",
+            bits, histogram.count
+        );
+    }
+    out
+}
+
+// Serves `render` over plain HTTP at GET /metrics for Prometheus to scrape
+pub fn spawn(addr: SocketAddr) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let response = if request.uri().path() == "/metrics" {
+                    Response::new(Body::from(render()))
+                } else {
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = StatusCode::NOT_FOUND;
+                    response
+                };
+                Ok::<_, Infallible>(response)
+            }))
+        });
+
+        let server = match Server::try_bind(&addr) {
+            Ok(builder) => builder.serve(make_service),
+            Err(e) => {
+                warn!("Failed to bind metrics endpoint on {}: {}", addr, e);
+                return;
+            }
+        };
+        info!("Serving metrics on http://{}/metrics", addr);
+        if let Err(e) = server.await {
+            warn!("Metrics endpoint failed: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mining_times_render_as_a_cumulative_histogram() {
+        // The histograms are global, so use a difficulty no other test mines at
+        for millis in [3, 200, 120_000] {
+            observe_mining_time(201, Duration::from_millis(millis));
+        }
+        let rendered = render();
+        let sample = |series: &str| {
+            let prefix = format!("mockchain_block_mining_seconds_{} ", series);
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(&prefix))
+                .map(str::to_string)
+        };
+
+        let bucket = |le: &str| sample(&format!("bucket{{difficulty_bits=\"201\",le=\"{}\"}}", le));
+        assert_eq!(bucket("0.001").as_deref(), Some("0"));
+        assert_eq!(bucket("0.005").as_deref(), Some("1"));
+        assert_eq!(bucket("0.5").as_deref(), Some("2"));
+        assert_eq!(bucket("60").as_deref(), Some("2"));
+        assert_eq!(bucket("+Inf").as_deref(), Some("3"));
+        assert_eq!(
+            sample("count{difficulty_bits=\"201\"}").as_deref(),
+            Some("3")
+        );
+        let sum: f64 = sample("sum{difficulty_bits=\"201\"}")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((sum - 120.203).abs() < 1e-9);
+    }
+}
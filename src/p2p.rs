@@ -0,0 +1,494 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use tonic::{
+    transport::{Channel, Uri},
+    Response,
+};
+
+use crate::{
+    block::Block,
+    blockchain::{
+        blockchain_service_client::BlockchainServiceClient, Block as ProtoBlock, BlockRangeRequest,
+        BlockRangeResponse, ChainInfoRequest, CompactBlock, MissingTransactionsRequest,
+        Transaction as ProtoTransaction,
+    },
+    rpc,
+    transaction::Transaction,
+    Blockchain,
+};
+
+// Blocks asked for per request while fetching a fork. A fork is fetched at most
+// this far past the deepest block a reorg may revert; any rest of the peer's
+// chain then follows as ordinary blocks on the adopted tip.
+const FORK_PAGE_BLOCKS: u64 = 100;
+
+// Relays a newly accepted transaction to every peer in the background.
+// Receivers drop transactions they already know, which stops gossip loops.
+pub fn broadcast_transaction(peers: &HashSet<String>, transaction: ProtoTransaction) {
+    for peer in peers {
+        let peer = peer.clone();
+        let transaction = transaction.clone();
+
+        tokio::spawn(async move {
+            let mut client = match BlockchainServiceClient::connect(peer.clone()).await {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Failed to connect to peer {}: {}", peer, e);
+                    return;
+                }
+            };
+
+            match client.relay_transaction(transaction).await {
+                Ok(response) if response.get_ref().success => {
+                    info!("Relayed transaction to peer {}", peer)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to relay transaction to peer {}: {}", peer, e),
+            }
+        });
+    }
+}
+
+// Announces a block to every peer in the background as its header and transaction
+// hashes; peers fetch any transactions they lack with GetMissingTransactions
+pub fn broadcast_compact_block(peers: &HashSet<String>, block: CompactBlock) {
+    for peer in peers {
+        let peer = peer.clone();
+        let block = block.clone();
+
+        tokio::spawn(async move {
+            let mut client = match BlockchainServiceClient::connect(peer.clone()).await {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Failed to connect to peer {}: {}", peer, e);
+                    return;
+                }
+            };
+
+            match client.submit_compact_block(block).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if !response.success {
+                        warn!("Peer {} rejected block: {}", peer, response.message);
+                    } else if !response.already_known {
+                        info!("Relayed block to peer {}", peer);
+                    }
+                }
+                Err(e) => warn!("Failed to relay block to peer {}: {}", peer, e),
+            }
+        });
+    }
+}
+
+// Asks each peer in turn for the transactions of `block_hash` we lack, by hash,
+// combining their answers and only asking each for the ones still missing, until
+// all have been found. Unreachable peers and peers without the block are
+// skipped, so the result may still be incomplete.
+pub async fn fetch_missing_transactions(
+    peers: &[String],
+    block_hash: &str,
+    tx_hashes: &[String],
+) -> HashMap<String, ProtoTransaction> {
+    let mut found = HashMap::new();
+    for peer in peers {
+        let missing: Vec<String> = tx_hashes
+            .iter()
+            .filter(|hash| !found.contains_key(*hash))
+            .cloned()
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        let mut client = match BlockchainServiceClient::connect(peer.clone()).await {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to connect to peer {}: {}", peer, e);
+                continue;
+            }
+        };
+        let request = MissingTransactionsRequest {
+            block_hash: block_hash.to_string(),
+            tx_hashes: missing.clone(),
+        };
+        match client.get_missing_transactions(request).await {
+            Ok(response) => {
+                collect_transactions(&mut found, &missing, response.into_inner().transactions)
+            }
+            Err(e) => warn!("Failed to get transactions from peer {}: {}", peer, e),
+        }
+    }
+    found
+}
+
+// Adds the transactions of a peer's `reply` that are among `wanted` to `found`,
+// by hash, ignoring any it sent unasked
+fn collect_transactions(
+    found: &mut HashMap<String, ProtoTransaction>,
+    wanted: &[String],
+    reply: Vec<ProtoTransaction>,
+) {
+    for tx in reply {
+        let hash = Transaction::from(tx.clone()).hash();
+        if wanted.contains(&hash) {
+            found.insert(hash, tx);
+        }
+    }
+}
+
+// Asks the peer for its genesis hash and fails unless it matches ours, so nodes on
+// incompatible chains (e.g. different chain ids or allocations) never peer
+pub async fn check_genesis(peer: &str, genesis_hash: &str) -> Result<(), String> {
+    let mut client = BlockchainServiceClient::connect(peer.to_string())
+        .await
+        .map_err(|e| format!("Failed to connect to peer {}: {}", peer, e))?;
+    let info = client
+        .get_chain_info(ChainInfoRequest {})
+        .await
+        .map_err(|e| format!("Failed to get chain info from peer {}: {}", peer, e))?
+        .into_inner();
+
+    if info.genesis_hash != genesis_hash {
+        return Err(format!(
+            "Peer {} has genesis block {}, ours is {}",
+            peer, info.genesis_hash, genesis_hash
+        ));
+    }
+    Ok(())
+}
+
+// Catches up from each peer in turn while the node is marked as syncing. Peers
+// that can't be reached or are on another chain are skipped, and the flag is
+// cleared once every peer has been tried, so the node never stays stuck.
+pub async fn initial_sync(blockchain: Arc<Mutex<Blockchain>>, peers: Vec<String>) {
+    sync_with_peers(&blockchain, peers).await;
+
+    let mut chain = blockchain.lock().unwrap();
+    chain.syncing = false;
+    info!("Initial sync finished at height {}", chain.chain.len() - 1);
+}
+
+// Catches up from each peer in turn, adopting any heavier fork they're on
+pub async fn sync_with_peers(blockchain: &Mutex<Blockchain>, peers: Vec<String>) {
+    for peer in peers {
+        if let Err(e) = sync_from(blockchain, &peer).await {
+            warn!("Stopped syncing from peer {}: {}", peer, e);
+        }
+    }
+}
+
+// Appends the peer's blocks beyond our tip, a page at a time. If the peer's
+// chain has forked from ours, or it advertises more cumulative work on another
+// tip without being longer, its branch is fetched and offered to
+// `replace_chain`, which adopts it only if it's heavier, and syncing carries on
+// from the new tip.
+async fn sync_from(blockchain: &Mutex<Blockchain>, peer: &str) -> Result<(), String> {
+    let genesis_hash = blockchain.lock().unwrap().genesis_hash();
+    check_genesis(peer, &genesis_hash).await?;
+    let mut client = BlockchainServiceClient::connect(peer.to_string())
+        .await
+        .map_err(|e| format!("Failed to connect to peer {}: {}", peer, e))?;
+
+    loop {
+        let (start, tip_hash) = {
+            let chain = blockchain.lock().unwrap();
+            (chain.chain.len() as u64, chain.chain.tip().hash.clone())
+        };
+        let page = get_block_range(&mut client, peer, start, 0).await?;
+        let Some(first) = page.blocks.first() else {
+            // No longer than us, but a shorter branch can still carry more work
+            if peer_has_heavier_tip(blockchain, &mut client, peer).await? {
+                sync_fork(blockchain, &mut client, peer).await?;
+            }
+            return Ok(());
+        };
+        if first
+            .header
+            .as_ref()
+            .is_some_and(|header| header.previous_hash != tip_hash)
+        {
+            sync_fork(blockchain, &mut client, peer).await?;
+            continue;
+        }
+
+        for block in page.blocks {
+            let mut chain = blockchain.lock().unwrap();
+            let block = from_proto_block(block, chain.chain.tip())?;
+            chain.add_block(block)?;
+        }
+        if !page.has_more {
+            return Ok(());
+        }
+    }
+}
+
+// Whether the peer advertises a different tip with more cumulative work than
+// ours. The claim is only a hint: `replace_chain` recomputes the work itself.
+async fn peer_has_heavier_tip(
+    blockchain: &Mutex<Blockchain>,
+    client: &mut BlockchainServiceClient<Channel>,
+    peer: &str,
+) -> Result<bool, String> {
+    let info = client
+        .get_chain_info(ChainInfoRequest {})
+        .await
+        .map_err(|e| format!("Failed to get chain info from peer {}: {}", peer, e))?
+        .into_inner();
+    let work: u128 = info
+        .cumulative_work
+        .parse()
+        .map_err(|e| format!("Peer {} sent invalid cumulative work: {}", peer, e))?;
+
+    let tip = blockchain.lock().unwrap().watch_tip().borrow().clone();
+    Ok(work > tip.cumulative_work && info.latest_hash != tip.hash)
+}
+
+// Fetches the peer's chain from the deepest height a reorg may revert, a page
+// at a time and no more than FORK_PAGE_BLOCKS past our tip, and offers it to
+// `replace_chain`. The bound keeps a peer from making us hold and validate an
+// arbitrarily long branch.
+async fn sync_fork(
+    blockchain: &Mutex<Blockchain>,
+    client: &mut BlockchainServiceClient<Channel>,
+    peer: &str,
+) -> Result<(), String> {
+    let (base, max_reorg_depth) = {
+        let chain = blockchain.lock().unwrap();
+        let start = chain
+            .chain
+            .len()
+            .saturating_sub(chain.max_reorg_depth as usize)
+            .max(1);
+        let base = chain
+            .chain
+            .get(start as u64 - 1)
+            .expect("start is above the genesis");
+        (base.into_owned(), chain.max_reorg_depth)
+    };
+    info!(
+        "Peer {} has forked from our chain; fetching its blocks from height {}",
+        peer,
+        base.index + 1
+    );
+
+    let limit = max_reorg_depth + FORK_PAGE_BLOCKS;
+    let mut branch: Vec<Block> = Vec::new();
+    while (branch.len() as u64) < limit {
+        let start = base.index + 1 + branch.len() as u64;
+        let count = FORK_PAGE_BLOCKS.min(limit - branch.len() as u64) as u32;
+        let page = get_block_range(client, peer, start, count).await?;
+        for block in page.blocks.iter().take(count as usize).cloned() {
+            let parent = branch.last().unwrap_or(&base);
+            if block
+                .header
+                .as_ref()
+                .is_some_and(|header| header.previous_hash != parent.hash)
+            {
+                return Err(format!(
+                    "Peer {} forked from our chain more than {} blocks below our tip",
+                    peer, max_reorg_depth
+                ));
+            }
+            branch.push(from_proto_block(block, parent)?);
+        }
+        if page.blocks.is_empty() || !page.has_more {
+            break;
+        }
+    }
+    blockchain.lock().unwrap().replace_chain(branch)
+}
+
+async fn get_block_range(
+    client: &mut BlockchainServiceClient<Channel>,
+    peer: &str,
+    start: u64,
+    count: u32,
+) -> Result<BlockRangeResponse, String> {
+    client
+        .get_block_range(BlockRangeRequest { start, count })
+        .await
+        .map(Response::into_inner)
+        .map_err(|e| format!("Failed to get blocks from peer {}: {}", peer, e))
+}
+
+// Rebuilds a peer's block on `parent`, checking it hashes as the peer claims
+fn from_proto_block(block: ProtoBlock, parent: &Block) -> Result<Block, String> {
+    let header = block.header.ok_or("Block has no header")?;
+    let timestamp = DateTime::parse_from_rfc3339(&header.timestamp)
+        .map_err(|e| format!("Invalid timestamp: {}", e))?
+        .with_timezone(&Utc);
+    rpc::rebuild_block(header, timestamp, block.transactions, parent)
+}
+
+// The IP address in a peer's endpoint, when it names one rather than a host
+pub fn peer_ip(addr: &str) -> Option<IpAddr> {
+    let uri: Uri = addr.parse().ok()?;
+    let host = uri.host()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+// Peers are gRPC endpoints such as http://[::1]:50052
+pub fn validate_peer_addr(addr: &str) -> Result<(), String> {
+    let uri: Uri = addr
+        .parse()
+        .map_err(|e| format!("Invalid peer address {}: {}", addr, e))?;
+
+    match (uri.scheme_str(), uri.authority()) {
+        (Some("http" | "https"), Some(_)) => Ok(()),
+        _ => Err(format!(
+            "Invalid peer address {}: expected http(s)://host:port",
+            addr
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        consensus::{BlockProduction, Consensus},
+        testkit::{self, TestNode},
+    };
+
+    fn proto_transfer(amount: u64) -> (String, ProtoTransaction) {
+        let tx = Transaction::new(crate::FAUCET_MOCKCHAIN_ADDRESS, "recipient", amount);
+        (tx.hash(), ProtoTransaction::from(&tx))
+    }
+
+    #[test]
+    fn replies_from_several_peers_are_combined() {
+        let (first_hash, first) = proto_transfer(1);
+        let (second_hash, second) = proto_transfer(2);
+        let (_, unasked) = proto_transfer(3);
+        let wanted = vec![first_hash.clone(), second_hash.clone()];
+
+        let mut found = HashMap::new();
+        collect_transactions(&mut found, &wanted, vec![first]);
+        collect_transactions(&mut found, &wanted, vec![second, unasked]);
+        // A later peer that knows none of them doesn't wipe out earlier answers
+        collect_transactions(&mut found, &wanted, Vec::new());
+
+        assert_eq!(found.len(), 2);
+        assert!(found.contains_key(&first_hash) && found.contains_key(&second_hash));
+    }
+
+    #[tokio::test]
+    async fn long_forks_are_adopted_a_bounded_branch_at_a_time() {
+        let config = || Config {
+            consensus: "dev".to_string(),
+            genesis_time: DateTime::from_timestamp(1_700_000_000, 0),
+            block_production: BlockProduction::Manual,
+            max_reorg_depth: 2,
+            ..Config::default()
+        };
+        let ours = TestNode::spawn(config()).await;
+        let (peer, addr) = TestNode::spawn_listening(config()).await;
+        for amount in 1..=2 {
+            ours.funded_account(amount);
+        }
+        // More than a fork page beyond the deepest block we may revert
+        let peer_len = 2 + FORK_PAGE_BLOCKS + 20;
+        for amount in 1..peer_len {
+            peer.funded_account(amount);
+        }
+        let len = |node: &TestNode| node.blockchain.lock().unwrap().chain.len() as u64;
+
+        // Forked at height 1, so the branch stops a page past the two blocks we may revert
+        let mut client = BlockchainServiceClient::connect(addr.clone())
+            .await
+            .unwrap();
+        sync_fork(&ours.blockchain, &mut client, &addr)
+            .await
+            .unwrap();
+        assert_eq!(len(&ours), 1 + 2 + FORK_PAGE_BLOCKS);
+
+        // Syncing carries on from the adopted tip
+        sync_with_peers(&ours.blockchain, vec![addr]).await;
+        let tip = |node: &TestNode| node.blockchain.lock().unwrap().chain.tip().hash.clone();
+        assert_eq!(len(&ours), peer_len);
+        assert_eq!(tip(&ours), tip(&peer));
+    }
+
+    // Weighs blocks by how many transactions they carry, so a shorter chain
+    // can be the heavier one
+    struct ByTransactions;
+
+    impl Consensus for ByTransactions {
+        fn seal_block(&self, block: Block) -> Block {
+            block
+        }
+
+        fn validate_block(&self, block: &Block, previous_hash: &str) -> bool {
+            block.previous_hash == previous_hash && block.hash == block.calculate_hash()
+        }
+
+        fn block_weight(&self, block: &Block) -> u128 {
+            block.transactions.len() as u128
+        }
+
+        fn start(&self, _blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
+            tokio::spawn(async {})
+        }
+
+        fn name(&self) -> &str {
+            "By transactions"
+        }
+    }
+
+    #[tokio::test]
+    async fn shorter_peers_with_more_work_are_synced() {
+        let config = || Config {
+            consensus: "dev".to_string(),
+            genesis_time: DateTime::from_timestamp(1_700_000_000, 0),
+            block_production: BlockProduction::Manual,
+            ..Config::default()
+        };
+        let ours = TestNode::spawn(config()).await;
+        let (peer, addr) = TestNode::spawn_listening(config()).await;
+        for node in [&ours, &peer] {
+            node.blockchain
+                .lock()
+                .unwrap()
+                .set_consensus(Box::new(ByTransactions));
+        }
+
+        // Three light blocks against two packed ones
+        for amount in 1..=3 {
+            ours.funded_account(amount);
+        }
+        for _ in 0..2 {
+            for amount in 1..=5 {
+                testkit::grant(&mut peer.blockchain.lock().unwrap(), "recipient", amount);
+            }
+            peer.force_mine();
+        }
+        let tip = |node: &TestNode| node.blockchain.lock().unwrap().chain.tip().hash.clone();
+        let len = |node: &TestNode| node.blockchain.lock().unwrap().chain.len();
+        assert!(len(&peer) < len(&ours));
+
+        sync_with_peers(&ours.blockchain, vec![addr.clone()]).await;
+        assert_eq!(tip(&ours), tip(&peer));
+
+        // The lighter node has nothing to take from us
+        let (lighter, lighter_addr) = TestNode::spawn_listening(config()).await;
+        lighter
+            .blockchain
+            .lock()
+            .unwrap()
+            .set_consensus(Box::new(ByTransactions));
+        lighter.funded_account(1);
+        sync_with_peers(&ours.blockchain, vec![lighter_addr]).await;
+        assert_eq!(tip(&ours), tip(&peer));
+    }
+}
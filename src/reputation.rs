@@ -0,0 +1,187 @@
+// Per-peer reputation, keyed by the IP address inbound peer requests come from.
+// Invalid blocks and transactions cost a peer points and accepted ones earn
+// them back, up to a neutral zero. A peer whose score falls to the ban
+// threshold is refused for the ban duration, then starts over.
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+
+use crate::clock::Clock;
+
+// Points below zero at which a peer is banned, unless configured otherwise
+pub const DEFAULT_BAN_THRESHOLD: u32 = 100;
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Something a peer sent us, good or bad
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    // A block that failed validation
+    InvalidBlock,
+    // A transaction whose signature doesn't verify
+    InvalidTransaction,
+    // A transaction that was already mined, sent again
+    ReplayedTransaction,
+    // A block or transaction we accepted
+    Accepted,
+}
+
+impl PeerEvent {
+    fn score_change(self) -> i64 {
+        match self {
+            Self::InvalidBlock => -20,
+            Self::InvalidTransaction => -10,
+            Self::ReplayedTransaction => -2,
+            Self::Accepted => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PeerStanding {
+    pub score: i64,
+    pub banned_until: Option<DateTime<Utc>>,
+}
+
+pub struct PeerReputation {
+    ban_threshold: u32,
+    ban_duration: Duration,
+    // Only peers with a negative score or a ban; the rest are neutral
+    peers: Mutex<HashMap<IpAddr, PeerStanding>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PeerReputation {
+    pub fn new(ban_threshold: u32, ban_duration: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            ban_threshold,
+            ban_duration,
+            peers: Mutex::new(HashMap::new()),
+            clock,
+        }
+    }
+
+    // Scores `event` against the peer at `ip`, returning true if it got the peer
+    // banned. Events from a peer that's already banned are ignored.
+    pub fn record(&self, ip: IpAddr, event: PeerEvent) -> bool {
+        let now = self.clock.now();
+        let mut peers = self.peers.lock().unwrap();
+        let standing = peers.entry(ip).or_default();
+        if standing.banned_until.is_some_and(|until| until <= now) {
+            *standing = PeerStanding::default();
+        }
+        if standing.banned_until.is_some() {
+            return false;
+        }
+
+        standing.score = (standing.score + event.score_change()).min(0);
+        if standing.score > -i64::from(self.ban_threshold) {
+            if standing.score == 0 {
+                peers.remove(&ip);
+            }
+            return false;
+        }
+        let until = chrono::Duration::from_std(self.ban_duration)
+            .ok()
+            .and_then(|duration| now.checked_add_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        standing.banned_until = Some(until);
+        warn!(
+            "Banned peer {} until {} (reputation {})",
+            ip, until, standing.score
+        );
+        true
+    }
+
+    // When the ban on `ip` ends, if it's banned
+    pub fn banned_until(&self, ip: IpAddr) -> Option<DateTime<Utc>> {
+        let now = self.clock.now();
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .and_then(|standing| standing.banned_until)
+            .filter(|until| *until > now)
+    }
+
+    // Every peer with a negative score or a current ban, sorted by address
+    pub fn standings(&self) -> Vec<(IpAddr, PeerStanding)> {
+        let now = self.clock.now();
+        let mut standings: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, standing)| standing.banned_until.is_none_or(|until| until > now))
+            .map(|(ip, standing)| (*ip, standing.clone()))
+            .collect();
+        standings.sort_by_key(|(ip, _)| *ip);
+        standings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::testkit::MockClock;
+
+    #[test]
+    fn peers_sending_invalid_blocks_are_banned_until_the_cooldown_ends() {
+        let clock = MockClock::new(Utc::now());
+        let reputation = PeerReputation::new(
+            DEFAULT_BAN_THRESHOLD,
+            Duration::from_secs(60),
+            Arc::new(clock.clone()),
+        );
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let bystander = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        // Four invalid blocks cost 80 points, one accepted block earns one back
+        for _ in 0..4 {
+            assert!(!reputation.record(peer, PeerEvent::InvalidBlock));
+        }
+        assert!(!reputation.record(peer, PeerEvent::Accepted));
+        assert!(!reputation.record(peer, PeerEvent::InvalidBlock));
+        assert_eq!(reputation.banned_until(peer), None);
+        assert!(reputation.record(peer, PeerEvent::InvalidBlock));
+        assert!(reputation.banned_until(peer).is_some());
+        assert_eq!(reputation.banned_until(bystander), None);
+
+        // Banned peers aren't scored again
+        assert!(!reputation.record(peer, PeerEvent::InvalidBlock));
+        let standings = reputation.standings();
+        assert_eq!(standings.len(), 1);
+        assert_eq!(standings[0].0, peer);
+        assert_eq!(standings[0].1.score, -119);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(reputation.banned_until(peer), None);
+        assert!(reputation.standings().is_empty());
+        assert!(!reputation.record(peer, PeerEvent::InvalidBlock));
+        assert_eq!(reputation.standings()[0].1.score, -20);
+    }
+
+    #[test]
+    fn good_behaviour_earns_points_back_up_to_neutral() {
+        let reputation = PeerReputation::new(
+            DEFAULT_BAN_THRESHOLD,
+            DEFAULT_BAN_DURATION,
+            Arc::new(MockClock::new(Utc::now())),
+        );
+        let peer = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        reputation.record(peer, PeerEvent::ReplayedTransaction);
+        assert_eq!(reputation.standings()[0].1.score, -2);
+        reputation.record(peer, PeerEvent::Accepted);
+        reputation.record(peer, PeerEvent::Accepted);
+        reputation.record(peer, PeerEvent::Accepted);
+        assert!(reputation.standings().is_empty());
+    }
+}
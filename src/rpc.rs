@@ -1,60 +1,627 @@
 use std::{
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use log::info;
-use secp256k1::Secp256k1;
-use tonic::{Request, Response, Status};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{info, warn};
+use secp256k1::PublicKey;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tonic::{service::Interceptor, Request, Response, Status};
 
 use crate::{
+    block::{self, Block, UnsealedBlock},
     blockchain::{
-        blockchain_service_server::BlockchainService, BalanceRequest, BalanceResponse,
-        FaucetRequest, FaucetResponse, Transaction as ProtoTransaction, TransactionResponse,
+        blockchain_service_server::BlockchainService, AccountBalance, AddPeerRequest, AddressEvent,
+        AddressStatsRequest, AddressStatsResponse, BalanceAtHeightRequest, BalanceRequest,
+        BalanceResponse, Block as ProtoBlock, BlockDifficulty, BlockHeader, BlockRangeRequest,
+        BlockRangeResponse, BlockRequest, BlockResponse, BlockStats, BlockTemplateRequest,
+        BlockTemplateResponse, ChainEvent as ProtoChainEvent, ChainInfoRequest, ChainInfoResponse,
+        CompactBlock, ConfirmationTimeRequest, ConfirmationTimeResponse, DifficultyHistoryRequest,
+        DifficultyHistoryResponse, EstimateFeeRequest, EstimateFeeResponse, FaucetRequest,
+        FaucetResponse, FinalityRequest, FinalityResponse, ForceMineRequest, ForceMineResponse,
+        HeadersRequest, IsValidatorRequest, IsValidatorResponse, ListPeersRequest,
+        ListPeersResponse, MissingTransactionsRequest, MissingTransactionsResponse,
+        Multisig as ProtoMultisig, NewBlockEvent, PeerReputation as ProtoPeerReputation,
+        PeerResponse, PendingBySenderRequest, PendingBySenderResponse, ReceiptRequest,
+        ReceiptResponse, RegisterStakeRequest, RegisterStakeResponse, RemovePeerRequest,
+        RewardRequest, RewardResponse, RollbackEvent, SealedBlockRequest, StateRequest,
+        StateResponse, SubmitBlockResponse, SubscribeAddressRequest, SubscribeBlocksRequest,
+        SupplyRequest, SupplyResponse, SwitchConsensusRequest, SwitchConsensusResponse, TipRequest,
+        TipResponse, Transaction as ProtoTransaction, TransactionOutput, TransactionProofRequest,
+        TransactionProofResponse, TransactionResponse, UndoBlockRequest, UndoBlockResponse,
+        WaitForConfirmationRequest, WaitForConfirmationResponse,
     },
-    transaction::Transaction,
-    Blockchain, FAUCET_MOCKCHAIN_ADDRESS,
+    clock::{self, Clock},
+    config::Config,
+    consensus::{self, target_for_bits, BlockProduction, ConsensusParams, ConsensusRegistry},
+    entropy, merkle, p2p,
+    reputation::{PeerEvent, PeerReputation},
+    transaction::{Multisig, Transaction},
+    Blockchain, ChainEvent, TipInfo, FAUCET_MOCKCHAIN_ADDRESS, MAX_BLOCK_TRANSACTIONS,
 };
 
 pub struct BlockchainServer {
     pub blockchain: Arc<Mutex<Blockchain>>,
+    // Tip snapshot kept current by the blockchain, read without its lock
+    tip: watch::Receiver<TipInfo>,
+    // Gossip targets, editable at runtime with AddPeer and RemovePeer
+    peers: Arc<Mutex<HashSet<String>>>,
+    // Scores peers by what they submit; banned ones are dropped and refused
+    reputation: PeerReputation,
+    dev_mode: bool,
+    rpc_timeout: Duration,
+    max_block_range: u32,
+    // Blocks on top of a transaction's block before GetFinality reports it final
+    finality_depth: u64,
+    // Factories for SwitchConsensus
+    consensus_registry: ConsensusRegistry,
+    // Configured parameters for consensuses created by SwitchConsensus; the
+    // request supplies difficulty and minimum stake
+    consensus_params: ConsensusParams,
+    // Admin RPCs need the token when one is configured
+    admin_token_required: bool,
+    // Most the faucet may ever grant, counted from the chain and pool
+    faucet_total_cap: Option<u64>,
+    // Responses to recent faucet requests by request id, so retries don't grant twice
+    faucet_requests: Mutex<HashMap<String, (DateTime<Utc>, FaucetResponse)>>,
+    // The blockchain's clock, for the faucet request TTL
+    clock: Arc<dyn Clock>,
+    // Block templates handed to external miners, oldest first
+    templates: Mutex<VecDeque<UnsealedBlock>>,
+    // Set while a sync started by a peer block we couldn't connect runs, so
+    // further gossip doesn't start another
+    fork_sync: Arc<AtomicBool>,
+}
+
+// How long a faucet request id is remembered
+const FAUCET_REQUEST_TTL: Duration = Duration::from_secs(10 * 60);
+const ADMIN_TOKEN_REQUIRED: &str = "This RPC requires the admin token";
+const NODE_SYNCING: &str = "Node syncing";
+// Most block templates remembered at once; miners asking for fresh ones evict the oldest
+const MAX_BLOCK_TEMPLATES: usize = 16;
+
+// Marks requests that carried the admin token; tonic interceptors can't see which
+// RPC is being called, so the admin handlers check for this themselves
+#[derive(Clone)]
+struct AdminAuthorized;
+
+// Checks the `authorization: Bearer <token>` metadata against --admin-token
+#[derive(Clone)]
+pub struct AdminAuth {
+    token: Option<String>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<String>) -> Self {
+        Self { token }
+    }
+}
+
+impl Interceptor for AdminAuth {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token) = &self.token else {
+            return Ok(request);
+        };
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if presented == Some(token.as_str()) {
+            request.extensions_mut().insert(AdminAuthorized);
+        }
+        Ok(request)
+    }
+}
+
+// Per-client-IP token buckets shared by every RPC, so no single client can
+// monopolise the blockchain lock. Requests carrying the admin token are exempt,
+// and connections without a peer address (in-process ones) aren't limited.
+#[derive(Clone)]
+pub struct RateLimit {
+    requests_per_sec: Option<u32>,
+    buckets: Arc<Mutex<HashMap<IpAddr, TokenBucket>>>,
+    clock: Arc<dyn Clock>,
+}
+
+// Bucket count past which buckets that have refilled, and so are no different
+// from new ones, are dropped
+const RATE_LIMIT_PRUNE_AT: usize = 10_000;
+
+struct TokenBucket {
+    tokens: f64,
+    refilled: DateTime<Utc>,
+}
+
+impl RateLimit {
+    pub fn new(requests_per_sec: Option<u32>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            requests_per_sec,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            clock,
+        }
+    }
+}
+
+impl Interceptor for RateLimit {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let (Some(limit), Some(addr)) = (self.requests_per_sec, request.remote_addr()) else {
+            return Ok(request);
+        };
+        if request.extensions().get::<AdminAuthorized>().is_some() {
+            return Ok(request);
+        }
+
+        // A full bucket allows a one-second burst
+        let capacity = f64::from(limit);
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_PRUNE_AT {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + clock::elapsed(bucket.refilled, now).as_secs_f64() * capacity
+                    < capacity
+            });
+        }
+        let bucket = buckets.entry(addr.ip()).or_insert(TokenBucket {
+            tokens: capacity,
+            refilled: now,
+        });
+        let elapsed = clock::elapsed(bucket.refilled, now).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.refilled = now;
+
+        if bucket.tokens < 1.0 {
+            return Err(Status::resource_exhausted(format!(
+                "Rate limit of {} requests per second exceeded",
+                limit
+            )));
+        }
+        bucket.tokens -= 1.0;
+        Ok(request)
+    }
 }
 
 impl BlockchainServer {
-    pub fn new(blockchain: Blockchain) -> Self {
+    pub fn new(
+        blockchain: Blockchain,
+        consensus_registry: ConsensusRegistry,
+        config: &Config,
+    ) -> Self {
+        let clock = Arc::clone(&blockchain.clock);
         Self {
+            tip: blockchain.watch_tip(),
             blockchain: Arc::new(Mutex::new(blockchain)),
+            peers: Arc::new(Mutex::new(config.peers.iter().cloned().collect())),
+            reputation: PeerReputation::new(
+                config.ban_threshold,
+                config.ban_duration,
+                Arc::clone(&clock),
+            ),
+            dev_mode: config.dev_mode,
+            rpc_timeout: config.rpc_timeout,
+            max_block_range: config.max_block_range,
+            finality_depth: config.finality_depth,
+            consensus_registry,
+            consensus_params: config.consensus_params(),
+            admin_token_required: config.admin_token.is_some(),
+            faucet_total_cap: config.faucet_total_cap,
+            faucet_requests: Mutex::new(HashMap::new()),
+            templates: Mutex::new(VecDeque::new()),
+            fork_sync: Arc::new(AtomicBool::new(false)),
+            clock,
         }
     }
-}
 
-#[tonic::async_trait]
-impl BlockchainService for BlockchainServer {
-    async fn submit_transaction(
+    // Syncs from our peers in the background, adopting the branch of a heavier
+    // fork if one of them is on it. Started when a peer sends a block that
+    // doesn't connect to our tip; only one such sync runs at a time.
+    fn sync_from_peers(&self) {
+        let peers: Vec<String> = self.peers.lock().unwrap().iter().cloned().collect();
+        if peers.is_empty() || self.fork_sync.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let blockchain = Arc::clone(&self.blockchain);
+        let fork_sync = Arc::clone(&self.fork_sync);
+        tokio::spawn(async move {
+            p2p::sync_with_peers(&blockchain, peers).await;
+            fork_sync.store(false, Ordering::SeqCst);
+        });
+    }
+
+    // Announces every block added to our chain to the peers as a compact block.
+    // Peers relay blocks they accept in turn, and acknowledge ones they already
+    // have without relaying them, which stops gossip loops.
+    pub fn spawn_block_gossip(&self) -> JoinHandle<()> {
+        let mut events = self.blockchain.lock().unwrap().subscribe_chain_events();
+        let peers = Arc::clone(&self.peers);
+
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(ChainEvent::Block(block)) => p2p::broadcast_compact_block(
+                        &peers.lock().unwrap(),
+                        CompactBlock::from(&*block),
+                    ),
+                    Ok(ChainEvent::Rollback { .. }) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Block gossip fell behind, skipped {} blocks", skipped)
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        })
+    }
+
+    fn is_admin<T>(&self, request: &Request<T>) -> bool {
+        !self.admin_token_required || request.extensions().get::<AdminAuthorized>().is_some()
+    }
+
+    // Refusal for a request from a banned peer
+    fn ban_status(&self, peer: Option<IpAddr>) -> Option<Status> {
+        let ip = peer?;
+        let until = self.reputation.banned_until(ip)?;
+        Some(Status::permission_denied(format!(
+            "Peer {} is banned until {}",
+            ip,
+            until.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        )))
+    }
+
+    // Scores what a peer sent; a peer that gets banned is also dropped from
+    // the gossip targets. In-process requests have no address and aren't scored.
+    fn record_peer(&self, peer: Option<IpAddr>, event: PeerEvent) {
+        let Some(ip) = peer else {
+            return;
+        };
+        if self.reputation.record(ip, event) {
+            self.peers
+                .lock()
+                .unwrap()
+                .retain(|addr| p2p::peer_ip(addr) != Some(ip));
+        }
+    }
+
+    // Adds a transaction to the pool and gossips it to peers if it was new and valid.
+    // Faucet transactions go unverified, so clients and peers may not send them;
+    // grants only come from RequestFaucet, under its cap and rate limit.
+    fn accept_transaction(&self, tx: ProtoTransaction) -> Result<(), String> {
+        if tx.from == FAUCET_MOCKCHAIN_ADDRESS {
+            return Err("Faucet transactions can only be requested through the faucet".to_string());
+        }
+        self.blockchain
+            .lock()
+            .unwrap()
+            .add_transaction(Transaction::from(tx.clone()))?;
+
+        p2p::broadcast_transaction(&self.peers.lock().unwrap(), tx);
+        Ok(())
+    }
+
+    // Appends a peer's block if it extends our tip; blocks we already have are
+    // acknowledged without touching the chain. A different block at a height we
+    // have, one ahead of our tip, or one that builds on another branch may come
+    // from a heavier fork, so it is refused and starts a sync from our peers.
+    fn accept_block(
         &self,
-        request: Request<ProtoTransaction>,
-    ) -> Result<Response<TransactionResponse>, Status> {
-        let tx = request.into_inner();
+        peer: Option<IpAddr>,
+        header: BlockHeader,
+        timestamp: DateTime<Utc>,
+        transactions: Vec<ProtoTransaction>,
+    ) -> SubmitBlockResponse {
+        let mut chain = self.blockchain.lock().unwrap();
+        let tip = chain.chain.tip();
+
+        // Gossip delivers the same block many times; answering these quietly
+        // keeps duplicates from being relayed again
+        if header.index <= tip.index
+            && chain.chain.get(header.index).expect("below the tip").hash == header.hash
+        {
+            return SubmitBlockResponse {
+                success: true,
+                message: format!("Already have block {}", header.index),
+                already_known: true,
+            };
+        }
+
+        let rejection = if header.index <= tip.index {
+            Some(format!(
+                "Already have a different block at height {}",
+                header.index
+            ))
+        } else if header.index != tip.index + 1 {
+            Some(format!(
+                "Block {} is ahead of our tip {}",
+                header.index, tip.index
+            ))
+        } else if header.previous_hash != tip.hash {
+            Some(format!("Block {} does not extend our tip", header.index))
+        } else {
+            None
+        };
+        if let Some(message) = rejection {
+            drop(chain);
+            self.sync_from_peers();
+            return SubmitBlockResponse {
+                success: false,
+                message,
+                already_known: false,
+            };
+        }
+
+        let index = header.index;
+        // A block from too far in the future may just be early, and can be resent
+        let mut early = false;
+        let result = rebuild_block(header, timestamp, transactions, tip).and_then(|block| {
+            early = chain.check_timestamp(&block).is_err();
+            chain.add_block(block)
+        });
+        drop(chain);
+        match (&result, early) {
+            (Ok(()), _) => self.record_peer(peer, PeerEvent::Accepted),
+            (Err(_), false) => self.record_peer(peer, PeerEvent::InvalidBlock),
+            (Err(_), true) => {}
+        }
+        match result {
+            Ok(()) => SubmitBlockResponse {
+                success: true,
+                message: format!("Added block {}", index),
+                already_known: false,
+            },
+            Err(reason) => SubmitBlockResponse {
+                success: false,
+                message: reason,
+                already_known: false,
+            },
+        }
+    }
+
+    // Sends faucet funds to `address` and mines them straight away
+    fn grant_faucet(&self, address: String) -> FaucetResponse {
+        // Create a faucet transaction
+        let faucet_amount = 1000; // Amount for testing
 
+        // Create a system transaction to fund the account
         let transaction = Transaction {
+            from: FAUCET_MOCKCHAIN_ADDRESS.to_string(), // Special faucet address
+            to: address,
+            amount: faucet_amount,
+            fee: 0,
+            timestamp: self.clock.unix_now(),
+            signature: vec![], // No signature needed for faucet
+            outputs: Vec::new(),
+            valid_until: 0,
+            not_before: 0,
+            gas_limit: 0,
+            multisig: None,
+            signatures: Vec::new(),
+        };
+
+        let mut chain = self.blockchain.lock().unwrap();
+        if let Some(cap) = self.faucet_total_cap {
+            if chain.faucet_emitted() + faucet_amount > cap {
+                warn!(
+                    "Faucet exhausted: granting {} would exceed the cap of {}",
+                    faucet_amount, cap
+                );
+                return FaucetResponse {
+                    success: false,
+                    amount: 0,
+                    message: "Faucet exhausted".to_string(),
+                };
+            }
+        }
+        let success = chain.add_transaction(transaction).is_ok();
+
+        // Immediately try to mine a block with this transaction; without a key
+        // it waits for the next block instead
+        let mined = match entropy::generate_keypair(&mut chain.rng) {
+            Ok((_, faucet_key)) => chain.mine_pending_transactions(&faucet_key),
+            Err(e) => {
+                warn!("Failed to generate a faucet miner key: {}", e);
+                None
+            }
+        };
+        if let Some(block) = mined {
+            info!("Created faucet block with hash {}", block.hash);
+
+            let message = match chain.faucet_confirmations {
+                0 => "Faucet funds sent successfully".to_string(),
+                n => format!("Faucet funds sent, spendable after {} confirmations", n),
+            };
+            FaucetResponse {
+                success: true,
+                amount: faucet_amount,
+                message,
+            }
+        } else {
+            FaucetResponse {
+                success,
+                amount: if success { faucet_amount } else { 0 },
+                message: if success {
+                    "Faucet funds queued for next block".to_string()
+                } else {
+                    "Failed to process faucet request".to_string()
+                },
+            }
+        }
+    }
+}
+
+impl From<ProtoTransaction> for Transaction {
+    fn from(tx: ProtoTransaction) -> Self {
+        Self {
             from: tx.from,
             to: tx.to,
             amount: tx.amount,
+            fee: tx.fee,
             timestamp: tx.timestamp,
             signature: tx.signature,
-        };
+            outputs: tx
+                .outputs
+                .into_iter()
+                .map(|output| (output.to, output.amount))
+                .collect(),
+            valid_until: tx.valid_until,
+            not_before: tx.not_before,
+            gas_limit: tx.gas_limit,
+            multisig: tx
+                .multisig
+                .map(|multisig| Multisig::new(multisig.threshold, multisig.public_keys)),
+            signatures: tx.signatures,
+        }
+    }
+}
 
-        let mut chain = self.blockchain.lock().unwrap();
-        let success = chain.add_transaction(transaction);
+impl From<&Transaction> for ProtoTransaction {
+    fn from(tx: &Transaction) -> Self {
+        Self {
+            from: tx.from.clone(),
+            to: tx.to.clone(),
+            amount: tx.amount,
+            fee: tx.fee,
+            timestamp: tx.timestamp,
+            signature: tx.signature.clone(),
+            outputs: tx
+                .outputs
+                .iter()
+                .map(|(to, amount)| TransactionOutput {
+                    to: to.clone(),
+                    amount: *amount,
+                })
+                .collect(),
+            valid_until: tx.valid_until,
+            not_before: tx.not_before,
+            gas_limit: tx.gas_limit,
+            multisig: tx.multisig.as_ref().map(|multisig| ProtoMultisig {
+                threshold: multisig.threshold,
+                public_keys: multisig.public_keys.clone(),
+            }),
+            signatures: tx.signatures.clone(),
+        }
+    }
+}
 
-        Ok(Response::new(TransactionResponse {
-            success,
-            message: if success {
-                "Transaction accepted".into()
-            } else {
-                "Transaction failed".into()
+// Rebuilds a peer's block on top of `parent` from its header fields, so the hash
+// is recomputed rather than trusted
+pub fn rebuild_block(
+    header: BlockHeader,
+    timestamp: DateTime<Utc>,
+    transactions: Vec<ProtoTransaction>,
+    parent: &Block,
+) -> Result<Block, String> {
+    let transactions = transactions.into_iter().map(Transaction::from).collect();
+    let mut block = Block::new_with_timestamp(transactions, Some(parent), timestamp);
+    block.nonce = header.nonce;
+    block.miner = header.miner;
+    block.state_root = header.state_root;
+    block.treasury_reward =
+        (!header.treasury.is_empty()).then_some((header.treasury, header.treasury_reward));
+    block.base_fee = header.base_fee;
+    block.data = header.data;
+    block.subsidy = header.subsidy;
+    block.consensus_data = header.consensus_data;
+    block.hash = block.calculate_hash();
+    block.producer_signature = header.producer_signature;
+
+    if block.hash != header.hash {
+        return Err(format!(
+            "Block {} hash does not match its contents",
+            header.index
+        ));
+    }
+    Ok(block)
+}
+
+impl From<&Block> for ProtoBlock {
+    fn from(block: &Block) -> Self {
+        Self {
+            header: Some(BlockHeader::from(block)),
+            transactions: block
+                .transactions
+                .iter()
+                .map(ProtoTransaction::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<&Block> for CompactBlock {
+    fn from(block: &Block) -> Self {
+        Self {
+            header: Some(BlockHeader::from(block)),
+            tx_hashes: block.transactions.iter().map(Transaction::hash).collect(),
+        }
+    }
+}
+
+impl From<&Block> for BlockHeader {
+    fn from(block: &Block) -> Self {
+        Self {
+            index: block.index,
+            timestamp: block.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            previous_hash: block.previous_hash.clone(),
+            hash: block.hash.clone(),
+            merkle_root: block.merkle_root.clone(),
+            miner: block.miner.clone(),
+            nonce: block.nonce,
+            state_root: block.state_root.clone(),
+            treasury: block
+                .treasury_reward
+                .as_ref()
+                .map_or_else(String::new, |(treasury, _)| treasury.clone()),
+            treasury_reward: block
+                .treasury_reward
+                .as_ref()
+                .map_or(0, |(_, share)| *share),
+            base_fee: block.base_fee,
+            data: block.data.clone(),
+            subsidy: block.subsidy,
+            consensus_data: block.consensus_data.clone(),
+            producer_signature: block.producer_signature.clone(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl BlockchainService for BlockchainServer {
+    type StreamHeadersStream = Pin<Box<dyn Stream<Item = Result<BlockHeader, Status>> + Send>>;
+    type SubscribeAddressStream = Pin<Box<dyn Stream<Item = Result<AddressEvent, Status>> + Send>>;
+    type SubscribeBlocksStream =
+        Pin<Box<dyn Stream<Item = Result<ProtoChainEvent, Status>> + Send>>;
+
+    async fn submit_transaction(
+        &self,
+        request: Request<ProtoTransaction>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        // Balance checks against a partially synced chain would be wrong
+        if self.blockchain.lock().unwrap().syncing {
+            return Err(Status::unavailable(NODE_SYNCING));
+        }
+        let response = match self.accept_transaction(request.into_inner()) {
+            Ok(()) => TransactionResponse {
+                success: true,
+                message: "Transaction accepted".into(),
             },
-        }))
+            Err(reason) => TransactionResponse {
+                success: false,
+                message: reason,
+            },
+        };
+
+        Ok(Response::new(response))
     }
 
     async fn get_balance(
@@ -68,53 +635,2474 @@ impl BlockchainService for BlockchainServer {
         Ok(Response::new(BalanceResponse { balance }))
     }
 
+    async fn get_balance_at_height(
+        &self,
+        request: Request<BalanceAtHeightRequest>,
+    ) -> Result<Response<BalanceResponse>, Status> {
+        let req = request.into_inner();
+        let chain = self.blockchain.lock().unwrap();
+        let balance = chain
+            .get_balance_at(&req.address, req.height)
+            .ok_or_else(|| {
+                Status::out_of_range(format!(
+                    "Height {} is beyond the chain tip {}",
+                    req.height,
+                    chain.chain.len() - 1
+                ))
+            })?;
+
+        Ok(Response::new(BalanceResponse { balance }))
+    }
+
+    async fn get_address_stats(
+        &self,
+        request: Request<AddressStatsRequest>,
+    ) -> Result<Response<AddressStatsResponse>, Status> {
+        let address = request.into_inner().address;
+        let mut stats = AddressStatsResponse::default();
+
+        // Only the blocks the balance index says touched the address are read
+        let chain = self.blockchain.lock().unwrap();
+        for height in chain.address_heights(&address) {
+            let block = chain
+                .chain
+                .get(height)
+                .expect("the balance index is in step with the chain");
+            for tx in &block.transactions {
+                let sent = tx.from == address;
+                if sent {
+                    stats.transactions_sent += 1;
+                    stats.total_sent += tx.total_amount();
+                }
+                let received: u64 = tx
+                    .payments()
+                    .iter()
+                    .filter(|(to, _)| *to == address)
+                    .map(|(_, amount)| amount)
+                    .sum();
+                if received > 0 {
+                    stats.transactions_received += 1;
+                    stats.total_received += received;
+                }
+
+                if sent || received > 0 {
+                    if !stats.active {
+                        stats.active = true;
+                        stats.first_active_height = block.index;
+                    }
+                    stats.last_active_height = block.index;
+                }
+            }
+        }
+
+        Ok(Response::new(stats))
+    }
+
     async fn request_faucet(
         &self,
         request: Request<FaucetRequest>,
     ) -> Result<Response<FaucetResponse>, Status> {
+        let req = request.into_inner();
+        info!("Faucet request for address: {}", req.address);
+
+        if req.request_id.is_empty() {
+            return Ok(Response::new(self.grant_faucet(req.address)));
+        }
+
+        // Held for the whole grant so concurrent retries can't both get through
+        let mut served = self.faucet_requests.lock().unwrap();
+        served.retain(|_, (served_at, _)| self.clock.since(*served_at) < FAUCET_REQUEST_TTL);
+        if let Some((_, response)) = served.get(&req.request_id) {
+            info!("Replaying faucet response for request {}", req.request_id);
+            return Ok(Response::new(response.clone()));
+        }
+
+        let response = self.grant_faucet(req.address);
+        served.insert(req.request_id, (self.clock.now(), response.clone()));
+        Ok(Response::new(response))
+    }
+
+    async fn get_chain_info(
+        &self,
+        _request: Request<ChainInfoRequest>,
+    ) -> Result<Response<ChainInfoResponse>, Status> {
+        // The lock is only needed for what isn't part of the tip snapshot
+        let tip = self.tip.borrow().clone();
+        let (consensus, pending_transactions, chain_id) = {
+            let chain = self.blockchain.lock().unwrap();
+            (
+                chain.consensus.name().to_string(),
+                chain.transaction_pool.len() as u64,
+                chain.chain_id,
+            )
+        };
+
+        Ok(Response::new(ChainInfoResponse {
+            height: tip.height,
+            latest_hash: tip.hash,
+            consensus,
+            pending_transactions,
+            seconds_since_last_block: self.clock.since(tip.timestamp).as_secs(),
+            chain_id,
+            cumulative_work: tip.cumulative_work.to_string(),
+            genesis_hash: tip.genesis_hash,
+        }))
+    }
+
+    async fn get_tip(
+        &self,
+        _request: Request<TipRequest>,
+    ) -> Result<Response<TipResponse>, Status> {
+        let tip = self.tip.borrow().clone();
+        Ok(Response::new(TipResponse {
+            height: tip.height,
+            hash: tip.hash,
+            timestamp: tip.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            cumulative_work: tip.cumulative_work.to_string(),
+        }))
+    }
+
+    async fn get_supply(
+        &self,
+        _request: Request<SupplyRequest>,
+    ) -> Result<Response<SupplyResponse>, Status> {
+        let chain = self.blockchain.lock().unwrap();
+
+        Ok(Response::new(SupplyResponse {
+            total_supply: chain.total_supply(),
+            total_burned: chain.total_burned(),
+        }))
+    }
+
+    async fn get_reward(
+        &self,
+        request: Request<RewardRequest>,
+    ) -> Result<Response<RewardResponse>, Status> {
+        let height = request.into_inner().height;
+
+        Ok(Response::new(RewardResponse {
+            reward: block::reward_for_index(height),
+            halvings: block::halvings_at(height),
+            next_halving_height: block::next_halving_after(height),
+        }))
+    }
+
+    async fn get_pending_by_sender(
+        &self,
+        request: Request<PendingBySenderRequest>,
+    ) -> Result<Response<PendingBySenderResponse>, Status> {
         let address = request.into_inner().address;
-        info!("Faucet request for address: {}", address);
+        let chain = self.blockchain.lock().unwrap();
 
-        // Create a faucet transaction
-        let faucet_amount = 1000; // Amount for testing
+        // Transactions carry no nonce, so the signed timestamp orders them; the
+        // sort is stable, so equal timestamps keep their pool order
+        let mut pending: Vec<&Transaction> = chain
+            .transaction_pool
+            .iter()
+            .filter(|tx| tx.from == address)
+            .collect();
+        pending.sort_by_key(|tx| tx.timestamp);
 
-        // Create a system transaction to fund the account
-        let transaction = Transaction {
-            from: FAUCET_MOCKCHAIN_ADDRESS.to_string(), // Special faucet address
-            to: address.clone(),
-            amount: faucet_amount,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs(),
-            signature: vec![], // No signature needed for faucet
+        Ok(Response::new(PendingBySenderResponse {
+            transactions: pending.into_iter().map(ProtoTransaction::from).collect(),
+        }))
+    }
+
+    async fn stream_headers(
+        &self,
+        request: Request<HeadersRequest>,
+    ) -> Result<Response<Self::StreamHeadersStream>, Status> {
+        let start_index = request.into_inner().start_index;
+
+        // Snapshot the headers so the lock isn't held while the client reads
+        let headers: Vec<BlockHeader> = {
+            let chain = self.blockchain.lock().unwrap();
+            chain
+                .chain
+                .iter_from(start_index)
+                .map(|block| BlockHeader::from(&*block))
+                .collect()
         };
 
-        let mut chain = self.blockchain.lock().unwrap();
-        let success = chain.add_transaction(transaction);
+        // The server-level timeout only covers the initial response, so bound the
+        // stream itself: a slow reader gets DEADLINE_EXCEEDED instead of holding it open
+        let (tx, rx) = mpsc::channel(16);
+        let deadline = self.rpc_timeout;
+        tokio::spawn(async move {
+            let send_all = async {
+                for header in headers {
+                    if tx.send(Ok(header)).await.is_err() {
+                        return;
+                    }
+                }
+            };
+            if tokio::time::timeout(deadline, send_all).await.is_err() {
+                let _ = tx
+                    .send(Err(Status::deadline_exceeded("Header stream timed out")))
+                    .await;
+            }
+        });
 
-        // Immediately try to mine a block with this transaction
-        let secp = Secp256k1::new();
-        let (_, faucet_key) = secp.generate_keypair(&mut rand::thread_rng());
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
 
-        if let Some(block) = chain.mine_pending_transactions(&faucet_key) {
-            info!("Created faucet block with hash {}", block.hash);
+    async fn get_transaction_proof(
+        &self,
+        request: Request<TransactionProofRequest>,
+    ) -> Result<Response<TransactionProofResponse>, Status> {
+        let req = request.into_inner();
+        let chain = self.blockchain.lock().unwrap();
 
-            Ok(Response::new(FaucetResponse {
-                success: true,
-                amount: faucet_amount,
-                message: "Faucet funds sent successfully".to_string(),
-            }))
-        } else {
-            Ok(Response::new(FaucetResponse {
-                success,
-                amount: if success { faucet_amount } else { 0 },
-                message: if success {
-                    "Faucet funds queued for next block".to_string()
-                } else {
-                    "Failed to process faucet request".to_string()
-                },
-            }))
+        let hashes = chain
+            .block_tx_hashes(req.block_index)
+            .ok_or_else(|| Status::not_found("Block not found"))?;
+        let position = hashes
+            .iter()
+            .position(|hash| *hash == req.tx_hash)
+            .ok_or_else(|| Status::not_found("Transaction not found in block"))?;
+
+        let sibling_hashes = merkle::merkle_proof(hashes, position);
+        let computed_root = merkle::root_from_proof(&req.tx_hash, position, &sibling_hashes);
+
+        Ok(Response::new(TransactionProofResponse {
+            position: position as u64,
+            sibling_hashes,
+            computed_root,
+        }))
+    }
+
+    async fn get_receipt(
+        &self,
+        request: Request<ReceiptRequest>,
+    ) -> Result<Response<ReceiptResponse>, Status> {
+        let req = request.into_inner();
+        let chain = self.blockchain.lock().unwrap();
+
+        let Some((block_index, position)) = chain.locate_transaction(&req.tx_hash) else {
+            let pending = chain
+                .transaction_pool
+                .iter()
+                .any(|tx| tx.hash() == req.tx_hash);
+            return Err(Status::not_found(if pending {
+                "Transaction is still pending"
+            } else {
+                "Transaction not found"
+            }));
+        };
+
+        // Balances as of the transaction itself, so later spends in the same
+        // block and the block reward are not included
+        let state = chain
+            .state_after_transaction(block_index, position)
+            .expect("located transactions are stored");
+        let block = chain
+            .chain
+            .get(block_index)
+            .expect("located blocks are stored");
+        let tx = &block.transactions[position];
+        let balance_of = |address: &str| state.balances.get(address).copied().unwrap_or(0);
+        let recipients = tx
+            .payments()
+            .into_iter()
+            .map(|(to, _)| AccountBalance {
+                address: to.to_string(),
+                balance: balance_of(to),
+            })
+            .collect();
+        Ok(Response::new(ReceiptResponse {
+            success: true,
+            block_index: block.index,
+            block_hash: block.hash.clone(),
+            position: position as u64,
+            fee: tx.fee,
+            sender_balance: balance_of(&tx.from),
+            recipients,
+        }))
+    }
+
+    async fn wait_for_confirmation(
+        &self,
+        request: Request<WaitForConfirmationRequest>,
+    ) -> Result<Response<WaitForConfirmationResponse>, Status> {
+        let req = request.into_inner();
+        // The server-level timeout would cut a longer wait off with DEADLINE_EXCEEDED
+        let timeout = Duration::from_secs(req.timeout_secs);
+        if timeout >= self.rpc_timeout {
+            return Err(Status::invalid_argument(format!(
+                "timeout_secs must be below the RPC timeout of {}s",
+                self.rpc_timeout.as_secs()
+            )));
+        }
+
+        let confirmed = |block: &Block| {
+            Response::new(WaitForConfirmationResponse {
+                confirmed: true,
+                block: Some(ProtoBlock::from(block)),
+            })
+        };
+        let find_mined = |chain: &Blockchain| {
+            chain
+                .locate_transaction(&req.tx_hash)
+                .and_then(|(block_index, _)| chain.chain.get(block_index))
+                .map(Cow::into_owned)
+        };
+
+        // Subscribe before checking, so a block mined in between isn't missed
+        let mut events = {
+            let chain = self.blockchain.lock().unwrap();
+            if let Some(block) = find_mined(&chain) {
+                return Ok(confirmed(&block));
+            }
+            if !chain
+                .transaction_pool
+                .iter()
+                .any(|tx| tx.hash() == req.tx_hash)
+            {
+                return Err(Status::not_found("Transaction not found"));
+            }
+            chain.subscribe_chain_events()
+        };
+
+        let wait = async {
+            loop {
+                match events.recv().await {
+                    Ok(ChainEvent::Block(block)) => {
+                        if block.transactions.iter().any(|tx| tx.hash() == req.tx_hash) {
+                            return Some(*block);
+                        }
+                    }
+                    Ok(ChainEvent::Rollback { .. }) => {}
+                    // Skipped blocks may hold the transaction, so look it up instead
+                    Err(RecvError::Lagged(_)) => {
+                        if let Some(block) = find_mined(&self.blockchain.lock().unwrap()) {
+                            return Some(block);
+                        }
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(Some(block)) => Ok(confirmed(&block)),
+            _ => Ok(Response::new(WaitForConfirmationResponse {
+                confirmed: false,
+                block: None,
+            })),
+        }
+    }
+
+    async fn get_finality(
+        &self,
+        request: Request<FinalityRequest>,
+    ) -> Result<Response<FinalityResponse>, Status> {
+        let req = request.into_inner();
+        let chain = self.blockchain.lock().unwrap();
+
+        // Confirmations count blocks on top of the transaction's, as for faucet grants
+        let Some((block_index, _)) = chain.locate_transaction(&req.tx_hash) else {
+            if !chain
+                .transaction_pool
+                .iter()
+                .any(|tx| tx.hash() == req.tx_hash)
+            {
+                return Err(Status::not_found("Transaction not found"));
+            }
+            return Ok(Response::new(FinalityResponse {
+                r#final: false,
+                mined: false,
+                confirmations: 0,
+                confirmations_needed: self.finality_depth,
+                finality_depth: self.finality_depth,
+                block_index: 0,
+                final_at_height: 0,
+            }));
+        };
+
+        let height = chain.chain.len() as u64 - 1;
+        let confirmations = height - block_index;
+        Ok(Response::new(FinalityResponse {
+            r#final: confirmations >= self.finality_depth,
+            mined: true,
+            confirmations,
+            confirmations_needed: self.finality_depth.saturating_sub(confirmations),
+            finality_depth: self.finality_depth,
+            block_index,
+            final_at_height: block_index + self.finality_depth,
+        }))
+    }
+
+    async fn switch_consensus(
+        &self,
+        request: Request<SwitchConsensusRequest>,
+    ) -> Result<Response<SwitchConsensusResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
         }
+        let req = request.into_inner();
+
+        // An unset difficulty keeps the configured one, bits included. A zero
+        // target would accept every hash, so it can't be asked for.
+        let mut params = ConsensusParams {
+            min_stake: req.min_stake,
+            ..self.consensus_params.clone()
+        };
+        if req.difficulty > 64 {
+            return Err(Status::invalid_argument(
+                "Difficulty must be at most 64 leading zero digits",
+            ));
+        }
+        if req.difficulty > 0 {
+            params.difficulty = req.difficulty as usize;
+            params.difficulty_bits = None;
+        }
+        let consensus = self
+            .consensus_registry
+            .create(&req.consensus, &params)
+            .map_err(Status::invalid_argument)?;
+
+        let mut chain = self.blockchain.lock().unwrap();
+        let dropped = chain.set_consensus(consensus);
+        chain.start_mining(Arc::clone(&self.blockchain));
+
+        Ok(Response::new(SwitchConsensusResponse {
+            success: true,
+            message: format!("Switched to {}", chain.consensus.name()),
+            retained_transactions: chain.transaction_pool.len() as u64,
+            dropped_transactions: dropped as u64,
+        }))
+    }
+
+    async fn relay_transaction(
+        &self,
+        request: Request<ProtoTransaction>,
+    ) -> Result<Response<TransactionResponse>, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        if let Some(status) = self.ban_status(peer) {
+            return Err(status);
+        }
+        // Balance checks against a partially synced chain would be wrong
+        if self.blockchain.lock().unwrap().syncing {
+            return Err(Status::unavailable(NODE_SYNCING));
+        }
+        let tx = request.into_inner();
+        let response = match self.accept_transaction(tx.clone()) {
+            Ok(()) => {
+                self.record_peer(peer, PeerEvent::Accepted);
+                TransactionResponse {
+                    success: true,
+                    message: "Transaction relayed".into(),
+                }
+            }
+            Err(reason) => {
+                // Most rejections (balance, fees, a copy still pooled) are honest
+                // gossip races; forgeries, faucet grants and replays of mined
+                // transactions aren't
+                let tx = Transaction::from(tx);
+                let event = {
+                    let chain = self.blockchain.lock().unwrap();
+                    let hash = tx.signing_hash();
+                    if tx.from == FAUCET_MOCKCHAIN_ADDRESS || !tx.verify(chain.chain_id) {
+                        Some(PeerEvent::InvalidTransaction)
+                    } else if chain.known_transactions.contains(&hash)
+                        && !chain
+                            .transaction_pool
+                            .iter()
+                            .any(|p| p.signing_hash() == hash)
+                    {
+                        Some(PeerEvent::ReplayedTransaction)
+                    } else {
+                        None
+                    }
+                };
+                if let Some(event) = event {
+                    self.record_peer(peer, event);
+                }
+                TransactionResponse {
+                    success: false,
+                    message: reason,
+                }
+            }
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn undo_block(
+        &self,
+        request: Request<UndoBlockRequest>,
+    ) -> Result<Response<UndoBlockResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
+        }
+        if !self.dev_mode {
+            return Err(Status::permission_denied(
+                "UndoBlock is only available in dev mode",
+            ));
+        }
+
+        let mut chain = self.blockchain.lock().unwrap();
+        match chain.undo_last_block() {
+            Some(block) => Ok(Response::new(UndoBlockResponse {
+                success: true,
+                message: format!("Removed block {}", block.hash),
+                removed_index: block.index,
+                requeued_transactions: block.transactions.len() as u64,
+            })),
+            None => Ok(Response::new(UndoBlockResponse {
+                success: false,
+                message: "Cannot undo the genesis block".to_string(),
+                removed_index: 0,
+                requeued_transactions: 0,
+            })),
+        }
+    }
+
+    async fn get_state(
+        &self,
+        request: Request<StateRequest>,
+    ) -> Result<Response<StateResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
+        }
+        if !self.dev_mode {
+            return Err(Status::permission_denied(
+                "GetState is only available in dev mode",
+            ));
+        }
+
+        let chain = self.blockchain.lock().unwrap();
+        let state = chain.tip_state();
+        let mut accounts: Vec<AccountBalance> = state
+            .balances
+            .iter()
+            .filter(|(_, balance)| **balance > 0)
+            .map(|(address, balance)| AccountBalance {
+                address: address.clone(),
+                balance: *balance,
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.address.cmp(&b.address));
+
+        Ok(Response::new(StateResponse {
+            accounts,
+            height: chain.chain.len() as u64 - 1,
+            state_root: state.root(),
+        }))
+    }
+
+    async fn register_stake(
+        &self,
+        request: Request<RegisterStakeRequest>,
+    ) -> Result<Response<RegisterStakeResponse>, Status> {
+        let req = request.into_inner();
+        let chain = self.blockchain.lock().unwrap();
+
+        // Only the address's key may stake it. Stakes aren't locked, but a
+        // validator must at least hold what it stakes.
+        let result = if !consensus::verify_stake_signature(
+            &req.address,
+            req.amount,
+            chain.chain_id,
+            &req.signature,
+        ) {
+            Err("Stake must be signed by the validator's key".to_string())
+        } else if chain.check_balance(&req.address, req.amount) {
+            chain.consensus.register_stake(&req.address, req.amount)
+        } else {
+            Err("Insufficient balance for stake".to_string())
+        };
+        // Stakes live in the chain file's envelope, so save them right away
+        if result.is_ok() {
+            chain.persist();
+        }
+
+        let response = match result {
+            Ok(()) => RegisterStakeResponse {
+                success: true,
+                message: format!("Registered {} with stake {}", req.address, req.amount),
+            },
+            Err(reason) => RegisterStakeResponse {
+                success: false,
+                message: reason,
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn is_validator(
+        &self,
+        request: Request<IsValidatorRequest>,
+    ) -> Result<Response<IsValidatorResponse>, Status> {
+        let req = request.into_inner();
+        let stake = self
+            .blockchain
+            .lock()
+            .unwrap()
+            .consensus
+            .validator_stake(&req.address);
+
+        Ok(Response::new(IsValidatorResponse {
+            is_validator: stake.is_some(),
+            stake: stake.unwrap_or(0),
+        }))
+    }
+
+    async fn get_block_range(
+        &self,
+        request: Request<BlockRangeRequest>,
+    ) -> Result<Response<BlockRangeResponse>, Status> {
+        let req = request.into_inner();
+        // Zero asks for the largest page the server allows
+        let count = match req.count {
+            0 => self.max_block_range,
+            n => n.min(self.max_block_range),
+        } as usize;
+        let chain = self.blockchain.lock().unwrap();
+
+        let blocks: Vec<ProtoBlock> = chain
+            .chain
+            .iter_from(req.start)
+            .take(count)
+            .map(|block| ProtoBlock::from(&*block))
+            .collect();
+        let next_start = req.start + blocks.len() as u64;
+
+        Ok(Response::new(BlockRangeResponse {
+            blocks,
+            next_start,
+            has_more: next_start < chain.chain.len() as u64,
+        }))
+    }
+
+    async fn get_block(
+        &self,
+        request: Request<BlockRequest>,
+    ) -> Result<Response<BlockResponse>, Status> {
+        let req = request.into_inner();
+        let chain = self.blockchain.lock().unwrap();
+        let block = chain
+            .chain
+            .get(req.index)
+            .ok_or_else(|| Status::not_found(format!("No block at height {}", req.index)))?;
+
+        let stats = req.include_stats.then(|| BlockStats {
+            total_fees: block.total_fees(),
+            total_output: block
+                .transactions
+                .iter()
+                .map(Transaction::total_amount)
+                .sum(),
+            transaction_count: block.transactions.len() as u64,
+            size_bytes: block.size() as u64,
+            reward: block.miner_reward(),
+        });
+        Ok(Response::new(BlockResponse {
+            block: Some(ProtoBlock::from(&*block)),
+            stats,
+        }))
+    }
+
+    async fn get_difficulty_history(
+        &self,
+        request: Request<DifficultyHistoryRequest>,
+    ) -> Result<Response<DifficultyHistoryResponse>, Status> {
+        let req = request.into_inner();
+        let count = match req.count {
+            0 => self.max_block_range,
+            n => n.min(self.max_block_range),
+        } as usize;
+        let chain = self.blockchain.lock().unwrap();
+
+        let blocks = chain
+            .chain
+            .iter_from(req.from_index)
+            .take(count)
+            .map(|block| {
+                let bits = chain.consensus.block_difficulty(&block);
+                BlockDifficulty {
+                    index: block.index,
+                    timestamp: block.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                    difficulty_bits: bits,
+                    target: bits
+                        .map_or_else(String::new, |bits| hex::encode(target_for_bits(bits))),
+                }
+            })
+            .collect();
+
+        Ok(Response::new(DifficultyHistoryResponse { blocks }))
+    }
+
+    async fn subscribe_address(
+        &self,
+        request: Request<SubscribeAddressRequest>,
+    ) -> Result<Response<Self::SubscribeAddressStream>, Status> {
+        let address = request.into_inner().address;
+        let mut events = self.blockchain.lock().unwrap().subscribe_chain_events();
+        let blockchain = Arc::clone(&self.blockchain);
+
+        // Unlike StreamHeaders this stream is open-ended, so it isn't bounded by the
+        // RPC timeout; it ends when the client disconnects
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            let touches = |t: &Transaction| {
+                t.from == address || t.payments().iter().any(|(to, _)| *to == address)
+            };
+            let rewards = |block: &Block| {
+                block.miner == address
+                    || block
+                        .treasury_reward
+                        .as_ref()
+                        .is_some_and(|(treasury, _)| *treasury == address)
+            };
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        // Silently skipping blocks would leave the wallet with a wrong history
+                        warn!("Subscriber for {} missed {} blocks", address, missed);
+                        let _ = tx
+                            .send(Err(Status::data_loss(format!(
+                                "Subscriber fell behind and missed {} blocks",
+                                missed
+                            ))))
+                            .await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                // A rollback is reported against the last block kept, so the wallet
+                // can mark the funds it credited from the reverted blocks pending again
+                let (block_index, block_hash, tx_hashes, mining_reward, rollback) = match event {
+                    ChainEvent::Block(block) => (
+                        block.index,
+                        block.hash.clone(),
+                        block
+                            .transactions
+                            .iter()
+                            .filter(|t| touches(t))
+                            .map(Transaction::hash)
+                            .collect::<Vec<_>>(),
+                        rewards(&block),
+                        false,
+                    ),
+                    ChainEvent::Rollback {
+                        fork_height,
+                        reverted,
+                        unconfirmed,
+                    } => (
+                        fork_height,
+                        reverted
+                            .last()
+                            .map(|block| block.previous_hash.clone())
+                            .unwrap_or_default(),
+                        unconfirmed
+                            .iter()
+                            .filter(|t| touches(t))
+                            .map(Transaction::hash)
+                            .collect(),
+                        reverted.iter().any(rewards),
+                        true,
+                    ),
+                };
+                if tx_hashes.is_empty() && !mining_reward {
+                    continue;
+                }
+
+                let balance = blockchain.lock().unwrap().get_balance(&address);
+                let event = AddressEvent {
+                    block_index,
+                    block_hash,
+                    tx_hashes,
+                    mining_reward,
+                    balance,
+                    rollback,
+                };
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn subscribe_blocks(
+        &self,
+        _request: Request<SubscribeBlocksRequest>,
+    ) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        let mut events = self.blockchain.lock().unwrap().subscribe_chain_events();
+        let finality_depth = self.finality_depth;
+
+        // Open-ended like SubscribeAddress
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(ChainEvent::Block(block)) => ProtoChainEvent {
+                        new_block: Some(NewBlockEvent {
+                            header: Some(BlockHeader::from(&*block)),
+                            finality_depth,
+                            final_height: block.index.checked_sub(finality_depth),
+                        }),
+                        rollback: None,
+                    },
+                    Ok(ChainEvent::Rollback {
+                        fork_height,
+                        reverted,
+                        unconfirmed,
+                    }) => ProtoChainEvent {
+                        new_block: None,
+                        rollback: Some(RollbackEvent {
+                            fork_height,
+                            reverted_block_hashes: reverted
+                                .into_iter()
+                                .map(|block| block.hash)
+                                .collect(),
+                            unconfirmed_tx_hashes: unconfirmed
+                                .iter()
+                                .map(Transaction::hash)
+                                .collect(),
+                        }),
+                    },
+                    // A missed rollback would leave the client crediting reverted funds
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Block subscriber missed {} events", missed);
+                        let _ = tx
+                            .send(Err(Status::data_loss(format!(
+                                "Subscriber fell behind and missed {} events",
+                                missed
+                            ))))
+                            .await;
+                        return;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if tx.send(Ok(event)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn estimate_confirmation_time(
+        &self,
+        request: Request<ConfirmationTimeRequest>,
+    ) -> Result<Response<ConfirmationTimeResponse>, Status> {
+        let fee = request.into_inner().fee;
+        let chain = self.blockchain.lock().unwrap();
+
+        // Fill blocks with the higher-fee transactions under the usual byte, gas
+        // and count limits; the transaction lands in the block after them
+        let mut ahead: Vec<Transaction> = chain
+            .transaction_pool
+            .iter()
+            .filter(|tx| tx.fee > fee)
+            .cloned()
+            .collect();
+        let transactions_ahead = ahead.len() as u64;
+        let mut blocks = 1;
+        while !ahead.is_empty() {
+            let (included, deferred) = chain.select_block_transactions(ahead);
+            if included.is_empty() {
+                break;
+            }
+            ahead = deferred;
+            blocks += 1;
+        }
+
+        Ok(Response::new(ConfirmationTimeResponse {
+            transactions_ahead,
+            blocks,
+            seconds: blocks * chain.average_block_interval(),
+        }))
+    }
+
+    async fn estimate_fee(
+        &self,
+        _request: Request<EstimateFeeRequest>,
+    ) -> Result<Response<EstimateFeeResponse>, Status> {
+        let chain = self.blockchain.lock().unwrap();
+
+        // Faucet grants pay no fee and would drag the distribution down
+        let mut fees: Vec<u64> = chain
+            .transaction_pool
+            .iter()
+            .filter(|tx| tx.from != FAUCET_MOCKCHAIN_ADDRESS)
+            .map(|tx| tx.fee)
+            .collect();
+        fees.sort_unstable();
+
+        let median_fee = match fees.len() {
+            0 => 0,
+            n if n & 1 == 1 => fees[n / 2],
+            n => (fees[n / 2 - 1] + fees[n / 2]) / 2,
+        };
+
+        // Blocks take the highest fee per byte first. Once the pool overflows a block, a
+        // transaction the size of the median pending one has to beat the lowest rate
+        // that would still be included.
+        let min_fee = chain.current_min_fee();
+        let (included, deferred) =
+            chain.select_block_transactions(chain.transaction_pool.iter().cloned().collect());
+        let mut sizes: Vec<usize> = chain
+            .transaction_pool
+            .iter()
+            .filter(|tx| tx.from != FAUCET_MOCKCHAIN_ADDRESS)
+            .map(Transaction::size)
+            .collect();
+        sizes.sort_unstable();
+
+        let suggested_fee = match (deferred.is_empty(), included.last()) {
+            (false, Some(marginal)) => {
+                let median_size = sizes
+                    .get(sizes.len() / 2)
+                    .copied()
+                    .unwrap_or(marginal.size());
+                let outbid = (marginal.fee as u128 * median_size as u128)
+                    .div_ceil(marginal.size() as u128) as u64
+                    + 1;
+                outbid.max(min_fee)
+            }
+            _ => min_fee,
+        };
+
+        Ok(Response::new(EstimateFeeResponse {
+            pending_transactions: fees.len() as u64,
+            min_fee: fees.first().copied().unwrap_or(0),
+            median_fee,
+            max_fee: fees.last().copied().unwrap_or(0),
+            suggested_fee,
+            block_limit: MAX_BLOCK_TRANSACTIONS as u64,
+            block_byte_limit: chain.max_block_bytes as u64,
+        }))
+    }
+
+    async fn add_peer(
+        &self,
+        request: Request<AddPeerRequest>,
+    ) -> Result<Response<PeerResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
+        }
+        let addr = request.into_inner().addr;
+        p2p::validate_peer_addr(&addr).map_err(Status::invalid_argument)?;
+        if let Some(status) = self.ban_status(p2p::peer_ip(&addr)) {
+            return Ok(Response::new(PeerResponse {
+                success: false,
+                message: status.message().to_string(),
+            }));
+        }
+
+        // Handshake: only peer with nodes that share our genesis block
+        let genesis_hash = self.blockchain.lock().unwrap().genesis_hash();
+        if let Err(message) = p2p::check_genesis(&addr, &genesis_hash).await {
+            warn!("Refused peer {}: {}", addr, message);
+            return Ok(Response::new(PeerResponse {
+                success: false,
+                message,
+            }));
+        }
+
+        let added = self.peers.lock().unwrap().insert(addr.clone());
+        if added {
+            info!("Added peer {}", addr);
+        }
+        Ok(Response::new(PeerResponse {
+            success: added,
+            message: if added {
+                format!("Added peer {}", addr)
+            } else {
+                format!("{} is already a peer", addr)
+            },
+        }))
+    }
+
+    async fn remove_peer(
+        &self,
+        request: Request<RemovePeerRequest>,
+    ) -> Result<Response<PeerResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
+        }
+        let addr = request.into_inner().addr;
+
+        let removed = self.peers.lock().unwrap().remove(&addr);
+        if removed {
+            info!("Removed peer {}", addr);
+        }
+        Ok(Response::new(PeerResponse {
+            success: removed,
+            message: if removed {
+                format!("Removed peer {}", addr)
+            } else {
+                format!("{} is not a peer", addr)
+            },
+        }))
+    }
+
+    async fn list_peers(
+        &self,
+        request: Request<ListPeersRequest>,
+    ) -> Result<Response<ListPeersResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
+        }
+        let mut peers: Vec<String> = self.peers.lock().unwrap().iter().cloned().collect();
+        peers.sort();
+        let reputations = self
+            .reputation
+            .standings()
+            .into_iter()
+            .map(|(ip, standing)| ProtoPeerReputation {
+                ip: ip.to_string(),
+                score: standing.score,
+                banned: standing.banned_until.is_some(),
+                banned_until: standing
+                    .banned_until
+                    .map(|until| until.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+                    .unwrap_or_default(),
+            })
+            .collect();
+
+        Ok(Response::new(ListPeersResponse { peers, reputations }))
+    }
+
+    async fn submit_block(
+        &self,
+        request: Request<ProtoBlock>,
+    ) -> Result<Response<SubmitBlockResponse>, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        if let Some(status) = self.ban_status(peer) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let header = req
+            .header
+            .ok_or_else(|| Status::invalid_argument("Block has no header"))?;
+        let timestamp = DateTime::parse_from_rfc3339(&header.timestamp)
+            .map_err(|e| Status::invalid_argument(format!("Invalid timestamp: {}", e)))?
+            .with_timezone(&Utc);
+
+        Ok(Response::new(self.accept_block(
+            peer,
+            header,
+            timestamp,
+            req.transactions,
+        )))
+    }
+
+    async fn get_block_template(
+        &self,
+        request: Request<BlockTemplateRequest>,
+    ) -> Result<Response<BlockTemplateResponse>, Status> {
+        let miner_key = PublicKey::from_str(&request.into_inner().miner)
+            .map_err(|e| Status::invalid_argument(format!("Invalid miner key: {}", e)))?;
+        let chain = self.blockchain.lock().unwrap();
+        if chain.syncing {
+            return Err(Status::unavailable(NODE_SYNCING));
+        }
+        let target_bits = chain.consensus.target_bits().ok_or_else(|| {
+            Status::failed_precondition(format!(
+                "{} blocks can't be sealed by an external miner",
+                chain.consensus.name()
+            ))
+        })?;
+        let unsealed = chain.block_template(&miner_key);
+        let block = unsealed.block();
+        let response = BlockTemplateResponse {
+            template_id: block.hash.clone(),
+            block: Some(ProtoBlock::from(block)),
+            target_bits,
+            target: hex::encode(target_for_bits(target_bits)),
+        };
+
+        // Templates on an old tip can never be appended
+        let mut templates = self.templates.lock().unwrap();
+        templates.retain(|template| template.block().previous_hash == block.previous_hash);
+        templates.push_back(unsealed);
+        if templates.len() > MAX_BLOCK_TEMPLATES {
+            templates.pop_front();
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn submit_sealed_block(
+        &self,
+        request: Request<SealedBlockRequest>,
+    ) -> Result<Response<SubmitBlockResponse>, Status> {
+        let req = request.into_inner();
+        let mut chain = self.blockchain.lock().unwrap();
+        let mut templates = self.templates.lock().unwrap();
+        let Some(position) = templates
+            .iter()
+            .position(|template| template.block().hash == req.template_id)
+        else {
+            return Err(Status::not_found("Unknown or expired block template"));
+        };
+
+        // A wrong nonce leaves the template in place, so the miner can keep searching
+        let mut block = templates[position].block().clone();
+        block.nonce = req.nonce;
+        block.hash = block.calculate_hash();
+        let index = block.index;
+        let response = match chain.add_block(block) {
+            Ok(()) => {
+                templates.remove(position);
+                SubmitBlockResponse {
+                    success: true,
+                    message: format!("Added block {}", index),
+                    already_known: false,
+                }
+            }
+            Err(reason) => SubmitBlockResponse {
+                success: false,
+                message: reason,
+                already_known: false,
+            },
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn submit_compact_block(
+        &self,
+        request: Request<CompactBlock>,
+    ) -> Result<Response<SubmitBlockResponse>, Status> {
+        let peer = request.remote_addr().map(|addr| addr.ip());
+        if let Some(status) = self.ban_status(peer) {
+            return Err(status);
+        }
+        let req = request.into_inner();
+        let header = req
+            .header
+            .ok_or_else(|| Status::invalid_argument("Block has no header"))?;
+        let timestamp = DateTime::parse_from_rfc3339(&header.timestamp)
+            .map_err(|e| Status::invalid_argument(format!("Invalid timestamp: {}", e)))?
+            .with_timezone(&Utc);
+
+        // Only a block extending our tip needs its transactions; any other is
+        // acknowledged or rejected from the header alone
+        let mut found = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let chain = self.blockchain.lock().unwrap();
+            if header.index == chain.chain.len() as u64 {
+                let pool: HashMap<String, &Transaction> = chain
+                    .transaction_pool
+                    .iter()
+                    .map(|tx| (tx.hash(), tx))
+                    .collect();
+                for hash in &req.tx_hashes {
+                    match pool.get(hash) {
+                        Some(tx) => {
+                            found.insert(hash.clone(), ProtoTransaction::from(*tx));
+                        }
+                        None => missing.push(hash.clone()),
+                    }
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let peers: Vec<String> = self.peers.lock().unwrap().iter().cloned().collect();
+            found.extend(p2p::fetch_missing_transactions(&peers, &header.hash, &missing).await);
+            let unresolved = missing
+                .iter()
+                .filter(|hash| !found.contains_key(*hash))
+                .count();
+            if unresolved > 0 {
+                return Ok(Response::new(SubmitBlockResponse {
+                    success: false,
+                    message: format!(
+                        "Missing {} transactions of block {}",
+                        unresolved, header.index
+                    ),
+                    already_known: false,
+                }));
+            }
+        }
+
+        let transactions = req
+            .tx_hashes
+            .iter()
+            .filter_map(|hash| found.get(hash).cloned())
+            .collect();
+        Ok(Response::new(self.accept_block(
+            peer,
+            header,
+            timestamp,
+            transactions,
+        )))
+    }
+
+    async fn get_missing_transactions(
+        &self,
+        request: Request<MissingTransactionsRequest>,
+    ) -> Result<Response<MissingTransactionsResponse>, Status> {
+        let req = request.into_inner();
+        let wanted: HashSet<String> = req.tx_hashes.into_iter().collect();
+
+        let chain = self.blockchain.lock().unwrap();
+        // Peers ask about blocks we just announced, so search from the tip
+        let block = chain
+            .chain
+            .iter()
+            .rev()
+            .find(|block| block.hash == req.block_hash)
+            .ok_or_else(|| Status::not_found(format!("Unknown block {}", req.block_hash)))?;
+        let hashes = chain
+            .block_tx_hashes(block.index)
+            .expect("every block is indexed");
+
+        let transactions = block
+            .transactions
+            .iter()
+            .zip(hashes)
+            .filter(|(_, hash)| wanted.contains(*hash))
+            .map(|(tx, _)| ProtoTransaction::from(tx))
+            .collect();
+        Ok(Response::new(MissingTransactionsResponse { transactions }))
+    }
+
+    async fn force_mine(
+        &self,
+        request: Request<ForceMineRequest>,
+    ) -> Result<Response<ForceMineResponse>, Status> {
+        if !self.is_admin(&request) {
+            return Err(Status::unauthenticated(ADMIN_TOKEN_REQUIRED));
+        }
+        let mut chain = self.blockchain.lock().unwrap();
+        if chain.block_production != BlockProduction::Manual && !self.dev_mode {
+            return Err(Status::failed_precondition(
+                "ForceMine requires manual block production or dev mode",
+            ));
+        }
+
+        let (_, miner_key) = entropy::generate_keypair(&mut chain.rng).map_err(Status::internal)?;
+        let response = match chain.mine_pending_transactions(&miner_key) {
+            Some(block) => ForceMineResponse {
+                success: true,
+                message: format!("Mined block {}", block.hash),
+                block_index: block.index,
+                transactions: block.transactions.len() as u64,
+            },
+            None => ForceMineResponse {
+                success: false,
+                message: "No pending transactions to mine".to_string(),
+                block_index: 0,
+                transactions: 0,
+            },
+        };
+        Ok(Response::new(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::{Message, Secp256k1, SecretKey};
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::{
+        state::AccountState,
+        testkit::{self, new_account, signed_transfer, TestNode},
+    };
+
+    fn sign_stake(secret_key: &SecretKey, address: &str, amount: u64, chain_id: u64) -> Vec<u8> {
+        let message = Message::from_slice(&consensus::stake_message(address, amount, chain_id))
+            .expect("digests are 32 bytes");
+        Secp256k1::signing_only()
+            .sign_ecdsa(&message, secret_key)
+            .serialize_compact()
+            .to_vec()
+    }
+
+    // A PoS node whose blocks are all produced by its own bootstrap validator
+    async fn pos_node() -> TestNode {
+        let (producer_key, producer) = new_account();
+        TestNode::spawn(Config {
+            consensus: "pos".to_string(),
+            min_stake: 100,
+            bootstrap_validator: Some(producer),
+            producer_key: Some(producer_key),
+            ..Config::default()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn stakes_must_be_signed_by_the_validator() {
+        let mut node = pos_node().await;
+        let (validator_key, validator) = node.funded_account(500);
+        let (other_key, _) = new_account();
+
+        for signature in [
+            Vec::new(),
+            sign_stake(&other_key, &validator, 200, node.chain_id),
+            sign_stake(&validator_key, &validator, 300, node.chain_id),
+            sign_stake(&validator_key, &validator, 200, node.chain_id + 1),
+        ] {
+            let response = node
+                .client
+                .register_stake(RegisterStakeRequest {
+                    address: validator.clone(),
+                    amount: 200,
+                    signature,
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!response.success);
+        }
+
+        let response = node
+            .client
+            .register_stake(RegisterStakeRequest {
+                address: validator.clone(),
+                amount: 200,
+                signature: sign_stake(&validator_key, &validator, 200, node.chain_id),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success, "{}", response.message);
+        assert_eq!(
+            node.blockchain
+                .lock()
+                .unwrap()
+                .consensus
+                .validator_stake(&validator),
+            Some(200)
+        );
+    }
+
+    #[tokio::test]
+    async fn is_validator_reports_registered_stakes() {
+        let mut node = pos_node().await;
+        let (validator_key, validator) = node.funded_account(500);
+        let (_, stranger) = new_account();
+        let response = node
+            .client
+            .register_stake(RegisterStakeRequest {
+                address: validator.clone(),
+                amount: 150,
+                signature: sign_stake(&validator_key, &validator, 150, node.chain_id),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.success, "{}", response.message);
+
+        let registered = node
+            .client
+            .is_validator(IsValidatorRequest { address: validator })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(registered.is_validator);
+        assert_eq!(registered.stake, 150);
+
+        let unregistered = node
+            .client
+            .is_validator(IsValidatorRequest { address: stranger })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!unregistered.is_validator);
+        assert_eq!(unregistered.stake, 0);
+    }
+
+    // Dev nodes with the same genesis time share a genesis block
+    async fn dev_node() -> TestNode {
+        TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            genesis_time: DateTime::from_timestamp(1_700_000_000, 0),
+            ..Config::default()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn only_the_same_block_is_already_known() {
+        let mut ours = dev_node().await;
+        let theirs = dev_node().await;
+        ours.funded_account(10);
+        theirs.funded_account(20);
+        let our_block = ProtoBlock::from(ours.blockchain.lock().unwrap().chain.tip());
+        let their_block = ProtoBlock::from(theirs.blockchain.lock().unwrap().chain.tip());
+
+        let known = ours
+            .client
+            .submit_block(our_block)
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(known.success && known.already_known);
+
+        let conflicting = ours
+            .client
+            .submit_block(their_block)
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!conflicting.success && !conflicting.already_known);
+        assert!(conflicting.message.contains("different block"));
+    }
+
+    // Asserts that GetTip, served without the lock, matches the locked chain
+    async fn assert_tip_matches(node: &mut TestNode) {
+        let tip = node
+            .client
+            .get_tip(TipRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        let chain = node.blockchain.lock().unwrap();
+        let last = chain.chain.tip();
+        assert_eq!(tip.height, last.index);
+        assert_eq!(tip.hash, last.hash);
+        assert_eq!(
+            tip.timestamp,
+            last.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn lock_free_tip_follows_the_chain() {
+        let mut node = dev_node().await;
+        assert_tip_matches(&mut node).await;
+        for amount in 1..=3 {
+            node.funded_account(amount);
+            assert_tip_matches(&mut node).await;
+        }
+
+        node.blockchain.lock().unwrap().undo_last_block().unwrap();
+        assert_tip_matches(&mut node).await;
+    }
+
+    #[tokio::test]
+    async fn headers_stream_in_order_from_the_start_index() {
+        let mut node = dev_node().await;
+        for amount in 1..=3 {
+            node.funded_account(amount);
+        }
+
+        let headers: Vec<BlockHeader> = node
+            .client
+            .stream_headers(HeadersRequest { start_index: 1 })
+            .await
+            .unwrap()
+            .into_inner()
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        // Headers carry the merkle root in place of the transactions
+        let chain = node.blockchain.lock().unwrap();
+        assert_eq!(headers.len(), 3);
+        for (header, index) in headers.iter().zip(1..) {
+            let block = chain.chain.get(index).unwrap();
+            assert_eq!(header.index, index);
+            assert_eq!(header.hash, block.hash);
+            assert_eq!(header.merkle_root, block.merkle_root);
+            assert!(!block.transactions.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_refused_before_processing() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            max_request_bytes: 1024,
+            ..Config::default()
+        })
+        .await;
+        let (sender_key, sender) = node.funded_account(1000);
+
+        let mut batch = signed_transfer(&sender_key, &sender, &sender, 1, node.chain_id);
+        batch.outputs = (0..20)
+            .map(|_| TransactionOutput {
+                to: new_account().1,
+                amount: 1,
+            })
+            .collect();
+        let status = node.client.submit_transaction(batch).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+        assert!(node.blockchain.lock().unwrap().transaction_pool.is_empty());
+
+        let (_, recipient) = new_account();
+        let small = signed_transfer(&sender_key, &sender, &recipient, 1, node.chain_id);
+        let response = node.client.submit_transaction(small).await.unwrap();
+        assert!(response.into_inner().success);
+    }
+
+    #[tokio::test]
+    async fn block_ranges_page_through_the_chain() {
+        let mut node = dev_node().await;
+        for amount in 1..25 {
+            node.funded_account(amount);
+        }
+        assert_eq!(node.blockchain.lock().unwrap().chain.len(), 25);
+
+        let mut pages = Vec::new();
+        let mut start = 0;
+        loop {
+            let page = node
+                .client
+                .get_block_range(BlockRangeRequest { start, count: 10 })
+                .await
+                .unwrap()
+                .into_inner();
+            pages.push(page.blocks.len());
+            for (block, index) in page.blocks.iter().zip(start..) {
+                assert_eq!(block.header.as_ref().unwrap().index, index);
+            }
+            if !page.has_more {
+                break;
+            }
+            start = page.next_start;
+        }
+        assert_eq!(pages, vec![10, 10, 5]);
+    }
+
+    #[tokio::test]
+    async fn address_events_only_cover_the_address() {
+        let mut node = dev_node().await;
+        let (_, alice) = new_account();
+        let mut events = node
+            .client
+            .subscribe_address(SubscribeAddressRequest {
+                address: alice.clone(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        node.funded_account(10);
+        testkit::grant(&mut node.blockchain.lock().unwrap(), &alice, 20);
+        let relevant = node.force_mine().unwrap();
+
+        // The unrelated block sent nothing, so the first event is alice's
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.block_index, relevant.index);
+        assert_eq!(event.tx_hashes, vec![relevant.transactions[0].hash()]);
+        assert_eq!(event.balance, 20);
+        assert!(!event.mining_reward);
+    }
+
+    #[tokio::test]
+    async fn fee_estimate_reports_the_pending_distribution() {
+        let mut node = dev_node().await;
+        let (sender_key, sender) = node.funded_account(1000);
+        for (amount, fee) in [(1, 6), (2, 2), (3, 10), (4, 4)] {
+            let mut tx = Transaction::new(&sender, &new_account().1, amount);
+            tx.fee = fee;
+            tx.sign(&sender_key, node.chain_id);
+            node.blockchain.lock().unwrap().add_transaction(tx).unwrap();
+        }
+        // Fee-less faucet grants don't count
+        testkit::grant(&mut node.blockchain.lock().unwrap(), &sender, 1);
+
+        let estimate = node
+            .client
+            .estimate_fee(EstimateFeeRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(estimate.pending_transactions, 4);
+        assert_eq!(estimate.min_fee, 2);
+        assert_eq!(estimate.median_fee, 5);
+        assert_eq!(estimate.max_fee, 10);
+        // Everything fits in the next block, so the floor is enough
+        assert_eq!(estimate.suggested_fee, 0);
+    }
+
+    #[tokio::test]
+    async fn repeated_faucet_requests_grant_once() {
+        let mut node = dev_node().await;
+        let (_, address) = new_account();
+        let request = |request_id: &str| FaucetRequest {
+            address: address.clone(),
+            request_id: request_id.to_string(),
+        };
+
+        // Each grant is mined straight away
+        let first = node
+            .client
+            .request_faucet(request("abc"))
+            .await
+            .unwrap()
+            .into_inner();
+        let repeat = node
+            .client
+            .request_faucet(request("abc"))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(first.success, "{}", first.message);
+        assert_eq!(
+            (repeat.success, repeat.amount),
+            (first.success, first.amount)
+        );
+        assert_eq!(node.blockchain.lock().unwrap().chain.len(), 2);
+        assert_eq!(node.balance(&address).await, first.amount);
+
+        node.client.request_faucet(request("def")).await.unwrap();
+        assert_eq!(node.balance(&address).await, 2 * first.amount);
+    }
+
+    async fn peers(node: &mut TestNode) -> Vec<String> {
+        let response = node.client.list_peers(ListPeersRequest {}).await.unwrap();
+        response.into_inner().peers
+    }
+
+    #[tokio::test]
+    async fn peers_are_added_and_removed_at_runtime() {
+        let mut node = dev_node().await;
+        let (_peer, addr) = TestNode::spawn_listening(Config {
+            consensus: "dev".to_string(),
+            genesis_time: DateTime::from_timestamp(1_700_000_000, 0),
+            ..Config::default()
+        })
+        .await;
+
+        let status = node
+            .client
+            .add_peer(AddPeerRequest {
+                addr: "localhost:50051".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let request = AddPeerRequest { addr: addr.clone() };
+        let added = node.client.add_peer(request.clone()).await.unwrap();
+        assert!(added.into_inner().success);
+        assert_eq!(peers(&mut node).await, vec![addr.clone()]);
+        let again = node.client.add_peer(request).await.unwrap();
+        assert!(!again.into_inner().success);
+
+        let removed = node
+            .client
+            .remove_peer(RemovePeerRequest { addr: addr.clone() })
+            .await
+            .unwrap();
+        assert!(removed.into_inner().success);
+        assert!(peers(&mut node).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn manual_mode_only_mines_on_force_mine() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            block_production: BlockProduction::Manual,
+            ..Config::default()
+        })
+        .await;
+        let blockchain = Arc::clone(&node.blockchain);
+        node.blockchain.lock().unwrap().start_mining(blockchain);
+        assert!(!node.blockchain.lock().unwrap().is_mining());
+
+        testkit::grant(&mut node.blockchain.lock().unwrap(), &new_account().1, 5);
+        // Dev blocks would appear within 100ms if a mining loop were running
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(node.blockchain.lock().unwrap().chain.len(), 1);
+
+        let mined = node
+            .client
+            .force_mine(ForceMineRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(mined.success, "{}", mined.message);
+        assert_eq!((mined.block_index, mined.transactions), (1, 1));
+        assert_eq!(node.blockchain.lock().unwrap().chain.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn get_state_matches_a_replay_of_the_chain() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            dev_mode: true,
+            ..Config::default()
+        })
+        .await;
+        let (sender_key, sender) = node.funded_account(100);
+        let (_, recipient) = new_account();
+        let transfer = signed_transfer(&sender_key, &sender, &recipient, 40, node.chain_id);
+        assert!(
+            node.client
+                .submit_transaction(transfer)
+                .await
+                .unwrap()
+                .into_inner()
+                .success
+        );
+        node.force_mine().unwrap();
+
+        let response = node
+            .client
+            .get_state(StateRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        let chain = node.blockchain.lock().unwrap();
+        let mut replayed = AccountState::default();
+        for block in chain.chain.iter() {
+            replayed.apply_block(&block).unwrap();
+        }
+        let mut expected: Vec<_> = replayed
+            .balances
+            .iter()
+            .filter(|(_, balance)| **balance > 0)
+            .map(|(address, balance)| (address.clone(), *balance))
+            .collect();
+        expected.sort();
+        let accounts: Vec<_> = response
+            .accounts
+            .into_iter()
+            .map(|account| (account.address, account.balance))
+            .collect();
+        assert_eq!(accounts, expected);
+        assert!(accounts.contains(&(recipient, 40)));
+        assert_eq!(response.height, chain.chain.tip().index);
+        assert_eq!(response.state_root, replayed.root());
+    }
+
+    #[tokio::test]
+    async fn get_state_is_refused_outside_dev_mode() {
+        let mut node = dev_node().await;
+        let status = node.client.get_state(StateRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn receipts_report_balances_right_after_the_transaction() {
+        let mut node = dev_node().await;
+        let (sender_key, sender) = node.funded_account(100);
+        let (_, recipient) = new_account();
+        let mut tx = Transaction::new(&sender, &recipient, 40);
+        tx.sign(&sender_key, node.chain_id);
+        let request = || ReceiptRequest { tx_hash: tx.hash() };
+
+        let status = node.client.get_receipt(request()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        node.client
+            .submit_transaction(ProtoTransaction::from(&tx))
+            .await
+            .unwrap();
+        let status = node.client.get_receipt(request()).await.unwrap_err();
+        assert!(status.message().contains("still pending"));
+
+        let block = node.force_mine().unwrap();
+        let receipt = node
+            .client
+            .get_receipt(request())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(receipt.success);
+        assert_eq!(receipt.block_index, block.index);
+        assert_eq!(receipt.block_hash, block.hash);
+        assert_eq!(receipt.position, 0);
+        assert_eq!(receipt.fee, tx.fee);
+        assert_eq!(receipt.sender_balance, 100 - 40 - tx.fee);
+        assert_eq!(receipt.recipients.len(), 1);
+        assert_eq!(receipt.recipients[0].address, recipient);
+        assert_eq!(receipt.recipients[0].balance, 40);
+    }
+
+    #[tokio::test]
+    async fn admin_rpcs_require_the_admin_token() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            dev_mode: true,
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        })
+        .await;
+        let with_token = |token: &str| {
+            let mut request = Request::new(StateRequest {});
+            request.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).parse().unwrap(),
+            );
+            request
+        };
+
+        let status = node.client.get_state(StateRequest {}).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        let status = node
+            .client
+            .get_state(with_token("guess"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert!(node.client.get_state(with_token("secret")).await.is_ok());
+
+        // Public RPCs don't need it
+        assert!(node.client.get_tip(TipRequest {}).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pending_by_sender_lists_only_the_sender_oldest_first() {
+        let mut node = dev_node().await;
+        let (sender_key, sender) = node.funded_account(100);
+        let (other_key, other) = node.funded_account(100);
+        let (_, recipient) = new_account();
+        let mut newer = Transaction::new(&sender, &recipient, 1);
+        newer.sign(&sender_key, node.chain_id);
+        let mut older = Transaction::new(&sender, &recipient, 2);
+        older.timestamp = newer.timestamp - 10;
+        older.sign(&sender_key, node.chain_id);
+        let mut unrelated = Transaction::new(&other, &recipient, 3);
+        unrelated.sign(&other_key, node.chain_id);
+        for tx in [&newer, &older, &unrelated] {
+            let response = node
+                .client
+                .submit_transaction(ProtoTransaction::from(tx))
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(response.success, "{}", response.message);
+        }
+
+        let pending = node
+            .client
+            .get_pending_by_sender(PendingBySenderRequest { address: sender })
+            .await
+            .unwrap()
+            .into_inner()
+            .transactions;
+        let amounts: Vec<u64> = pending.iter().map(|tx| tx.amount).collect();
+        assert_eq!(amounts, [2, 1]);
+
+        let none = node
+            .client
+            .get_pending_by_sender(PendingBySenderRequest { address: recipient })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(none.transactions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn peers_on_another_genesis_are_refused() {
+        let mut node = dev_node().await;
+        let (_peer, addr) = TestNode::spawn_listening(Config {
+            consensus: "dev".to_string(),
+            genesis_time: DateTime::from_timestamp(1_600_000_000, 0),
+            ..Config::default()
+        })
+        .await;
+
+        let response = node
+            .client
+            .add_peer(AddPeerRequest { addr })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!response.success);
+        assert!(response.message.contains("genesis"), "{}", response.message);
+        assert!(peers(&mut node).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait_for_confirmation_returns_once_mined() {
+        let mut node = dev_node().await;
+        let (sender_key, sender) = node.funded_account(100);
+        let (_, recipient) = new_account();
+        let mut tx = Transaction::new(&sender, &recipient, 10);
+        tx.sign(&sender_key, node.chain_id);
+        let request = |timeout_secs| WaitForConfirmationRequest {
+            tx_hash: tx.hash(),
+            timeout_secs,
+        };
+
+        let status = node
+            .client
+            .wait_for_confirmation(request(1))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        node.client
+            .submit_transaction(ProtoTransaction::from(&tx))
+            .await
+            .unwrap();
+        let pending = node.client.wait_for_confirmation(request(0)).await.unwrap();
+        assert!(!pending.into_inner().confirmed);
+        let too_long = request(Config::default().rpc_timeout.as_secs());
+        let status = node
+            .client
+            .wait_for_confirmation(too_long)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let (mut client, wait) = (node.client.clone(), request(5));
+        let waiting = tokio::spawn(async move { client.wait_for_confirmation(wait).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let block = node.force_mine().unwrap();
+        let response = waiting.await.unwrap().unwrap().into_inner();
+        assert!(response.confirmed);
+        assert_eq!(response.block.unwrap().header.unwrap().hash, block.hash);
+    }
+
+    #[tokio::test]
+    async fn faucet_grants_stop_at_the_cap() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            faucet_total_cap: Some(2_500),
+            ..Config::default()
+        })
+        .await;
+        let mut granted = Vec::new();
+        for request_id in ["a", "b", "c", "d"] {
+            let response = node
+                .client
+                .request_faucet(FaucetRequest {
+                    address: new_account().1,
+                    request_id: request_id.to_string(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            granted.push((response.success, response.amount));
+            if !response.success {
+                assert_eq!(response.message, "Faucet exhausted");
+            }
+        }
+        assert_eq!(
+            granted,
+            [(true, 1_000), (true, 1_000), (false, 0), (false, 0)]
+        );
+        assert_eq!(node.blockchain.lock().unwrap().faucet_emitted(), 2_000);
+
+        // Nor can a client or peer mint its own grant past it
+        let (_, address) = new_account();
+        let forged =
+            ProtoTransaction::from(&Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, &address, 1_000));
+        let submitted = node
+            .client
+            .submit_transaction(forged.clone())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!submitted.success);
+        assert!(
+            submitted.message.contains("faucet"),
+            "{}",
+            submitted.message
+        );
+        let relayed = node
+            .client
+            .relay_transaction(forged)
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!relayed.success);
+        node.force_mine();
+        assert_eq!(node.balance(&address).await, 0);
+        assert_eq!(node.blockchain.lock().unwrap().faucet_emitted(), 2_000);
+    }
+
+    #[tokio::test]
+    async fn syncing_nodes_refuse_transactions() {
+        let mut node = dev_node().await;
+        let (sender_key, sender) = node.funded_account(100);
+        let (_, recipient) = new_account();
+        let transfer = || signed_transfer(&sender_key, &sender, &recipient, 5, node.chain_id);
+        node.blockchain.lock().unwrap().syncing = true;
+
+        let status = node
+            .client
+            .submit_transaction(transfer())
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.message(), NODE_SYNCING);
+        let status = node.client.relay_transaction(transfer()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert!(node.blockchain.lock().unwrap().transaction_pool.is_empty());
+
+        node.blockchain.lock().unwrap().syncing = false;
+        let response = node.client.submit_transaction(transfer()).await.unwrap();
+        assert!(response.into_inner().success);
+    }
+
+    #[tokio::test]
+    async fn confirmation_estimates_count_the_blocks_of_higher_fees() {
+        let mut node = dev_node().await;
+        let interval = {
+            let mut chain = node.blockchain.lock().unwrap();
+            chain.block_gas_limit = 2 * crate::transaction::TRANSFER_GAS;
+            for amount in 1..=5 {
+                let mut tx = Transaction::new(&new_account().1, "recipient", amount);
+                tx.fee = 10;
+                chain.transaction_pool.push_back(tx);
+            }
+            chain.average_block_interval()
+        };
+        let estimate = |fee| ConfirmationTimeRequest { fee };
+
+        // Five ahead at two per block: three blocks, then ours
+        let low = node
+            .client
+            .estimate_confirmation_time(estimate(1))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((low.transactions_ahead, low.blocks), (5, 4));
+        assert_eq!(low.seconds, 4 * interval);
+
+        let matching = node
+            .client
+            .estimate_confirmation_time(estimate(10))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((matching.transactions_ahead, matching.blocks), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn address_stats_sum_mined_activity() {
+        let mut node = dev_node().await;
+        let (sender_key, sender) = node.funded_account(100);
+        let funded_at = node.blockchain.lock().unwrap().chain.tip().index;
+        let (_, recipient) = new_account();
+        for amount in [10, 15] {
+            let transfer = signed_transfer(&sender_key, &sender, &recipient, amount, node.chain_id);
+            node.client.submit_transaction(transfer).await.unwrap();
+            node.force_mine().unwrap();
+        }
+        let stats = |address: &str| AddressStatsRequest {
+            address: address.to_string(),
+        };
+
+        let sent = node
+            .client
+            .get_address_stats(stats(&sender))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!((sent.transactions_sent, sent.total_sent), (2, 25));
+        assert_eq!((sent.transactions_received, sent.total_received), (1, 100));
+        assert!(sent.active);
+        assert_eq!(sent.first_active_height, funded_at);
+        assert_eq!(sent.last_active_height, funded_at + 2);
+
+        let idle = node
+            .client
+            .get_address_stats(stats(&new_account().1))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(idle, AddressStatsResponse::default());
+
+        // Block rewards alone aren't transactions
+        let miner = node.blockchain.lock().unwrap().chain.tip().miner.clone();
+        let mined = node
+            .client
+            .get_address_stats(stats(&miner))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(mined, AddressStatsResponse::default());
+    }
+
+    #[tokio::test]
+    async fn remote_peers_are_rate_limited() {
+        let (mut node, _) = TestNode::spawn_listening(Config {
+            consensus: "dev".to_string(),
+            rate_limit: Some(2),
+            ..Config::default()
+        })
+        .await;
+        let mut codes = Vec::new();
+        for _ in 0..5 {
+            let result = node.client.get_tip(TipRequest {}).await;
+            codes.push(result.map_or_else(|status| status.code(), |_| tonic::Code::Ok));
+        }
+        // The bucket starts with a one-second burst and refills far slower than this loop
+        assert_eq!(&codes[..2], [tonic::Code::Ok; 2]);
+        assert!(
+            codes[2..].contains(&tonic::Code::ResourceExhausted),
+            "{:?}",
+            codes
+        );
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(node.client.get_tip(TipRequest {}).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn difficulty_history_reports_each_block_target() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "pow".to_string(),
+            difficulty_bits: Some(4),
+            ..Config::default()
+        })
+        .await;
+        for amount in 1..=3 {
+            node.funded_account(amount);
+        }
+
+        let history = node
+            .client
+            .get_difficulty_history(DifficultyHistoryRequest {
+                from_index: 1,
+                count: 2,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .blocks;
+        let indexes: Vec<u64> = history.iter().map(|block| block.index).collect();
+        assert_eq!(indexes, [1, 2]);
+        // Four bits leave a target of 2^252
+        for block in history {
+            assert_eq!(block.difficulty_bits, Some(4));
+            assert_eq!(block.target, format!("10{}", "0".repeat(62)));
+        }
+    }
+
+    #[tokio::test]
+    async fn sealed_templates_need_a_nonce_that_meets_the_target() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "pow".to_string(),
+            difficulty_bits: Some(8),
+            ..Config::default()
+        })
+        .await;
+        let (_, miner) = new_account();
+        let template = node
+            .client
+            .get_block_template(BlockTemplateRequest { miner })
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Grind like an external miner, from the template alone
+        let proto = template.block.unwrap();
+        let header = proto.header.unwrap();
+        let timestamp = DateTime::parse_from_rfc3339(&header.timestamp)
+            .unwrap()
+            .into();
+        let parent = node.blockchain.lock().unwrap().chain.tip().clone();
+        let mut block = rebuild_block(header, timestamp, proto.transactions, &parent).unwrap();
+        assert_eq!(block.hash, template.template_id);
+        let target = hex::decode(&template.target).unwrap();
+        let meets = |block: &Block| hex::decode(&block.hash).unwrap() < target;
+        let mut search = |wanted: bool| loop {
+            block.nonce += 1;
+            block.hash = block.calculate_hash();
+            if meets(&block) == wanted {
+                return block.nonce;
+            }
+        };
+        let (wrong, right) = (search(false), search(true));
+        let submit = |nonce| SealedBlockRequest {
+            template_id: template.template_id.clone(),
+            nonce,
+        };
+
+        let rejected = node
+            .client
+            .submit_sealed_block(submit(wrong))
+            .await
+            .unwrap();
+        assert!(!rejected.into_inner().success);
+        let accepted = node
+            .client
+            .submit_sealed_block(submit(right))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(accepted.success, "{}", accepted.message);
+        assert_eq!(node.blockchain.lock().unwrap().chain.len(), 2);
+        let status = node
+            .client
+            .submit_sealed_block(submit(right))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn finality_follows_the_confirmation_depth() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            finality_depth: 2,
+            ..Config::default()
+        })
+        .await;
+        let (sender_key, sender) = node.funded_account(100);
+        let transfer = signed_transfer(&sender_key, &sender, &new_account().1, 10, node.chain_id);
+        let request = FinalityRequest {
+            tx_hash: Transaction::from(transfer.clone()).hash(),
+        };
+        node.client.submit_transaction(transfer).await.unwrap();
+
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            let finality = node
+                .client
+                .get_finality(request.clone())
+                .await
+                .unwrap()
+                .into_inner();
+            seen.push((finality.mined, finality.confirmations, finality.r#final));
+            node.funded_account(1);
+        }
+        let finality = node
+            .client
+            .get_finality(request)
+            .await
+            .unwrap()
+            .into_inner();
+        seen.push((finality.mined, finality.confirmations, finality.r#final));
+        // Pending, then mined alongside the first grant and buried by the next two
+        assert_eq!(
+            seen,
+            [
+                (false, 0, false),
+                (true, 0, false),
+                (true, 1, false),
+                (true, 2, true)
+            ]
+        );
+        assert_eq!(finality.final_at_height, finality.block_index + 2);
+        assert_eq!(finality.confirmations_needed, 0);
+    }
+
+    #[tokio::test]
+    async fn get_reward_reports_the_schedule() {
+        let mut node = dev_node().await;
+        let reward = |height| RewardRequest { height };
+        let genesis = node
+            .client
+            .get_reward(reward(0))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(genesis.reward, 0);
+        // Tests run with the default schedule, which never halves
+        let later = node
+            .client
+            .get_reward(reward(1_000))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(later.reward, crate::MINING_REWARD);
+        assert_eq!((later.halvings, later.next_halving_height), (0, None));
+    }
+
+    #[tokio::test]
+    async fn peers_sending_invalid_blocks_are_banned() {
+        let (mut node, _) = TestNode::spawn_listening(Config {
+            consensus: "dev".to_string(),
+            ban_threshold: 100,
+            ..Config::default()
+        })
+        .await;
+        let miner_key = PublicKey::from_secret_key(&Secp256k1::new(), &new_account().0);
+        let template = node.blockchain.lock().unwrap().block_template(&miner_key);
+        let mut forged = ProtoBlock::from(template.block());
+        forged.header.as_mut().unwrap().hash = "00".repeat(32);
+
+        // Each invalid block costs 20 points, so the fifth reaches the threshold
+        for _ in 0..4 {
+            let response = node.client.submit_block(forged.clone()).await.unwrap();
+            assert!(!response.into_inner().success);
+        }
+        let peers = node
+            .client
+            .list_peers(ListPeersRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(peers.reputations.len(), 1);
+        assert_eq!(peers.reputations[0].score, -80);
+        assert!(!peers.reputations[0].banned);
+
+        let response = node.client.submit_block(forged.clone()).await.unwrap();
+        assert!(!response.into_inner().success);
+        let status = node.client.submit_block(forged).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        let peers = node
+            .client
+            .list_peers(ListPeersRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(peers.reputations[0].banned);
+        assert_eq!(peers.reputations[0].score, -100);
+    }
+
+    #[tokio::test]
+    async fn block_stats_match_the_block_transactions() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            ..Config::default()
+        })
+        .await;
+        let (sender_key, sender) = node.funded_account(100);
+        let chain_id = node.chain_id;
+        let mut single = Transaction::new(&sender, &new_account().1, 10);
+        single.fee = 3;
+        single.sign(&sender_key, chain_id);
+        let mut batch = Transaction::new(&sender, "", 0);
+        batch.outputs = vec![(new_account().1, 7), (new_account().1, 5)];
+        batch.fee = 2;
+        batch.sign(&sender_key, chain_id);
+        for tx in [single, batch] {
+            node.blockchain.lock().unwrap().add_transaction(tx).unwrap();
+        }
+        let index = node.force_mine().unwrap().index;
+
+        let response = node
+            .client
+            .get_block(BlockRequest {
+                index,
+                include_stats: true,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let block = response.block.unwrap();
+        let stats = response.stats.unwrap();
+        let fees: u64 = block.transactions.iter().map(|tx| tx.fee).sum();
+        let output: u64 = block
+            .transactions
+            .iter()
+            .map(|tx| tx.amount + tx.outputs.iter().map(|out| out.amount).sum::<u64>())
+            .sum();
+        let stored = node
+            .blockchain
+            .lock()
+            .unwrap()
+            .chain
+            .get(index)
+            .unwrap()
+            .into_owned();
+        assert_eq!(stats.total_fees, 5);
+        assert_eq!(stats.total_fees, fees);
+        assert_eq!(stats.total_output, output);
+        assert_eq!(stats.total_output, 22);
+        assert_eq!(stats.transaction_count, 2);
+        assert_eq!(
+            stats.size_bytes,
+            serde_json::to_vec(&stored).unwrap().len() as u64
+        );
+        assert_eq!(stats.reward, block::reward_for_index(index) + fees);
+
+        let plain = node
+            .client
+            .get_block(BlockRequest {
+                index,
+                include_stats: false,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(plain.stats.is_none());
+    }
+
+    #[tokio::test]
+    async fn reorgs_emit_rollbacks_for_the_reverted_transactions() {
+        let config = || Config {
+            consensus: "dev".to_string(),
+            finality_depth: 2,
+            genesis_time: DateTime::from_timestamp(1_700_000_000, 0),
+            ..Config::default()
+        };
+        let mut node = TestNode::spawn(config()).await;
+        let mut fork = testkit::open_chain(&config(), Arc::new(crate::clock::SystemClock));
+        let (sender_key, sender) = node.funded_account(100);
+        fork.add_block(node.blockchain.lock().unwrap().chain.tip().clone())
+            .unwrap();
+        let mut events = node
+            .client
+            .subscribe_blocks(SubscribeBlocksRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        let (_, recipient) = new_account();
+        let orphaned = Transaction::from(signed_transfer(
+            &sender_key,
+            &sender,
+            &recipient,
+            30,
+            node.chain_id,
+        ));
+        node.blockchain
+            .lock()
+            .unwrap()
+            .add_transaction(orphaned.clone())
+            .unwrap();
+        let reverted = node.force_mine().unwrap();
+        let miner_key = PublicKey::from_secret_key(&Secp256k1::new(), &new_account().0);
+        let branch: Vec<Block> = (0..2)
+            .map(|amount| {
+                testkit::grant(&mut fork, &recipient, amount + 1);
+                fork.mine_pending_transactions(&miner_key).unwrap()
+            })
+            .collect();
+        node.blockchain
+            .lock()
+            .unwrap()
+            .replace_chain(branch.clone())
+            .unwrap();
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(events.next().await.unwrap().unwrap());
+        }
+        let mined = received[0].new_block.clone().unwrap();
+        assert_eq!(mined.header.unwrap().hash, reverted.hash);
+        assert_eq!((mined.finality_depth, mined.final_height), (2, Some(0)));
+        let rollback = received[1].rollback.clone().unwrap();
+        assert_eq!(rollback.fork_height, 1);
+        assert_eq!(rollback.reverted_block_hashes, [reverted.hash]);
+        assert_eq!(rollback.unconfirmed_tx_hashes, [orphaned.hash()]);
+        for (event, block) in received[2..].iter().zip(&branch) {
+            let event = event.new_block.clone().unwrap();
+            assert_eq!(event.header.unwrap().hash, block.hash);
+            assert_eq!(event.final_height, block.index.checked_sub(2));
+        }
+    }
+
+    #[tokio::test]
+    async fn switching_consensus_keeps_the_chain_valid() {
+        let mut node = TestNode::spawn(Config {
+            consensus: "dev".to_string(),
+            genesis_time: DateTime::from_timestamp(1_700_000_000, 0),
+            block_production: BlockProduction::Manual,
+            difficulty_bits: Some(6),
+            ..Config::default()
+        })
+        .await;
+        node.funded_account(10);
+        let switch = |difficulty| SwitchConsensusRequest {
+            consensus: "pow".to_string(),
+            difficulty,
+            min_stake: 0,
+        };
+
+        let status = node.client.switch_consensus(switch(65)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        // No difficulty keeps the configured target bits
+        node.client.switch_consensus(switch(0)).await.unwrap();
+        let chain = || node.blockchain.lock().unwrap();
+        assert_eq!(chain().consensus.target_bits(), Some(6));
+
+        // The dev block before the switch still validates under dev rules
+        node.funded_account(20);
+        assert_eq!(chain().chain.tip().consensus_data, "6");
+        chain().validate().unwrap();
     }
 }
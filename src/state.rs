@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+
+use crate::{block::Block, hash, merkle, transaction::Transaction, FAUCET_MOCKCHAIN_ADDRESS};
+
+// Account balances obtained by replaying blocks in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountState {
+    pub balances: HashMap<String, u64>,
+}
+
+impl AccountState {
+    // Applies a block's transactions in order, then its mining reward (none for genesis).
+    // Fails if any sender would be overdrawn at that point in the block,
+    // in which case the state is partially applied and should be discarded.
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), String> {
+        for tx in &block.transactions {
+            self.apply_transaction(tx)?;
+        }
+        self.apply_reward(block);
+        Ok(())
+    }
+
+    // Debits the sender (unless it's the faucet) and credits every recipient.
+    // Leaves the state untouched if the sender can't cover the transaction.
+    pub fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), String> {
+        if tx.from != FAUCET_MOCKCHAIN_ADDRESS {
+            let balance = self.balances.entry(tx.from.clone()).or_default();
+            *balance = balance.checked_sub(tx.total_debit()).ok_or_else(|| {
+                format!(
+                    "transaction {} overdraws {} (balance {}, amount {}, fee {})",
+                    tx.hash(),
+                    tx.from,
+                    balance,
+                    tx.total_amount(),
+                    tx.fee
+                )
+            })?;
+        }
+        for (to, amount) in tx.payments() {
+            *self.balances.entry(to.to_string()).or_default() += amount;
+        }
+        Ok(())
+    }
+
+    pub fn apply_reward(&mut self, block: &Block) {
+        if block.is_genesis() {
+            return;
+        }
+        *self.balances.entry(block.miner.clone()).or_default() += block.miner_reward();
+        if let Some((treasury, share)) = &block.treasury_reward {
+            *self.balances.entry(treasury.clone()).or_default() += share;
+        }
+    }
+
+    // Merkle root over the non-zero balances sorted by address, so any two
+    // nodes holding the same balances agree on it
+    pub fn root(&self) -> String {
+        let mut accounts: Vec<(&String, &u64)> = self
+            .balances
+            .iter()
+            .filter(|(_, balance)| **balance > 0)
+            .collect();
+        accounts.sort();
+
+        let leaves: Vec<String> = accounts
+            .iter()
+            .map(|account| hash::hex_digest(serde_json::to_string(account).unwrap().as_bytes()))
+            .collect();
+        merkle::merkle_root(&leaves)
+    }
+}
+
+// Running totals of what an address has been paid, granted by the faucet, and
+// has spent, as of a block that changed them
+#[derive(Debug, Clone, Copy, Default)]
+struct BalancePoint {
+    height: u64,
+    received: u64,
+    granted: u64,
+    spent: u64,
+}
+
+impl BalancePoint {
+    fn balance(&self) -> u64 {
+        (self.received + self.granted).saturating_sub(self.spent)
+    }
+}
+
+// Each address's running totals after every block that changed them, and the
+// balances at the tip, so balance lookups at any height and the tip state
+// are read without replaying the chain. Blocks are pushed in order and
+// truncated from the tip, in step with the chain.
+#[derive(Debug, Default)]
+pub struct BalanceIndex {
+    history: HashMap<String, Vec<BalancePoint>>,
+    // Addresses each block changed, by height, so truncation only revisits them
+    changed: Vec<Vec<String>>,
+    tip: AccountState,
+}
+
+impl BalanceIndex {
+    // Indexes `block`, which must follow the last block indexed
+    pub fn push(&mut self, block: &Block) {
+        let mut deltas: HashMap<&str, BalancePoint> = HashMap::new();
+        for tx in &block.transactions {
+            if tx.from != FAUCET_MOCKCHAIN_ADDRESS {
+                deltas.entry(&tx.from).or_default().spent += tx.total_debit();
+            }
+            for (to, amount) in tx.payments() {
+                let delta = deltas.entry(to).or_default();
+                if tx.from == FAUCET_MOCKCHAIN_ADDRESS {
+                    delta.granted += amount;
+                } else {
+                    delta.received += amount;
+                }
+            }
+        }
+        if !block.is_genesis() {
+            deltas.entry(&block.miner).or_default().received += block.miner_reward();
+            if let Some((treasury, share)) = &block.treasury_reward {
+                deltas.entry(treasury).or_default().received += share;
+            }
+        }
+
+        let mut changed = Vec::with_capacity(deltas.len());
+        for (address, delta) in deltas {
+            let points = self.history.entry(address.to_string()).or_default();
+            let last = points.last().copied().unwrap_or_default();
+            let point = BalancePoint {
+                height: block.index,
+                received: last.received + delta.received,
+                granted: last.granted + delta.granted,
+                spent: last.spent + delta.spent,
+            };
+            points.push(point);
+            self.tip
+                .balances
+                .insert(address.to_string(), point.balance());
+            changed.push(address.to_string());
+        }
+        self.changed.push(changed);
+    }
+
+    // Forgets every block from height `len` up
+    pub fn truncate(&mut self, len: usize) {
+        for addresses in self.changed.drain(len.min(self.changed.len())..) {
+            for address in addresses {
+                let Some(points) = self.history.get_mut(&address) else {
+                    continue;
+                };
+                while points
+                    .last()
+                    .is_some_and(|point| point.height >= len as u64)
+                {
+                    points.pop();
+                }
+                match points.last() {
+                    Some(point) => {
+                        self.tip.balances.insert(address, point.balance());
+                    }
+                    None => {
+                        self.history.remove(&address);
+                        self.tip.balances.remove(&address);
+                    }
+                }
+            }
+        }
+    }
+
+    // Balance of `address` as of block `height`. Faucet grants only count once
+    // `faucet_confirmations` blocks deep at that height.
+    pub fn balance_at(&self, address: &str, height: u64, faucet_confirmations: u64) -> u64 {
+        let Some(points) = self.history.get(address) else {
+            return 0;
+        };
+        let as_of = |height: u64| {
+            let after = points.partition_point(|point| point.height <= height);
+            after.checked_sub(1).map(|i| points[i]).unwrap_or_default()
+        };
+        let point = as_of(height);
+        let granted = height
+            .checked_sub(faucet_confirmations)
+            .map_or(0, |confirmed| as_of(confirmed).granted);
+        (point.received + granted).saturating_sub(point.spent)
+    }
+
+    // Heights of the blocks that touched `address`, in order: every transaction
+    // from or paying it records a point, even one that nets to zero, as does
+    // any reward it was paid
+    pub fn heights(&self, address: &str) -> impl Iterator<Item = u64> + '_ {
+        self.history
+            .get(address)
+            .into_iter()
+            .flatten()
+            .map(|point| point.height)
+    }
+
+    // Balances after block `height`, as replaying the chain up to it would give,
+    // so a fork can be validated from where it branches off
+    pub fn state_at(&self, height: u64) -> AccountState {
+        let balances = self
+            .history
+            .keys()
+            .map(|address| (address.clone(), self.balance_at(address, height, 0)))
+            .filter(|(_, balance)| *balance > 0)
+            .collect();
+        AccountState { balances }
+    }
+
+    // Balances after the last block indexed, counting faucet grants straight away
+    pub fn tip(&self) -> &AccountState {
+        &self.tip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn block(previous: &Block, transactions: Vec<Transaction>, miner: &str) -> Block {
+        let mut block = Block::new(transactions, Some(previous), Utc::now());
+        block.miner = miner.to_string();
+        block
+    }
+
+    // Genesis, a faucet grant to alice, then alice paying bob 30 with a fee of 2
+    fn chain() -> Vec<Block> {
+        let genesis = Block::new(Vec::new(), None, Utc::now());
+        let grant = block(
+            &genesis,
+            vec![Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, "alice", 100)],
+            "miner",
+        );
+        let mut payment = Transaction::new("alice", "bob", 30);
+        payment.fee = 2;
+        let payment = block(&grant, vec![payment], "miner");
+        vec![genesis, grant, payment]
+    }
+
+    #[test]
+    fn balances_match_a_replay_at_every_height() {
+        let chain = chain();
+        let mut index = BalanceIndex::default();
+        let mut replayed = AccountState::default();
+        for block in &chain {
+            index.push(block);
+            replayed.apply_block(block).unwrap();
+            for address in ["alice", "bob", "miner"] {
+                assert_eq!(
+                    index.balance_at(address, block.index, 0),
+                    replayed.balances.get(address).copied().unwrap_or(0)
+                );
+            }
+            assert_eq!(index.tip().root(), replayed.root());
+        }
+        assert_eq!(index.balance_at("alice", 1, 0), 100);
+        assert_eq!(index.balance_at("alice", 2, 0), 68);
+        assert_eq!(index.balance_at("bob", 1, 0), 0);
+    }
+
+    #[test]
+    fn faucet_grants_wait_for_confirmations() {
+        let mut index = BalanceIndex::default();
+        for block in &chain() {
+            index.push(block);
+        }
+        // Two blocks deep only at height 3, which the chain hasn't reached
+        assert_eq!(index.balance_at("alice", 1, 2), 0);
+        assert_eq!(index.balance_at("bob", 2, 2), 30);
+    }
+
+    #[test]
+    fn truncation_restores_earlier_balances() {
+        let chain = chain();
+        let mut index = BalanceIndex::default();
+        for block in &chain {
+            index.push(block);
+        }
+        index.truncate(2);
+        assert_eq!(index.balance_at("alice", 2, 0), 100);
+        assert_eq!(index.tip().balances.get("bob"), None);
+
+        index.push(&chain[2]);
+        assert_eq!(index.balance_at("bob", 2, 0), 30);
+    }
+
+    #[test]
+    fn heights_list_every_block_touching_an_address() {
+        let chain = chain();
+        let mut index = BalanceIndex::default();
+        for block in &chain {
+            index.push(block);
+        }
+        let heights = |index: &BalanceIndex, address| index.heights(address).collect::<Vec<_>>();
+        assert_eq!(heights(&index, "alice"), [1, 2]);
+        assert_eq!(heights(&index, "bob"), [2]);
+        assert_eq!(heights(&index, "miner"), [1, 2]);
+        assert!(heights(&index, "carol").is_empty());
+
+        index.truncate(2);
+        assert_eq!(heights(&index, "alice"), [1]);
+        assert!(heights(&index, "bob").is_empty());
+    }
+
+    #[test]
+    fn past_states_match_a_replay() {
+        let chain = chain();
+        let mut index = BalanceIndex::default();
+        for block in &chain {
+            index.push(block);
+        }
+        let mut replayed = AccountState::default();
+        for block in &chain {
+            replayed.apply_block(block).unwrap();
+            assert_eq!(index.state_at(block.index).root(), replayed.root());
+        }
+        assert_eq!(index.state_at(1).balances.get("alice"), Some(&100));
+        assert_eq!(index.state_at(1).balances.get("bob"), None);
+    }
+}
@@ -0,0 +1,395 @@
+use std::{
+    error::Error,
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Nonce,
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::Block,
+    hash::{self, HashAlgo},
+};
+
+// Bincode files start with this header, then the format version as two ASCII
+// digits, so the format can be detected on load
+const BINCODE_MAGIC: &[u8] = b"MCKBIN";
+// Gzip stream header; neither encoding starts with it, so compressed files are
+// detected on load
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+// Encrypted files start with this header, then the key derivation salt and the
+// nonce, then the ChaCha20-Poly1305 ciphertext of the (possibly gzipped) chain
+const ENCRYPTED_MAGIC: &[u8] = b"MCKENC";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// Version of the persisted layout. Fields added to blocks and transactions
+// default when missing, so older files still load as long as this matches;
+// bump it, and add a step to `migrate`, for changes defaults can't absorb.
+// Version 2 added the validator set to the envelope.
+const CHAIN_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StorageFormat {
+    // Human-readable, the default
+    #[default]
+    Json,
+    // Compact binary encoding, much faster and smaller for long chains
+    Bincode,
+}
+
+impl FromStr for StorageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "bincode" => Ok(Self::Bincode),
+            other => Err(format!("Unknown storage format: {}", other)),
+        }
+    }
+}
+
+// Where and how the chain is persisted after each new block
+#[derive(Debug, Clone)]
+pub struct Storage {
+    pub path: PathBuf,
+    pub format: StorageFormat,
+    // Gzip the encoded chain before writing it
+    pub compress: bool,
+    // Encrypt the file with a key derived from this passphrase
+    pub passphrase: Option<String>,
+}
+
+// JSON files carry the format version next to the envelope; the first
+// format predates the tag
+#[derive(Serialize, Deserialize)]
+struct VersionedJson<C> {
+    #[serde(default = "first_format_version")]
+    version: u32,
+    #[serde(flatten)]
+    envelope: PersistedChain<C>,
+}
+
+fn first_format_version() -> u32 {
+    1
+}
+
+// On-disk envelope around the chain
+#[derive(Serialize, Deserialize)]
+struct PersistedChain<C> {
+    // Files written before the algorithm was configurable are SHA-256
+    #[serde(default)]
+    hash_algo: HashAlgo,
+    chain: C,
+    // Registered stakes by address; stakes aren't recorded on-chain, so they
+    // would otherwise be lost on restart
+    #[serde(default)]
+    validators: Vec<(String, u64)>,
+}
+
+// Version 1 envelope. JSON defaults the missing validators, but bincode isn't
+// self-describing, so older bincode files are decoded with this layout.
+#[derive(Deserialize)]
+struct PersistedChainV1 {
+    hash_algo: HashAlgo,
+    chain: Vec<Block>,
+}
+
+// A loaded chain and the validator set saved alongside it
+pub struct Snapshot {
+    pub chain: Vec<Block>,
+    pub validators: Vec<(String, u64)>,
+}
+
+pub fn save(
+    storage: &Storage,
+    chain: &[Block],
+    validators: Vec<(String, u64)>,
+) -> Result<(), Box<dyn Error>> {
+    let envelope = PersistedChain {
+        hash_algo: hash::algo(),
+        chain,
+        validators,
+    };
+    let bytes = match storage.format {
+        StorageFormat::Json => serde_json::to_vec_pretty(&VersionedJson {
+            version: CHAIN_FORMAT_VERSION,
+            envelope,
+        })?,
+        StorageFormat::Bincode => {
+            let mut bytes = BINCODE_MAGIC.to_vec();
+            bytes.extend(format!("{:02}", CHAIN_FORMAT_VERSION).as_bytes());
+            bytes.extend(bincode::serialize(&envelope)?);
+            bytes
+        }
+    };
+    let bytes = if storage.compress {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bytes)?;
+        encoder.finish()?
+    } else {
+        bytes
+    };
+    // Compress first: ciphertext doesn't compress
+    let bytes = match &storage.passphrase {
+        Some(passphrase) => encrypt(passphrase, &bytes)?,
+        None => bytes,
+    };
+
+    // Write to a temporary file first so a crash never leaves a truncated chain behind
+    let tmp_path = storage.path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, &storage.path)?;
+    Ok(())
+}
+
+// `passphrase` is only needed for encrypted files
+pub fn load(path: &Path, passphrase: Option<&str>) -> Result<Snapshot, Box<dyn Error>> {
+    let mut bytes = fs::read(path)?;
+    if let Some(rest) = bytes.strip_prefix(ENCRYPTED_MAGIC) {
+        let passphrase = passphrase.ok_or_else(|| {
+            format!(
+                "{} is encrypted; start with --encrypt-passphrase to load it",
+                path.display()
+            )
+        })?;
+        bytes = decrypt(passphrase, rest)?;
+    }
+    if bytes.starts_with(GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+        bytes = decompressed;
+    }
+
+    let (version, envelope): (u32, PersistedChain<Vec<Block>>) =
+        match bytes.strip_prefix(BINCODE_MAGIC) {
+            Some(rest) if rest.len() >= 2 => {
+                let version = std::str::from_utf8(&rest[..2])?.parse()?;
+                let envelope = if version == 1 {
+                    let v1: PersistedChainV1 = bincode::deserialize(&rest[2..])?;
+                    PersistedChain {
+                        hash_algo: v1.hash_algo,
+                        chain: v1.chain,
+                        validators: Vec::new(),
+                    }
+                } else {
+                    check_version(version)?;
+                    bincode::deserialize(&rest[2..])?
+                };
+                (version, envelope)
+            }
+            Some(_) => return Err("Truncated bincode chain file".into()),
+            None => {
+                let versioned: VersionedJson<Vec<Block>> = serde_json::from_slice(&bytes)?;
+                (versioned.version, versioned.envelope)
+            }
+        };
+
+    // Every hash in the chain depends on the algorithm, so it can't change on reload
+    if envelope.hash_algo != hash::algo() {
+        return Err(format!(
+            "Chain was saved with {:?} but the node is configured for {:?}",
+            envelope.hash_algo,
+            hash::algo()
+        )
+        .into());
+    }
+    Ok(Snapshot {
+        chain: migrate(version, envelope.chain)?,
+        validators: envelope.validators,
+    })
+}
+
+// Derives the 256-bit file key from the passphrase with Argon2
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Failed to derive the chain file key: {}", e))?;
+    ChaCha20Poly1305::new_from_slice(&key).map_err(|e| e.to_string())
+}
+
+// A fresh salt and nonce per save, so no key and nonce pair is ever reused
+fn encrypt(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng
+        .try_fill_bytes(&mut salt)
+        .and_then(|()| OsRng.try_fill_bytes(&mut nonce))
+        .map_err(|e| format!("Failed to generate the encryption nonce: {}", e))?;
+
+    let ciphertext = derive_key(passphrase, &salt)?
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| "Failed to encrypt the chain file".to_string())?;
+    let mut bytes = ENCRYPTED_MAGIC.to_vec();
+    bytes.extend(salt);
+    bytes.extend(nonce);
+    bytes.extend(ciphertext);
+    Ok(bytes)
+}
+
+// The AEAD tag covers the whole file, so a wrong passphrase or a tampered
+// file fails here rather than decoding to garbage
+fn decrypt(passphrase: &str, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if bytes.len() < SALT_LEN + NONCE_LEN {
+        return Err("Truncated encrypted chain file".to_string());
+    }
+    let (salt, rest) = bytes.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    derive_key(passphrase, salt)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Wrong passphrase or corrupted encrypted chain file".to_string())
+}
+
+// Files from a newer node are refused rather than guessed at
+fn check_version(version: u32) -> Result<(), String> {
+    if version > CHAIN_FORMAT_VERSION {
+        return Err(format!(
+            "Chain file format version {} is newer than this node supports ({})",
+            version, CHAIN_FORMAT_VERSION
+        ));
+    }
+    Ok(())
+}
+
+// Upgrades a chain saved in an older format to the current one
+fn migrate(version: u32, chain: Vec<Block>) -> Result<Vec<Block>, Box<dyn Error>> {
+    check_version(version)?;
+    match version {
+        // Version 1 only lacked the validator set, which loads as empty
+        1 | CHAIN_FORMAT_VERSION => Ok(chain),
+        v => Err(format!("Unknown chain file format version {}", v).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file path unique to this test process and `name`
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mockchain-{}-{}", std::process::id(), name))
+    }
+
+    // The same two blocks on every call
+    fn chain() -> Vec<Block> {
+        let time = chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let genesis = Block::new_with_timestamp(Vec::new(), None, time);
+        let next = Block::new_with_timestamp(Vec::new(), Some(&genesis), time);
+        vec![genesis, next]
+    }
+
+    // Saves `chain` with `storage`'s settings, returning the raw file and the reloaded snapshot
+    fn round_trip(
+        storage: &Storage,
+        passphrase: Option<&str>,
+    ) -> (Vec<u8>, Result<Snapshot, Box<dyn Error>>) {
+        save(storage, &chain(), vec![("validator".to_string(), 100)]).unwrap();
+        let bytes = fs::read(&storage.path).unwrap();
+        let snapshot = load(&storage.path, passphrase);
+        fs::remove_file(&storage.path).unwrap();
+        (bytes, snapshot)
+    }
+
+    fn load_json(name: &str, json: &str) -> Result<Snapshot, Box<dyn Error>> {
+        let path = temp_path(name);
+        fs::write(&path, json).unwrap();
+        let snapshot = load(&path, None);
+        fs::remove_file(&path).unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn pre_fee_json_loads_with_defaults() {
+        // A first-release file: no version, no validators, and a block and
+        // transaction without any of the fields added since
+        let snapshot = load_json(
+            "pre-fee.json",
+            r#"{"chain": [{
+                "index": 0,
+                "timestamp": "2023-11-14T22:13:20Z",
+                "transactions": [{"from": "alice", "to": "bob", "amount": 5, "timestamp": 1700000000}],
+                "previous_hash": "0",
+                "merkle_root": "",
+                "hash": "",
+                "nonce": 0,
+                "miner": ""
+            }]}"#,
+        )
+        .unwrap();
+        assert!(snapshot.validators.is_empty());
+        let block = &snapshot.chain[0];
+        assert!(block.state_root.is_empty() && block.producer_signature.is_empty());
+        assert_eq!(block.subsidy, None);
+        let tx = &block.transactions[0];
+        assert_eq!((tx.amount, tx.fee, tx.valid_until), (5, 0, 0));
+        assert!(tx.signature.is_empty() && tx.outputs.is_empty());
+    }
+
+    #[test]
+    fn newer_format_versions_are_refused() {
+        let future = format!(
+            r#"{{"version": {}, "chain": []}}"#,
+            CHAIN_FORMAT_VERSION + 1
+        );
+        let error = load_json("future.json", &future).err().unwrap();
+        assert!(
+            error.to_string().contains("newer than this node"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn compressed_files_load_in_either_format() {
+        for format in [StorageFormat::Json, StorageFormat::Bincode] {
+            let storage = Storage {
+                path: temp_path(&format!("compressed-{:?}", format)),
+                format,
+                compress: true,
+                passphrase: None,
+            };
+            let (bytes, snapshot) = round_trip(&storage, None);
+            assert!(bytes.starts_with(GZIP_MAGIC), "{:?}", format);
+            let snapshot = snapshot.unwrap();
+            assert_eq!(snapshot.chain.last().unwrap().hash, chain()[1].hash);
+            assert_eq!(snapshot.validators, [("validator".to_string(), 100)]);
+        }
+    }
+
+    #[test]
+    fn encrypted_files_need_the_right_passphrase() {
+        let storage = Storage {
+            path: temp_path("encrypted"),
+            format: StorageFormat::Json,
+            compress: true,
+            passphrase: Some("correct horse".to_string()),
+        };
+        let (bytes, snapshot) = round_trip(&storage, Some("correct horse"));
+        assert!(bytes.starts_with(ENCRYPTED_MAGIC));
+        assert!(!bytes.windows(4).any(|window| window == b"hash"));
+        assert_eq!(snapshot.unwrap().chain[1].hash, chain()[1].hash);
+
+        for passphrase in [Some("battery staple"), None] {
+            let error = round_trip(&storage, passphrase)
+                .1
+                .err()
+                .unwrap()
+                .to_string();
+            let expected = match passphrase {
+                Some(_) => "Wrong passphrase",
+                None => "is encrypted",
+            };
+            assert!(error.contains(expected), "{}", error);
+        }
+    }
+}
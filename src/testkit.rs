@@ -0,0 +1,257 @@
+// In-process node harness for integration tests and benchmarks, enabled by the
+// `testkit` feature (and always built for the crate's own unit tests).
+//
+// The node's full gRPC surface is served over an in-memory duplex stream, so tests
+// exercise the real request/response path without binding a TCP port. No mining
+// task is started; blocks are produced on demand with `force_mine`.
+// `spawn_simulated` runs the node on a `MockClock` instead of the wall clock, and
+// `spawn_listening` serves it on a loopback port other nodes can peer with.
+//
+//     let mut node = TestNode::spawn(Config::default()).await;
+//     let (alice_key, alice) = node.funded_account(1000);
+//     let (_, bob) = new_account();
+//
+//     let tx = signed_transfer(&alice_key, &alice, &bob, 100, node.chain_id);
+//     assert!(node.client.submit_transaction(tx).await?.into_inner().success);
+//
+//     node.force_mine();
+//     assert_eq!(node.balance(&bob).await, 100);
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use secp256k1::{Secp256k1, SecretKey};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_stream::Stream;
+use tonic::transport::{
+    server::{Connected, TcpIncoming},
+    Channel, Endpoint, Server, Uri,
+};
+use tower::service_fn;
+
+use crate::{
+    block::Block,
+    blockchain::{
+        blockchain_service_client::BlockchainServiceClient, BalanceRequest,
+        Transaction as ProtoTransaction,
+    },
+    clock::{Clock, SystemClock},
+    config::Config,
+    consensus::{ConsensusParams, ConsensusRegistry},
+    entropy, grpc_service,
+    rpc::BlockchainServer,
+    Blockchain, FAUCET_MOCKCHAIN_ADDRESS,
+};
+
+pub use crate::storage::{Storage, StorageFormat};
+pub use crate::transaction::{verify_all, Transaction};
+
+// Low difficulty keeps force-mined blocks fast
+const TEST_DIFFICULTY: usize = 1;
+
+pub struct TestNode {
+    pub client: BlockchainServiceClient<Channel>,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub chain_id: u64,
+}
+
+impl TestNode {
+    pub async fn spawn(config: Config) -> Self {
+        Self::spawn_with_clock(config, Arc::new(SystemClock)).await
+    }
+
+    // Like `spawn`, but on virtual time starting at the genesis time (or now).
+    // Block timestamps, expiry, and the pool TTL only move when the returned
+    // clock is advanced, so time-dependent tests need no sleeps.
+    pub async fn spawn_simulated(config: Config) -> (Self, MockClock) {
+        let clock = MockClock::new(config.genesis_time.unwrap_or_else(Utc::now));
+        let node = Self::spawn_with_clock(config, Arc::new(clock.clone())).await;
+        (node, clock)
+    }
+
+    pub async fn spawn_with_clock(config: Config, clock: Arc<dyn Clock>) -> Self {
+        let (client_io, server_io) = tokio::io::duplex(1024 * 1024);
+        let (blockchain, chain_id) = serve(
+            config,
+            clock,
+            tokio_stream::once(Ok::<_, io::Error>(server_io)),
+        );
+
+        // The URI is ignored; the connector hands out the in-memory stream once
+        let mut client_io = Some(client_io);
+        let channel = Endpoint::try_from("http://[::]:50051")
+            .expect("static URI is valid")
+            .connect_with_connector(service_fn(move |_: Uri| {
+                let io = client_io.take();
+                async move { io.ok_or_else(|| io::Error::other("already connected")) }
+            }))
+            .await
+            .expect("in-process channel connects");
+
+        Self {
+            client: BlockchainServiceClient::new(channel),
+            blockchain,
+            chain_id,
+        }
+    }
+
+    // Like `spawn`, but served on a loopback TCP port that other nodes can dial
+    // as a peer. Requests then carry a remote address, so the rate limit and
+    // peer bans apply to them. Returns the node's address alongside it.
+    pub async fn spawn_listening(config: Config) -> (Self, String) {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("loopback port binds");
+        let addr = format!(
+            "http://{}",
+            listener.local_addr().expect("listener has an address")
+        );
+        let incoming =
+            TcpIncoming::from_listener(listener, true, None).expect("listener accepts connections");
+        let (blockchain, chain_id) = serve(config, Arc::new(SystemClock), incoming);
+
+        let client = BlockchainServiceClient::connect(addr.clone())
+            .await
+            .expect("loopback channel connects");
+        let node = Self {
+            client,
+            blockchain,
+            chain_id,
+        };
+        (node, addr)
+    }
+
+    // Creates a new account and funds it through a mined faucet transaction
+    pub fn funded_account(&self, amount: u64) -> (SecretKey, String) {
+        let (secret_key, address) = new_account();
+        grant(&mut self.blockchain.lock().unwrap(), &address, amount);
+        self.force_mine();
+
+        (secret_key, address)
+    }
+
+    // Mines every pending transaction into a block with a throwaway miner key
+    pub fn force_mine(&self) -> Option<Block> {
+        let mut chain = self.blockchain.lock().unwrap();
+        let (_, miner_key) = entropy::generate_keypair(&mut chain.rng).ok()?;
+        chain.mine_pending_transactions(&miner_key)
+    }
+
+    pub async fn balance(&mut self, address: &str) -> u64 {
+        self.client
+            .get_balance(BalanceRequest {
+                address: address.to_string(),
+            })
+            .await
+            .expect("GetBalance succeeds")
+            .into_inner()
+            .balance
+    }
+}
+
+// Serves the node `config` describes on `incoming` connections, as the binary
+// would, returning its blockchain and chain id
+fn serve<I, IO, IE>(
+    config: Config,
+    clock: Arc<dyn Clock>,
+    incoming: I,
+) -> (Arc<Mutex<Blockchain>>, u64)
+where
+    I: Stream<Item = Result<IO, IE>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    let config = Config {
+        genesis_time: Some(config.genesis_time.unwrap_or_else(|| clock.now())),
+        ..config
+    };
+    let blockchain = open_chain(&config, Arc::clone(&clock));
+    let server = BlockchainServer::new(blockchain, ConsensusRegistry::default(), &config);
+    let blockchain = Arc::clone(&server.blockchain);
+    let service = grpc_service(server, &config, clock);
+
+    let timeout = config.rpc_timeout;
+    tokio::spawn(async move {
+        Server::builder()
+            .timeout(timeout)
+            .add_service(service)
+            .serve_with_incoming(incoming)
+            .await
+    });
+    (blockchain, config.chain_id)
+}
+
+// Opens the chain `config` describes, as a node would, but without serving it.
+// Loads `chain_file` if it exists, so benchmarks can time a reload.
+pub fn open_chain(config: &Config, clock: Arc<dyn Clock>) -> Blockchain {
+    let params = ConsensusParams {
+        difficulty: TEST_DIFFICULTY,
+        ..config.consensus_params()
+    };
+    let consensus = ConsensusRegistry::default()
+        .create(&config.consensus, &params)
+        .expect("consensus is registered");
+    Blockchain::from_config(config, consensus, clock).expect("test chain opens")
+}
+
+// Queues a faucet transfer of `amount` to `address`, to be mined with the next block
+pub fn grant(chain: &mut Blockchain, address: &str, amount: u64) {
+    let faucet_tx = Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, address, amount);
+    chain
+        .add_transaction(faucet_tx)
+        .expect("faucet transactions are always accepted");
+}
+
+// Virtual time for deterministic tests: stands still until advanced. Clones
+// share the same time, so a test keeps one to drive the node's copy.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let by = chrono::Duration::from_std(by).expect("advance fits in a chrono Duration");
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+// Generates a keypair, returning the secret key and the hex-encoded address
+pub fn new_account() -> (SecretKey, String) {
+    let secp = Secp256k1::new();
+    let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+    (secret_key, hex::encode(public_key.serialize()))
+}
+
+// Builds a transfer signed by `secret_key` for `chain_id`, ready to submit over gRPC
+pub fn signed_transfer(
+    secret_key: &SecretKey,
+    from: &str,
+    to: &str,
+    amount: u64,
+    chain_id: u64,
+) -> ProtoTransaction {
+    let mut tx = Transaction::new(from, to, amount);
+    tx.sign(secret_key, chain_id);
+
+    ProtoTransaction::from(&tx)
+}
@@ -1,19 +1,108 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::HashSet,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use log::warn;
-use secp256k1::{ffi::PublicKey, Secp256k1};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey, VerifyOnly};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-use crate::FAUCET_MOCKCHAIN_ADDRESS;
+use crate::{hash, FAUCET_MOCKCHAIN_ADDRESS};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub from: String,
     pub to: String,
     pub amount: u64,
+    // Paid to the miner; omitted when zero so pre-fee transactions keep their hashes
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fee: u64,
     pub timestamp: u64,
+    // Defaulted so an unsigned transaction file can leave it out
+    #[serde(default)]
     pub signature: Vec<u8>,
+    // Recipients of a batched transfer, paid atomically; when set, `to` is
+    // empty and `amount` is zero. Omitted when empty, like `fee`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<(String, u64)>,
+    // Unix time in seconds after which the transaction can't be mined; zero never expires
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub valid_until: u64,
+    // Unix time in seconds before which the transaction can't be mined; zero is unlocked
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub not_before: u64,
+    // Most gas the sender allows the transaction to use; zero allows exactly what it uses
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub gas_limit: u64,
+    // Key set of an M-of-N sender, whose address `from` must be; signed by at
+    // least `threshold` of its keys through `signatures` instead of `signature`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multisig: Option<Multisig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<Vec<u8>>,
+}
+
+// An M-of-N shared account: any `threshold` of the `public_keys` can spend from it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Multisig {
+    pub threshold: u32,
+    // Hex public keys; sorted when hashed, so their order doesn't change the address
+    pub public_keys: Vec<String>,
+}
+
+impl Multisig {
+    pub fn new(threshold: u32, mut public_keys: Vec<String>) -> Self {
+        public_keys.sort();
+        Self {
+            threshold,
+            public_keys,
+        }
+    }
+
+    // Hash of the sorted key set and threshold. At 32 bytes it can't collide
+    // with a 33-byte public key address.
+    pub fn address(&self) -> String {
+        let mut public_keys = self.public_keys.clone();
+        public_keys.sort();
+        let content = serde_json::to_string(&(self.threshold, public_keys)).unwrap();
+        hash::hex_digest(content.as_bytes())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let signers = self.public_keys.len();
+        if self.threshold == 0 || self.threshold as usize > signers {
+            return Err(format!(
+                "Multisig threshold {} must be between 1 and its {} keys",
+                self.threshold, signers
+            ));
+        }
+        let mut seen = HashSet::new();
+        for key in &self.public_keys {
+            let bytes = hex::decode(key).map_err(|e| format!("Multisig key is not hex: {}", e))?;
+            PublicKey::from_slice(&bytes)
+                .map_err(|e| format!("Multisig key is not a valid public key: {}", e))?;
+            if !seen.insert(key) {
+                return Err(format!("Multisig key {} is listed twice", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Flat gas charged for each payment until there is anything else to meter
+pub const TRANSFER_GAS: u64 = 21_000;
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+// Current Unix time in seconds, the clock transaction timestamps and expiries use
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
 }
 
 impl Transaction {
@@ -22,31 +111,217 @@ impl Transaction {
             from: from.to_string(),
             to: to.to_string(),
             amount,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs(),
+            fee: 0,
+            timestamp: unix_now(),
             signature: Vec::new(),
+            outputs: Vec::new(),
+            valid_until: 0,
+            not_before: 0,
+            gas_limit: 0,
+            multisig: None,
+            signatures: Vec::new(),
         }
     }
 
-    pub fn get_message_to_sign(&self) -> Vec<u8> {
-        let mut hasher = Sha256::new();
-        hasher.update(
-            serde_json::to_string(&(&self.from, &self.to, self.amount, self.timestamp))
-                .unwrap()
-                .as_bytes(),
-        );
-        hasher.finalize().to_vec()
+    // A transfer from the M-of-N account `multisig`, to be signed with `sign_multisig`
+    #[cfg(any(test, feature = "testkit"))]
+    pub fn new_multisig(multisig: Multisig, to: &str, amount: u64) -> Self {
+        Self {
+            multisig: Some(multisig.clone()),
+            ..Self::new(&multisig.address(), to, amount)
+        }
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        TRANSFER_GAS * self.payments().len() as u64
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.valid_until != 0 && now > self.valid_until
+    }
+
+    pub fn is_time_locked(&self, now: u64) -> bool {
+        now < self.not_before
+    }
+
+    // Every (recipient, amount) the transaction pays; a plain transfer is a single payment
+    pub fn payments(&self) -> Vec<(&str, u64)> {
+        if self.outputs.is_empty() {
+            vec![(self.to.as_str(), self.amount)]
+        } else {
+            self.outputs
+                .iter()
+                .map(|(to, amount)| (to.as_str(), *amount))
+                .collect()
+        }
+    }
+
+    // Sum of all payments, saturating so an overflowing batch can never be covered
+    pub fn total_amount(&self) -> u64 {
+        self.payments()
+            .iter()
+            .fold(0, |total, (_, amount)| total.saturating_add(*amount))
+    }
+
+    // Total the sender is debited: every payment plus the fee
+    pub fn total_debit(&self) -> u64 {
+        self.total_amount().saturating_add(self.fee)
+    }
+
+    // The fee is only part of the signed message when set, so zero-fee
+    // transactions from wallets that predate fees still verify; batched
+    // outputs, expiry, time lock and gas limit are appended the same way, the
+    // last three tagged so they can't be mistaken for a fee. Likewise a
+    // non-zero chain id is prepended as a domain separator, so a signature
+    // for one chain never verifies on another.
+    pub fn get_message_to_sign(&self, chain_id: u64) -> [u8; 32] {
+        let mut fields = serde_json::json!([self.from, self.to, self.amount, self.timestamp]);
+        let fields = fields.as_array_mut().expect("built as an array");
+        if self.fee != 0 {
+            fields.push(self.fee.into());
+        }
+        // Every (recipient, amount) pair, in order, so once signed no output can
+        // be added, dropped, reordered or changed; a plain transfer gains the
+        // field if outputs are attached, which breaks its signature too
+        if !self.outputs.is_empty() {
+            fields.push(serde_json::json!(self.outputs));
+        }
+        if self.valid_until != 0 {
+            fields.push(serde_json::json!({ "valid_until": self.valid_until }));
+        }
+        if self.not_before != 0 {
+            fields.push(serde_json::json!({ "not_before": self.not_before }));
+        }
+        if self.gas_limit != 0 {
+            fields.push(serde_json::json!({ "gas_limit": self.gas_limit }));
+        }
+        if chain_id != 0 {
+            fields.insert(0, chain_id.into());
+        }
+        let content = serde_json::to_string(fields).unwrap();
+        hash::digest(content.as_bytes())
+    }
+
+    // Cheap shape checks, run before any signature check or balance scan. The
+    // sender must be a public key on the curve unless it is the faucet.
+    pub fn validate_structure(&self) -> Result<(), String> {
+        if !self.outputs.is_empty() && (!self.to.is_empty() || self.amount != 0) {
+            return Err("Batched transactions must leave to and amount unset".to_string());
+        }
+
+        for (to, amount) in self.payments() {
+            if to.is_empty() {
+                return Err("Recipient address is empty".to_string());
+            }
+            if to == self.from {
+                return Err("Sender and recipient are the same".to_string());
+            }
+            if amount == 0 {
+                return Err(format!("Payment to {} has a zero amount", to));
+            }
+        }
+
+        if self.valid_until != 0 && self.not_before > self.valid_until {
+            return Err(format!(
+                "Transaction is locked until {}, after it expires at {}",
+                self.not_before, self.valid_until
+            ));
+        }
+
+        if self.gas_limit != 0 && self.gas_limit < self.gas_used() {
+            return Err(format!(
+                "Gas limit {} is below the {} gas the transaction uses",
+                self.gas_limit,
+                self.gas_used()
+            ));
+        }
+
+        if let Some(reason) = self.misplaced_signatures() {
+            return Err(reason.to_string());
+        }
+        if let Some(multisig) = &self.multisig {
+            multisig.validate()?;
+            if self.from != multisig.address() {
+                return Err("Sender address does not match its multisig key set".to_string());
+            }
+        } else if self.from != FAUCET_MOCKCHAIN_ADDRESS {
+            let public_key_bytes =
+                hex::decode(&self.from).map_err(|e| format!("Sender address is not hex: {}", e))?;
+            PublicKey::from_slice(&public_key_bytes)
+                .map_err(|e| format!("Sender address is not a valid public key: {}", e))?;
+        }
+        Ok(())
+    }
+
+    // Signature bytes in the field the sender's kind doesn't sign with. Verification
+    // ignores them, so they'd give a signed transaction a new hash for free.
+    fn misplaced_signatures(&self) -> Option<&'static str> {
+        match self.multisig {
+            Some(_) if !self.signature.is_empty() => {
+                Some("Multisig transactions are signed through signatures, not signature")
+            }
+            None if !self.signatures.is_empty() => {
+                Some("Only multisig transactions carry signatures")
+            }
+            _ => None,
+        }
+    }
+
+    // Serialized length in bytes, the blockspace the transaction takes up
+    pub fn size(&self) -> usize {
+        serde_json::to_vec(self).unwrap().len()
+    }
+
+    pub fn hash(&self) -> String {
+        hash::hex_digest(serde_json::to_string(self).unwrap().as_bytes())
+    }
+
+    // Hash of the signed content alone, leaving out the signatures. A copy that
+    // only differs in its signature bytes, such as the same multisig transfer
+    // signed by another subset of keys, is the same payment, so duplicates are
+    // detected by this rather than by `hash`.
+    pub fn signing_hash(&self) -> String {
+        hex::encode(self.get_message_to_sign(0))
     }
 
-    pub fn verify(&self) -> bool {
+    // Signs the transaction for `chain_id`. secp256k1 derives the ECDSA nonce from the
+    // key and message (RFC 6979), so the same transaction always gets byte-identical
+    // signatures, and therefore the same hash.
+    pub fn sign(&mut self, secret_key: &SecretKey, chain_id: u64) {
+        let message = Message::from_slice(&self.get_message_to_sign(chain_id))
+            .expect("signing digests are 32 bytes");
+        self.signature = Secp256k1::signing_only()
+            .sign_ecdsa(&message, secret_key)
+            .serialize_compact()
+            .to_vec();
+    }
+
+    // Adds one signer's signature to a multisig transaction; the signed message
+    // is the same as for a single-key transaction
+    pub fn sign_multisig(&mut self, secret_key: &SecretKey, chain_id: u64) {
+        let message = Message::from_slice(&self.get_message_to_sign(chain_id))
+            .expect("signing digests are 32 bytes");
+        let signature = Secp256k1::signing_only().sign_ecdsa(&message, secret_key);
+        self.signatures.push(signature.serialize_compact().to_vec());
+    }
+
+    pub fn verify(&self, chain_id: u64) -> bool {
+        self.verify_with(&Secp256k1::verification_only(), chain_id)
+    }
+
+    // `verify` with a caller-provided context, which is costly to create per transaction
+    fn verify_with(&self, secp: &Secp256k1<VerifyOnly>, chain_id: u64) -> bool {
         // Skip verification for faucet transactions
         if self.from == FAUCET_MOCKCHAIN_ADDRESS {
             return true;
         }
-
-        let secp = Secp256k1::new();
+        if let Some(reason) = self.misplaced_signatures() {
+            warn!("{}", reason);
+            return false;
+        }
+        if let Some(multisig) = &self.multisig {
+            return self.verify_multisig(secp, multisig, chain_id);
+        }
 
         let public_key_bytes = match hex::decode(&self.from) {
             Ok(bytes) => bytes,
@@ -64,12 +339,240 @@ impl Transaction {
             }
         };
 
-        if let Ok(sig) = secp256k1::ecdsa::Signature::from_compact(&self.signature) {
-            let message = self.get_message_to_sign();
-            if let Ok(msg) = secp256k1::Message::from_slice(&message) {
-                return secp.verify_ecdsa(&msg, &sig, &public_key).is_ok();
+        let signature = match Signature::from_compact(&self.signature) {
+            Ok(signature) => signature,
+            Err(e) => {
+                warn!("Invalid signature: {}", e);
+                return false;
             }
+        };
+
+        // The digest is fixed at 32 bytes whatever the hash algorithm, so this can't fail
+        let message = Message::from_slice(&self.get_message_to_sign(chain_id))
+            .expect("signing digests are 32 bytes");
+        secp.verify_ecdsa(&message, &signature, &public_key).is_ok()
+    }
+
+    // At least `threshold` distinct keys of the set must each have a valid
+    // signature; a signature is only ever counted for one key
+    fn verify_multisig(
+        &self,
+        secp: &Secp256k1<VerifyOnly>,
+        multisig: &Multisig,
+        chain_id: u64,
+    ) -> bool {
+        if self.from != multisig.address() {
+            warn!("Sender address does not match its multisig key set");
+            return false;
+        }
+
+        let message = Message::from_slice(&self.get_message_to_sign(chain_id))
+            .expect("signing digests are 32 bytes");
+        let mut signatures: Vec<Option<Signature>> = self
+            .signatures
+            .iter()
+            .map(|bytes| Signature::from_compact(bytes).ok())
+            .collect();
+
+        let mut signers = 0;
+        for key in &multisig.public_keys {
+            let Some(public_key) = hex::decode(key)
+                .ok()
+                .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+            else {
+                warn!("Invalid multisig public key: {}", key);
+                return false;
+            };
+            let matched = signatures.iter_mut().find(|signature| {
+                signature.is_some_and(|signature| {
+                    secp.verify_ecdsa(&message, &signature, &public_key).is_ok()
+                })
+            });
+            if let Some(signature) = matched {
+                *signature = None;
+                signers += 1;
+            }
+        }
+        signers >= multisig.threshold as usize
+    }
+}
+
+// Verifies every signature in `transactions`, split across the available cores
+// with one shared context. Agrees with calling `verify` on each transaction.
+pub fn verify_all(transactions: &[Transaction], chain_id: u64) -> bool {
+    let secp = Secp256k1::verification_only();
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = transactions.len().div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = transactions
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let secp = &secp;
+                scope.spawn(move || chunk.iter().all(|tx| tx.verify_with(secp, chain_id)))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .all(|worker| worker.join().expect("verification threads don't panic"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::new_account;
+
+    const CHAIN_ID: u64 = 7;
+
+    fn signed_transfers(count: u64) -> Vec<Transaction> {
+        let (secret_key, from) = new_account();
+        let (_, to) = new_account();
+        (1..=count)
+            .map(|amount| {
+                let mut tx = Transaction::new(&from, &to, amount);
+                tx.sign(&secret_key, CHAIN_ID);
+                tx
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_verification_agrees_with_serial() {
+        let mut transactions = signed_transfers(1000);
+        assert!(transactions.iter().all(|tx| tx.verify(CHAIN_ID)));
+        assert!(verify_all(&transactions, CHAIN_ID));
+
+        // One tampered transaction in the middle fails the whole batch
+        transactions[617].amount += 1;
+        assert!(!transactions[617].verify(CHAIN_ID));
+        assert!(!verify_all(&transactions, CHAIN_ID));
+        assert!(!verify_all(&transactions, CHAIN_ID + 1));
+        assert!(verify_all(&[], CHAIN_ID));
+    }
+
+    #[test]
+    fn malformed_signatures_are_rejected_without_panicking() {
+        let mut tx = signed_transfers(1).remove(0);
+        assert_eq!(tx.get_message_to_sign(CHAIN_ID).len(), 32);
+        // secp256k1 only accepts 32-byte messages; anything else is an error, not a panic
+        assert!(Message::from_slice(&[0; 31]).is_err());
+
+        for signature in [Vec::new(), vec![1; 10], vec![0xff; 64], vec![1; 65]] {
+            tx.signature = signature;
+            assert!(!tx.verify(CHAIN_ID));
         }
-        false
+    }
+
+    #[test]
+    fn signing_twice_gives_identical_signatures() {
+        let (secret_key, from) = new_account();
+        let mut first = Transaction::new(&from, &new_account().1, 10);
+        let mut second = first.clone();
+        first.sign(&secret_key, CHAIN_ID);
+        second.sign(&secret_key, CHAIN_ID);
+        assert_eq!(first.signature, second.signature);
+
+        // A different message still gets a different nonce, and so signature
+        second.sign(&secret_key, CHAIN_ID + 1);
+        assert_ne!(first.signature, second.signature);
+    }
+
+    #[test]
+    fn structural_failures_report_their_reason() {
+        let (_, from) = new_account();
+        let (_, to) = new_account();
+        let with = |change: fn(&mut Transaction)| {
+            let mut tx = Transaction::new(&from, &to, 5);
+            change(&mut tx);
+            tx.validate_structure().unwrap_err()
+        };
+
+        assert!(Transaction::new(&from, &to, 5).validate_structure().is_ok());
+        assert!(with(|tx| tx.from = "zz".to_string()).contains("not hex"));
+        assert!(with(|tx| tx.from = "05".repeat(33)).contains("not a valid public key"));
+        // An uncompressed key for (0, 0), which isn't on the curve
+        assert!(with(|tx| tx.from = format!("04{}", "00".repeat(64)))
+            .contains("not a valid public key"));
+        assert!(with(|tx| tx.to = String::new()).contains("Recipient address is empty"));
+        assert!(with(|tx| tx.to = tx.from.clone()).contains("Sender and recipient are the same"));
+        assert!(with(|tx| tx.amount = 0).contains("zero amount"));
+        assert!(with(|tx| tx.outputs = vec![("x".to_string(), 1)])
+            .contains("leave to and amount unset"));
+        assert!(with(|tx| {
+            tx.valid_until = 10;
+            tx.not_before = 11;
+        })
+        .contains("locked until 11"));
+        assert!(with(|tx| tx.gas_limit = 1).contains("Gas limit 1"));
+        assert!(with(|tx| tx.signatures = vec![vec![1, 2, 3]])
+            .contains("Only multisig transactions carry signatures"));
+    }
+
+    #[test]
+    fn multisig_spends_need_the_threshold_of_distinct_keys() {
+        let keys: Vec<(SecretKey, String)> = (0..3).map(|_| new_account()).collect();
+        let multisig = Multisig::new(2, keys.iter().map(|(_, key)| key.clone()).collect());
+        let (_, recipient) = new_account();
+        let unsigned = Transaction::new_multisig(multisig.clone(), &recipient, 10);
+        assert_eq!(unsigned.from, multisig.address());
+        let signed_by = |signers: &[&SecretKey]| {
+            let mut tx = unsigned.clone();
+            for secret_key in signers {
+                tx.sign_multisig(secret_key, CHAIN_ID);
+            }
+            tx
+        };
+
+        assert!(!signed_by(&[&keys[0].0]).verify(CHAIN_ID));
+        assert!(signed_by(&[&keys[0].0, &keys[2].0]).verify(CHAIN_ID));
+        assert!(!signed_by(&[&keys[0].0, &keys[2].0]).verify(CHAIN_ID + 1));
+        // One key signing twice is still one signer
+        assert!(!signed_by(&[&keys[1].0, &keys[1].0]).verify(CHAIN_ID));
+        let (outsider, _) = new_account();
+        assert!(!signed_by(&[&keys[1].0, &outsider]).verify(CHAIN_ID));
+
+        // A single-key signature alongside is refused, not ignored
+        let mut padded = signed_by(&[&keys[0].0, &keys[2].0]);
+        padded.signature = vec![1, 2, 3];
+        assert!(!padded.verify(CHAIN_ID));
+        assert!(padded
+            .validate_structure()
+            .unwrap_err()
+            .contains("not signature"));
+        // Any quorum signs the same payment
+        assert_eq!(
+            signed_by(&[&keys[0].0, &keys[1].0]).signing_hash(),
+            signed_by(&[&keys[2].0, &keys[1].0]).signing_hash()
+        );
+    }
+
+    #[test]
+    fn signatures_cover_every_output() {
+        let (secret_key, from) = new_account();
+        let recipients: Vec<String> = (0..3).map(|_| new_account().1).collect();
+        let mut batch = Transaction::new(&from, "", 0);
+        batch.outputs = vec![(recipients[0].clone(), 10), (recipients[1].clone(), 20)];
+        batch.sign(&secret_key, CHAIN_ID);
+        assert!(batch.verify(CHAIN_ID));
+        let tampered = |change: &dyn Fn(&mut Transaction)| {
+            let mut tx = batch.clone();
+            change(&mut tx);
+            tx.verify(CHAIN_ID)
+        };
+
+        assert!(!tampered(&|tx| tx.outputs.push((recipients[2].clone(), 1))));
+        assert!(!tampered(&|tx| {
+            tx.outputs.pop();
+        }));
+        assert!(!tampered(&|tx| tx.outputs.swap(0, 1)));
+        assert!(!tampered(&|tx| tx.outputs[1].1 += 1));
+        assert!(!tampered(&|tx| tx.outputs[1].0 = recipients[2].clone()));
+
+        // Attaching outputs to a signed plain transfer breaks it too
+        let mut single = Transaction::new(&from, &recipients[0], 10);
+        single.sign(&secret_key, CHAIN_ID);
+        single.outputs.push((recipients[2].clone(), 1));
+        assert!(!single.verify(CHAIN_ID));
     }
 }
@@ -0,0 +1,82 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
+use tokio::task::JoinHandle;
+
+use crate::{consensus::MINING_INTERVAL_SECS, Blockchain};
+
+// Number of mining intervals the mining task may skip its loop before it is considered stuck
+const STALE_TIP_INTERVALS: u64 = 6;
+
+// Watches the mining task and respawns it if it dies or stops going round its
+// loop. A task that runs but has nothing worth mining yet is left alone.
+pub fn spawn(blockchain: Arc<Mutex<Blockchain>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let stale_after = Duration::from_secs(MINING_INTERVAL_SECS * STALE_TIP_INTERVALS);
+        let mut last_restart = Instant::now();
+
+        loop {
+            tokio::time::sleep(Duration::from_secs(MINING_INTERVAL_SECS)).await;
+
+            // A panic while holding the lock poisons it for every later caller
+            if blockchain.is_poisoned() {
+                warn!("Blockchain lock was poisoned, clearing it");
+                blockchain.clear_poison();
+            }
+
+            let mut chain = blockchain.lock().unwrap();
+            let stalled = is_stalled(&chain, stale_after) && last_restart.elapsed() >= stale_after;
+
+            if !chain.is_mining() || stalled {
+                error!(
+                    "Mining task is not running its loop (running: {}), respawning",
+                    chain.is_mining()
+                );
+                chain.start_mining(Arc::clone(&blockchain));
+                last_restart = Instant::now();
+            }
+        }
+    })
+}
+
+// Whether the mining task has gone `stale_after` without a round of its loop
+fn is_stalled(chain: &Blockchain, stale_after: Duration) -> bool {
+    chain.since_mining_round() >= stale_after
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config, consensus::ConsensusRegistry, testkit::MockClock, transaction::Transaction,
+        FAUCET_MOCKCHAIN_ADDRESS,
+    };
+
+    #[test]
+    fn idle_miner_with_pending_transactions_is_not_stalled() {
+        let clock = MockClock::new(chrono::Utc::now());
+        let config = Config::default();
+        let consensus = ConsensusRegistry::default()
+            .create("pow", &config.consensus_params())
+            .unwrap();
+        let mut chain =
+            Blockchain::from_config(&config, consensus, Arc::new(clock.clone())).unwrap();
+        let stale_after = Duration::from_secs(60);
+
+        // A few pending transactions don't make PoW mine, but the loop still runs
+        chain
+            .add_transaction(Transaction::new(FAUCET_MOCKCHAIN_ADDRESS, "recipient", 1))
+            .unwrap();
+        for _ in 0..10 {
+            clock.advance(Duration::from_secs(MINING_INTERVAL_SECS));
+            chain.record_mining_round();
+            assert!(!is_stalled(&chain, stale_after));
+        }
+
+        clock.advance(stale_after);
+        assert!(is_stalled(&chain, stale_after));
+    }
+}
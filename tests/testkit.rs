@@ -0,0 +1,81 @@
+// End-to-end scenarios against an in-process node, over its real gRPC surface
+use mockchain::blockchain::Transaction as ProtoTransaction;
+use mockchain::config::Config;
+use mockchain::testkit::{new_account, signed_transfer, TestNode, Transaction};
+
+#[tokio::test]
+async fn transfer_is_mined_and_credited() {
+    let mut node = TestNode::spawn(Config::default()).await;
+    let (alice_key, alice) = node.funded_account(1000);
+    let (_, bob) = new_account();
+
+    let tx = signed_transfer(&alice_key, &alice, &bob, 100, node.chain_id);
+    let response = node
+        .client
+        .submit_transaction(tx)
+        .await
+        .expect("SubmitTransaction succeeds")
+        .into_inner();
+    assert!(response.success, "{}", response.message);
+
+    node.force_mine();
+    assert_eq!(node.balance(&bob).await, 100);
+    assert_eq!(node.balance(&alice).await, 900);
+}
+
+#[tokio::test]
+async fn transfers_signed_for_another_chain_are_rejected() {
+    let mut node = TestNode::spawn(Config {
+        chain_id: 7,
+        ..Config::default()
+    })
+    .await;
+    let (alice_key, alice) = node.funded_account(1000);
+    let (_, bob) = new_account();
+
+    let replayed = signed_transfer(&alice_key, &alice, &bob, 100, 8);
+    let response = node
+        .client
+        .submit_transaction(replayed)
+        .await
+        .expect("SubmitTransaction succeeds")
+        .into_inner();
+    assert!(!response.success);
+
+    let tx = signed_transfer(&alice_key, &alice, &bob, 100, 7);
+    let response = node
+        .client
+        .submit_transaction(tx)
+        .await
+        .expect("SubmitTransaction succeeds")
+        .into_inner();
+    assert!(response.success, "{}", response.message);
+}
+
+#[tokio::test]
+async fn one_transaction_pays_three_recipients() {
+    let mut node = TestNode::spawn(Config::default()).await;
+    let (alice_key, alice) = node.funded_account(1000);
+    let recipients: Vec<String> = (0..3).map(|_| new_account().1).collect();
+
+    let mut tx = Transaction::new(&alice, "", 0);
+    tx.outputs = recipients
+        .iter()
+        .zip([100, 200, 300])
+        .map(|(to, amount)| (to.clone(), amount))
+        .collect();
+    tx.sign(&alice_key, node.chain_id);
+    let response = node
+        .client
+        .submit_transaction(ProtoTransaction::from(&tx))
+        .await
+        .expect("SubmitTransaction succeeds")
+        .into_inner();
+    assert!(response.success, "{}", response.message);
+
+    node.force_mine();
+    for (recipient, amount) in recipients.iter().zip([100, 200, 300]) {
+        assert_eq!(node.balance(recipient).await, amount);
+    }
+    assert_eq!(node.balance(&alice).await, 400);
+}